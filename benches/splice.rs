@@ -0,0 +1,52 @@
+//! Throughput of moving a stream from a pipe into a local file with a userspace copy versus
+//! `splice(2)`. Run with `cargo bench --bench splice`.
+use filedescriptor::{FileDescriptor, Pipe};
+use gazpacho::daemon::bandwidth::Throttle;
+use gazpacho::daemon::splice;
+use std::fs::File;
+use std::io::Write;
+use std::time::Instant;
+
+const STREAM_SIZE: usize = 2 * 1024 * 1024 * 1024;
+const WRITE_CHUNK: usize = 1024 * 1024;
+
+fn feed(mut write: FileDescriptor) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let chunk = vec![0x5au8; WRITE_CHUNK];
+        for _ in 0..STREAM_SIZE / WRITE_CHUNK {
+            write.write_all(&chunk).unwrap();
+        }
+    })
+}
+
+fn run(name: &str, copy: impl FnOnce(&mut FileDescriptor, &mut File) -> u64) {
+    let path = std::env::temp_dir().join(format!("gazpacho-bench-{}-{}", name, std::process::id()));
+    let mut file = File::create(&path).unwrap();
+    let Pipe { mut read, write } = Pipe::new().unwrap();
+    let feeder = feed(write);
+    let started = Instant::now();
+    let bytes = copy(&mut read, &mut file);
+    let elapsed = started.elapsed();
+    feeder.join().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(bytes, STREAM_SIZE as u64);
+    println!(
+        "{:>6}: {} MiB in {:.2}s, {:.1} MiB/s",
+        name,
+        bytes / (1024 * 1024),
+        elapsed.as_secs_f64(),
+        bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    run("copy", |read, file| std::io::copy(read, file).unwrap());
+    if splice::SUPPORTED {
+        let throttle = Throttle::new(None);
+        run("splice", |read, file| {
+            splice::splice_all(read, file, &throttle).unwrap()
+        });
+    } else {
+        println!("splice: not supported on this platform");
+    }
+}
@@ -9,5 +9,33 @@ unsafe fn check_root() {
 }
 fn main() {
     //unsafe { check_root() }
-    gazpacho::daemon::start_daemon();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("test-destination") => match args.get(1) {
+            Some(name) => {
+                if !gazpacho::daemon::test_destination(name) {
+                    std::process::exit(1);
+                }
+            }
+            None => {
+                eprintln!("Usage: gazpacho test-destination <name>");
+                std::process::exit(2);
+            }
+        },
+        Some("restore") => match (args.get(1), args.get(2)) {
+            (Some(destination), Some(target)) => {
+                let key_file = args.get(3).map(std::path::Path::new);
+                if !gazpacho::daemon::restore(destination, std::path::Path::new(target), key_file) {
+                    std::process::exit(1);
+                }
+            }
+            _ => {
+                eprintln!("Usage: gazpacho restore <destination> <path> [key-file] | zfs receive <dataset>");
+                eprintln!("The backup is verified before it's written. If restore still fails, it exits with");
+                eprintln!("non-zero status and whatever was received has to be discarded.");
+                std::process::exit(2);
+            }
+        },
+        _ => gazpacho::daemon::start_daemon(),
+    }
 }
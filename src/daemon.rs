@@ -1,23 +1,42 @@
 use uclicious::{Priority, DEFAULT_DUPLICATE_STRATEGY};
 
+pub mod bandwidth;
+pub mod command;
+pub mod compression;
 pub mod config;
+pub mod control;
 pub mod destination;
+pub mod encryption;
 pub mod ensured;
 pub mod logging;
+pub mod manifest;
+pub mod measure;
+pub mod metrics;
+pub mod notify;
+pub mod pipeline;
+pub mod restore;
+pub mod splice;
 pub mod strategy;
 pub mod system;
+pub mod tee;
+pub mod template;
 
 use crate::daemon::system::bootstrap_system;
 use crate::daemon::system::messages::maid::Cleanup;
 use config::Configuration;
+use ensured::pool::SessionPool;
+use ensured::probe;
 use logging::GlobalLogger as Log;
+use measure::Measured;
 use once_cell::sync::OnceCell;
 use slog::{debug, error, info, warn};
+use std::path::Path;
 use std::sync::mpsc;
 
 static CURRENT_CONFIGURATION: OnceCell<Configuration> = OnceCell::new();
 
-pub fn start_daemon() {
+/// Configuration the daemon runs with.
+pub fn load_configuration() -> Configuration {
     let input = r#"
         daemon {
             database = "/usr/home/andoriyu/gazpacho.sqlite3",
@@ -67,7 +86,118 @@ pub fn start_daemon() {
     builder
         .add_chunk_full(input, Priority::default(), DEFAULT_DUPLICATE_STRATEGY)
         .unwrap();
-    let conf: Configuration = builder.build().unwrap();
+    builder.build().unwrap()
+}
+
+/// Run destination self-test without starting the daemon. Returns whether all checks passed.
+pub fn test_destination(name: &str) -> bool {
+    let conf = load_configuration();
+    logging::setup_root_logger(&conf);
+    let dst = match conf.destinations.get(name) {
+        Some(dst) => dst,
+        None => {
+            eprintln!("Destination `{}` not found", name);
+            return false;
+        }
+    };
+    let pool = SessionPool::for_destination(dst.ssh.as_ref());
+    let report = probe::test_destination(&Log::get(), name, dst, &pool);
+    println!("{}", report);
+    report.passed()
+}
+
+/// Write the `zfs send` stream of a backup to stdout, to be piped into `zfs receive`. Stored
+/// volumes are checked against the manifest before anything is written. Returns whether the
+/// stream was complete and matched its checksum, output of a failed restore must be discarded.
+pub fn restore(destination: &str, target: &Path, key_file: Option<&Path>) -> bool {
+    let conf = load_configuration();
+    logging::setup_root_logger(&conf);
+    let dst = match conf.destinations.get(destination) {
+        Some(dst) => dst,
+        None => {
+            eprintln!("Destination `{}` not found", destination);
+            return false;
+        }
+    };
+    let pool = SessionPool::for_destination(dst.ssh.as_ref());
+    // zfs receive can't take back what it was fed, so the backup is read twice.
+    if let Err(e) = verify_stored(dst, &pool, target) {
+        eprintln!(
+            "Backup {} is damaged, nothing was restored: {}",
+            target.display(),
+            e
+        );
+        return false;
+    }
+    let (manifest, reader) = match restore::open_stream(&Log::get(), dst, &pool, target, key_file) {
+        Ok(opened) => opened,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", target.display(), e);
+            return false;
+        }
+    };
+    let mut reader = Measured::new(reader);
+    let stdout = std::io::stdout();
+    let error = match std::io::copy(&mut reader, &mut stdout.lock()) {
+        Err(e) => Some(e.to_string()),
+        Ok(_) if reader.bytes() != manifest.bytes_raw => Some(format!(
+            "stream is {} bytes, {} bytes were sent",
+            reader.bytes(),
+            manifest.bytes_raw
+        )),
+        Ok(_) => match manifest.checksum {
+            Some(ref checksum) if checksum.raw != reader.checksum() => Some(String::from(
+                "checksum of the stream doesn't match the one recorded in the manifest",
+            )),
+            _ => None,
+        },
+    };
+    match error {
+        Some(e) => {
+            eprintln!(
+                "Failed to restore {}: {}. The output is incomplete or corrupt and must be discarded",
+                target.display(),
+                e
+            );
+            false
+        }
+        None => true,
+    }
+}
+
+/// Read the backup as stored and compare it to its manifest.
+fn verify_stored(
+    dst: &destination::Destination,
+    pool: &SessionPool,
+    target: &Path,
+) -> Result<(), String> {
+    let (manifest, stored) =
+        restore::open(&Log::get(), dst, pool, target).map_err(|e| e.to_string())?;
+    let (bytes, checksum) = measure::checksum_of(stored).map_err(|e| e.to_string())?;
+    if bytes != manifest.bytes_written {
+        return Err(format!(
+            "{} bytes are stored, {} bytes were written",
+            bytes, manifest.bytes_written
+        ));
+    }
+    match manifest.checksum {
+        Some(ref recorded) if recorded.written != checksum => Err(String::from(
+            "checksum of stored files doesn't match the one recorded in the manifest",
+        )),
+        Some(_) => Ok(()),
+        None => {
+            warn!(
+                Log::get(),
+                "Manifest of {} has no checksum, only size was checked",
+                target.display()
+            );
+            Ok(())
+        }
+    }
+}
+
+pub fn start_daemon() {
+    let conf = load_configuration();
 
     CURRENT_CONFIGURATION
         .set(conf.clone())
@@ -79,6 +209,9 @@ pub fn start_daemon() {
     }
     info!(Log::get(), "Startup sequence initialized"; "module" => module_path!());
     debug!(Log::get(), "Current configuration: {:?}", &conf);
+    for warning in conf.warnings() {
+        warn!(Log::get(), "{}", warning);
+    }
     match slog_stdlog::init() {
         Ok(()) => debug!(Log::get(), "Installed stdlog backend"),
         Err(e) => error!(Log::get(), "Failed to install stdlog backend: {}", e),
@@ -0,0 +1,218 @@
+use chrono::{Local, NaiveTime};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uclicious::Uclicious;
+
+/// Largest chunk written at once, so parallel transfers get interleaved instead of taking turns
+/// with big bursts.
+const MAX_CHUNK: usize = 64 * 1024;
+
+/// Limit that applies between `from` and `to` local time. Windows may cross midnight.
+#[derive(Uclicious, Clone, Debug, Hash)]
+#[ucl(skip_builder)]
+pub struct BandwidthWindow {
+    #[ucl(map = "crate::utils::time_of_day")]
+    pub from: NaiveTime,
+    #[ucl(map = "crate::utils::time_of_day")]
+    pub to: NaiveTime,
+    /// Bytes per second, unlimited if not set.
+    #[ucl(default)]
+    pub limit: Option<u64>,
+}
+
+impl BandwidthWindow {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            time >= self.from || time < self.to
+        }
+    }
+}
+
+/// Bandwidth limit of a destination, shared by all of its agents.
+#[derive(Uclicious, Clone, Debug, Hash, Default)]
+#[ucl(skip_builder)]
+pub struct Bandwidth {
+    /// Bytes per second outside of scheduled windows, unlimited if not set.
+    #[ucl(default)]
+    pub limit: Option<u64>,
+    /// The first window that contains current time wins.
+    #[ucl(default)]
+    pub schedule: Vec<BandwidthWindow>,
+}
+
+impl Bandwidth {
+    pub fn limit_at(&self, time: NaiveTime) -> Option<u64> {
+        self.schedule
+            .iter()
+            .find(|window| window.contains(time))
+            .map(|window| window.limit)
+            .unwrap_or(self.limit)
+            .filter(|limit| *limit > 0)
+    }
+}
+
+struct Bucket {
+    /// May go negative: whoever takes more than available sleeps off the debt.
+    tokens: f64,
+    last: Instant,
+}
+
+/// Token bucket shared between agents of a destination. Cloning shares the bucket.
+#[derive(Clone)]
+pub struct Throttle {
+    config: Option<Bandwidth>,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl Throttle {
+    pub fn new(config: Option<Bandwidth>) -> Self {
+        Throttle {
+            config,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: 0.0,
+                last: Instant::now(),
+            })),
+        }
+    }
+
+    fn current_limit(&self) -> Option<u64> {
+        self.config
+            .as_ref()
+            .and_then(|config| config.limit_at(Local::now().time()))
+    }
+
+    /// Block until `bytes` may be sent under the current limit.
+    pub fn acquire(&self, bytes: usize) {
+        let limit = match self.current_limit() {
+            Some(limit) => limit as f64,
+            None => return,
+        };
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last).as_secs_f64();
+            // Burst of at most one second worth of data.
+            bucket.tokens = (bucket.tokens + elapsed * limit).min(limit);
+            bucket.last = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Some(Duration::from_secs_f64(-bucket.tokens / limit))
+            } else {
+                None
+            }
+        };
+        if let Some(wait) = wait {
+            std::thread::sleep(wait);
+        }
+    }
+
+    pub fn wrap<W: Write>(&self, inner: W) -> Throttled<W> {
+        Throttled {
+            inner,
+            throttle: self.clone(),
+        }
+    }
+}
+
+/// Writer that doesn't go faster than its throttle allows.
+pub struct Throttled<W> {
+    inner: W,
+    throttle: Throttle,
+}
+
+impl<W> Throttled<W> {
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk = &buf[..buf.len().min(MAX_CHUNK)];
+        let written = self.inner.write(chunk)?;
+        // Only what was taken is paid for, the bucket goes into debt until then.
+        self.throttle.acquire(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn schedule_windows() {
+        let bandwidth = Bandwidth {
+            limit: None,
+            schedule: vec![
+                BandwidthWindow {
+                    from: time("08:00"),
+                    to: time("18:00"),
+                    limit: Some(1024),
+                },
+                BandwidthWindow {
+                    from: time("22:00"),
+                    to: time("02:00"),
+                    limit: Some(4096),
+                },
+            ],
+        };
+        assert_eq!(bandwidth.limit_at(time("07:59")), None);
+        assert_eq!(bandwidth.limit_at(time("08:00")), Some(1024));
+        assert_eq!(bandwidth.limit_at(time("18:00")), None);
+        assert_eq!(bandwidth.limit_at(time("23:30")), Some(4096));
+        assert_eq!(bandwidth.limit_at(time("01:00")), Some(4096));
+    }
+
+    #[test]
+    fn throttles_shared_writers() {
+        let throttle = Throttle::new(Some(Bandwidth {
+            limit: Some(100 * 1024),
+            schedule: Vec::new(),
+        }));
+        let started = Instant::now();
+        let mut first = throttle.wrap(io::sink());
+        let mut second = throttle.wrap(io::sink());
+        first.write_all(&[0u8; 25 * 1024]).unwrap();
+        second.write_all(&[0u8; 25 * 1024]).unwrap();
+        // 50KiB at 100KiB/s starting from an empty bucket.
+        assert!(started.elapsed() >= Duration::from_millis(450));
+    }
+
+    /// Takes at most a KiB per write.
+    struct Trickle;
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len().min(1024))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn charges_partial_writes_once() {
+        let throttle = Throttle::new(Some(Bandwidth {
+            limit: Some(100 * 1024),
+            schedule: Vec::new(),
+        }));
+        let started = Instant::now();
+        throttle.wrap(Trickle).write_all(&[0u8; 25 * 1024]).unwrap();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_millis(1000));
+    }
+}
@@ -0,0 +1,235 @@
+use crate::daemon::destination::DestinationCommand;
+use crate::daemon::template::TemplateContext;
+use slog::{debug, info, Logger};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// Running program of a command destination. The stream is written to its stdin.
+pub struct CommandSink {
+    program: String,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stderr: Option<JoinHandle<()>>,
+    /// Dropping the sender stops the watchdog.
+    watchdog: Option<mpsc::Sender<()>>,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl CommandSink {
+    pub fn spawn(
+        logger: &Logger,
+        config: &DestinationCommand,
+        ctx: &TemplateContext,
+    ) -> io::Result<Self> {
+        let program = config.program.display().to_string();
+        let args: Vec<String> = config.args.iter().map(|arg| arg.render(ctx)).collect();
+        debug!(logger, "Spawning {} {}", program, args.join(" "));
+        let mut command = Command::new(&config.program);
+        command
+            .args(&args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        for var in &config.env {
+            command.env(&var.name, var.value.render(ctx));
+        }
+        // Own process group, so whatever the program spawns is killed along with it.
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setpgid(0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = command.spawn().map_err(|e| {
+            io::Error::new(e.kind(), format!("Failed to spawn `{}`: {}", program, e))
+        })?;
+
+        let stdin = child.stdin.take();
+        let stderr = child.stderr.take().map(|stderr| {
+            let logger = logger.new(slog::o!("program" => program.clone()));
+            std::thread::spawn(move || {
+                for line in BufReader::new(stderr).lines() {
+                    match line {
+                        Ok(line) => info!(logger, "{}", line),
+                        Err(_) => break,
+                    }
+                }
+            })
+        });
+
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let watchdog = config
+            .timeout
+            .and_then(|timeout| timeout.to_std().ok())
+            .map(|timeout| {
+                let (tx, rx) = mpsc::channel::<()>();
+                let pid = child.id() as libc::pid_t;
+                let timed_out = timed_out.clone();
+                std::thread::spawn(move || {
+                    if let Err(mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(timeout) {
+                        timed_out.store(true, Ordering::SeqCst);
+                        kill_group(pid);
+                    }
+                });
+                tx
+            });
+
+        Ok(CommandSink {
+            program,
+            child,
+            stdin,
+            stderr,
+            watchdog,
+            timed_out,
+        })
+    }
+
+    /// Close stdin and wait for the program to exit. Anything but exit status 0 is an error.
+    pub fn finish(mut self) -> io::Result<()> {
+        drop(self.stdin.take());
+        let status = self.child.wait();
+        drop(self.watchdog.take());
+        let status = status?;
+        // Children that left the process group may hold stderr open, don't wait for them.
+        if self.timed_out.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("`{}` timed out and was killed", self.program),
+            ));
+        }
+        if let Some(stderr) = self.stderr.take() {
+            let _ = stderr.join();
+        }
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("`{}` failed: {}", self.program, status),
+            ))
+        }
+    }
+
+    fn stdin(&mut self) -> io::Result<&mut ChildStdin> {
+        let program = &self.program;
+        self.stdin.as_mut().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("stdin of `{}` is closed", program),
+            )
+        })
+    }
+
+    fn map_err(&self, e: io::Error) -> io::Error {
+        if self.timed_out.load(Ordering::SeqCst) {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("`{}` timed out and was killed", self.program),
+            )
+        } else {
+            e
+        }
+    }
+}
+
+impl Drop for CommandSink {
+    /// Transfer failed before `finish`: don't leave the program running.
+    fn drop(&mut self) {
+        if self.stdin.is_some() {
+            drop(self.watchdog.take());
+            drop(self.stdin.take());
+            kill_group(self.child.id() as libc::pid_t);
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Kill the program along with everything it spawned.
+fn kill_group(pid: libc::pid_t) {
+    unsafe { libc::kill(-pid, libc::SIGKILL) };
+}
+
+impl Write for CommandSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.stdin()?.write(buf) {
+            Ok(n) => Ok(n),
+            Err(e) => Err(self.map_err(e)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.stdin()?.flush() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.map_err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::daemon::destination::CommandEnv;
+    use crate::daemon::template::Template;
+    use crate::utils::TempDir;
+    use std::path::{Path, PathBuf};
+
+    fn ctx() -> TemplateContext<'static> {
+        TemplateContext {
+            host: "nimble",
+            task: "test",
+            pool: "z",
+            dataset: Path::new("z/usr/ports"),
+            snapshot: "gazpacho-20200301-1583020800",
+            source: None,
+            date: chrono::Utc::now(),
+            ext: "zfs",
+        }
+    }
+
+    fn sh(script: &str, timeout: Option<chrono::Duration>) -> DestinationCommand {
+        DestinationCommand {
+            program: PathBuf::from("/bin/sh"),
+            args: vec![
+                Template::parse("-c").unwrap(),
+                Template::parse(script).unwrap(),
+            ],
+            env: vec![CommandEnv {
+                name: String::from("KIND"),
+                value: Template::parse("{kind}").unwrap(),
+            }],
+            timeout,
+        }
+    }
+
+    #[test]
+    fn pipes_to_command() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let dir = TempDir::new("command");
+        let out = dir.join("out");
+        let script = format!("cat > {} && test \"$KIND\" = full", out.display());
+        let mut sink = CommandSink::spawn(&logger, &sh(&script, None), &ctx()).unwrap();
+        sink.write_all(b"stream").unwrap();
+        sink.finish().unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), b"stream");
+
+        let sink =
+            CommandSink::spawn(&logger, &sh("cat > /dev/null; exit 3", None), &ctx()).unwrap();
+        assert!(sink.finish().is_err());
+    }
+
+    #[test]
+    fn kills_on_timeout() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let config = sh("exec sleep 10", Some(chrono::Duration::milliseconds(200)));
+        let sink = CommandSink::spawn(&logger, &config, &ctx()).unwrap();
+        let err = sink.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
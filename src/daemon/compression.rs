@@ -0,0 +1,619 @@
+use slog::{warn, Logger};
+use std::io::{self, Read, Write};
+use uclicious::traits::TryInto;
+use uclicious::{FromObject, ObjectError, ObjectRef, Uclicious};
+
+/// Largest level that doesn't need `ultra`.
+pub const ZSTD_MAX_LEVEL: i32 = 19;
+/// Largest level allowed with `ultra`.
+pub const ZSTD_ULTRA_MAX_LEVEL: i32 = 22;
+/// Window zstd uses for long-distance matching unless told otherwise.
+pub const ZSTD_LONG_WINDOW_LOG: u32 = 27;
+/// Decoders refuse larger windows unless their limit is raised.
+pub const ZSTD_DEFAULT_WINDOW_LOG_MAX: u32 = 27;
+const ZSTD_WINDOW_LOG_RANGE: std::ops::RangeInclusive<u32> = 10..=31;
+
+#[derive(Uclicious, Clone, Debug)]
+#[ucl(skip_builder)]
+pub struct ZstdCompression {
+    #[ucl(default = "3")]
+    pub level: i32,
+    #[ucl(default = "1")]
+    pub workers: u32,
+    /// Long-distance matching, finds repetitions far apart in big streams.
+    #[ucl(default = "false")]
+    pub long: bool,
+    /// Log2 of the window size. Windows above 2^27 need the same limit set when decompressing.
+    #[ucl(default)]
+    pub window_log: Option<u32>,
+    /// Content checksum in every frame.
+    #[ucl(default = "true")]
+    pub checksum: bool,
+    /// Levels 20 to 22, implied by setting one. They use a lot of memory on both ends.
+    #[ucl(default = "false")]
+    pub ultra: bool,
+}
+
+impl ZstdCompression {
+    /// Window log set for the stream, `None` if it's left to the level.
+    pub fn window_log(&self) -> Option<u32> {
+        self.window_log
+            .or_else(|| Some(ZSTD_LONG_WINDOW_LOG).filter(|_| self.long))
+    }
+
+    /// Whether levels 20 to 22 are in use, levels above 19 imply `ultra`.
+    pub fn ultra(&self) -> bool {
+        self.ultra || self.level > ZSTD_MAX_LEVEL
+    }
+
+    /// Settings that are accepted, but likely not what was meant.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if !self.ultra && self.level > ZSTD_MAX_LEVEL {
+            warnings.push(format!(
+                "zstd level {} is above {} without ultra, ultra is implied",
+                self.level, ZSTD_MAX_LEVEL
+            ));
+        }
+        if self.ultra && self.level <= ZSTD_MAX_LEVEL {
+            warnings.push(format!(
+                "zstd ultra only applies to levels {}-{}, it has no effect at level {}",
+                ZSTD_MAX_LEVEL + 1,
+                ZSTD_ULTRA_MAX_LEVEL,
+                self.level
+            ));
+        }
+        warnings
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.level > ZSTD_ULTRA_MAX_LEVEL {
+            return Err(format!(
+                "zstd level {} is above {}",
+                self.level, ZSTD_ULTRA_MAX_LEVEL
+            ));
+        }
+        if let Some(window_log) = self.window_log {
+            if !ZSTD_WINDOW_LOG_RANGE.contains(&window_log) {
+                return Err(format!(
+                    "zstd window_log {} is out of {}-{} range",
+                    window_log,
+                    ZSTD_WINDOW_LOG_RANGE.start(),
+                    ZSTD_WINDOW_LOG_RANGE.end()
+                ));
+            }
+            if window_log > ZSTD_DEFAULT_WINDOW_LOG_MAX && !self.long && !self.ultra() {
+                return Err(format!(
+                    "zstd window_log {} requires long or ultra",
+                    window_log
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fast and light on CPU, for hosts that can't spare much of it.
+#[derive(Uclicious, Clone, Debug)]
+#[ucl(skip_builder)]
+pub struct Lz4Compression {
+    #[ucl(default = "1")]
+    pub level: u32,
+}
+
+/// Slow, but compresses best. For cold archives.
+#[derive(Uclicious, Clone, Debug)]
+#[ucl(skip_builder)]
+pub struct XzCompression {
+    #[ucl(default = "6")]
+    pub level: u32,
+}
+
+/// For compatibility with tooling that doesn't know anything else.
+#[derive(Uclicious, Clone, Debug)]
+#[ucl(skip_builder)]
+pub struct GzipCompression {
+    #[ucl(default = "6")]
+    pub level: u32,
+}
+
+/// Codec a stream is compressed with, configured as a block named after the codec.
+#[derive(Clone, Debug)]
+pub enum Compression {
+    Zstd(ZstdCompression),
+    Lz4(Lz4Compression),
+    Xz(XzCompression),
+    Gzip(GzipCompression),
+}
+
+impl Compression {
+    pub fn codec(&self) -> &'static str {
+        match self {
+            Compression::Zstd(_) => "zstd",
+            Compression::Lz4(_) => "lz4",
+            Compression::Xz(_) => "xz",
+            Compression::Gzip(_) => "gzip",
+        }
+    }
+
+    /// Suffix added to backup file name.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Zstd(_) => "zst",
+            Compression::Lz4(_) => "lz4",
+            Compression::Xz(_) => "xz",
+            Compression::Gzip(_) => "gz",
+        }
+    }
+
+    pub fn level(&self) -> i32 {
+        match self {
+            Compression::Zstd(zstd) => zstd.level,
+            Compression::Lz4(lz4) => lz4.level as i32,
+            Compression::Xz(xz) => xz.level as i32,
+            Compression::Gzip(gzip) => gzip.level as i32,
+        }
+    }
+
+    /// Log2 of the window size a decoder has to accept, if it's not the default.
+    pub fn window_log(&self) -> Option<u32> {
+        match self {
+            Compression::Zstd(zstd) => zstd.window_log(),
+            _ => None,
+        }
+    }
+
+    /// Number of compression threads.
+    pub fn workers(&self) -> u32 {
+        match self {
+            Compression::Zstd(zstd) => zstd.workers,
+            _ => 1,
+        }
+    }
+
+    /// Settings that are accepted, but likely not what was meant.
+    pub fn warnings(&self) -> Vec<String> {
+        match self {
+            Compression::Zstd(zstd) => zstd.warnings(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Compression {
+    /// Same codec and settings at a different level.
+    pub fn with_level(&self, level: i32) -> Compression {
+        match self {
+            Compression::Zstd(zstd) => Compression::Zstd(ZstdCompression {
+                level,
+                ultra: zstd.ultra && level > ZSTD_MAX_LEVEL,
+                ..zstd.clone()
+            }),
+            Compression::Lz4(_) => Compression::Lz4(Lz4Compression {
+                level: level.max(0) as u32,
+            }),
+            Compression::Xz(_) => Compression::Xz(XzCompression {
+                level: level.max(0).min(9) as u32,
+            }),
+            Compression::Gzip(_) => Compression::Gzip(GzipCompression {
+                level: level.max(0).min(9) as u32,
+            }),
+        }
+    }
+
+    /// Cheapest level of the codec that still compresses.
+    pub fn lowest_level(&self) -> i32 {
+        match self {
+            Compression::Xz(_) => 0,
+            _ => 1,
+        }
+    }
+}
+
+impl FromObject<ObjectRef> for Compression {
+    // There is unwrap in it, but that's okay because nested keys always have key name.
+    fn try_from(value: ObjectRef) -> Result<Self, ObjectError> {
+        if value.iter().count() > 1 {
+            return Err(ObjectError::Other(
+                "Only one compression codec can be defined".to_string(),
+            ));
+        }
+        let ret = {
+            value
+                .iter()
+                .map(|obj| match obj.key().unwrap().as_str() {
+                    "zstd" => {
+                        let c: ZstdCompression = obj.try_into()?;
+                        c.validate().map_err(ObjectError::Other)?;
+                        Ok(Compression::Zstd(c))
+                    }
+                    "lz4" => {
+                        let c: Lz4Compression = obj.try_into()?;
+                        Ok(Compression::Lz4(c))
+                    }
+                    "xz" => {
+                        let c: XzCompression = obj.try_into()?;
+                        if c.level > 9 {
+                            return Err(ObjectError::Other(format!(
+                                "xz level {} is out of 0-9 range",
+                                c.level
+                            )));
+                        }
+                        Ok(Compression::Xz(c))
+                    }
+                    "gzip" => {
+                        let c: GzipCompression = obj.try_into()?;
+                        if c.level > 9 {
+                            return Err(ObjectError::Other(format!(
+                                "gzip level {} is out of 0-9 range",
+                                c.level
+                            )));
+                        }
+                        Ok(Compression::Gzip(c))
+                    }
+                    codec => Err(ObjectError::Other(format!(
+                        "Compression codec \"{}\" is not supported.",
+                        codec
+                    ))),
+                })
+                .next()
+        };
+        ret.unwrap_or_else(|| {
+            Err(ObjectError::Other(
+                "Please define compression codec to use".to_string(),
+            ))
+        })
+    }
+}
+
+/// Lower the effort or skip compression for streams that barely compress. Ratio is compressed
+/// size divided by raw size.
+#[derive(Uclicious, Clone, Debug)]
+#[ucl(skip_builder)]
+pub struct AdaptiveCompression {
+    /// Bytes from the start of the stream compressed with configured settings to measure the
+    /// ratio. With `0` the ratio of recent transfers of the dataset is used instead.
+    #[ucl(default = "8 * 1024 * 1024")]
+    pub sample: u64,
+    /// Store uncompressed if the sampled ratio is above this.
+    #[ucl(default = "0.95")]
+    pub store_above: f64,
+    /// Use `lower_level` if the ratio is above this.
+    #[ucl(default = "0.8")]
+    pub lower_above: f64,
+    /// Defaults to the lowest level of the codec.
+    #[ucl(default)]
+    pub lower_level: Option<i32>,
+}
+
+/// Where the ratio an adaptive decision was based on came from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RatioSource {
+    Sample,
+    History,
+}
+
+impl std::fmt::Display for RatioSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RatioSource::Sample => write!(f, "sampled"),
+            RatioSource::History => write!(f, "historical"),
+        }
+    }
+}
+
+/// Outcome of adaptive compression, recorded in the step log.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompressionDecision {
+    Keep(RatioSource, f64),
+    Lower(RatioSource, f64, i32),
+    Store(RatioSource, f64),
+}
+
+impl std::fmt::Display for CompressionDecision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompressionDecision::Keep(source, ratio) => {
+                write!(f, "kept configured level, {} ratio {:.3}", source, ratio)
+            }
+            CompressionDecision::Lower(source, ratio, level) => {
+                write!(
+                    f,
+                    "lowered to level {}, {} ratio {:.3}",
+                    level, source, ratio
+                )
+            }
+            CompressionDecision::Store(source, ratio) => {
+                write!(f, "stored uncompressed, {} ratio {:.3}", source, ratio)
+            }
+        }
+    }
+}
+
+impl AdaptiveCompression {
+    /// Compression to use given the ratio measured with `compression`. Historical ratio never
+    /// leads to storing uncompressed.
+    pub fn decide(
+        &self,
+        compression: &Compression,
+        source: RatioSource,
+        ratio: f64,
+    ) -> (Option<Compression>, CompressionDecision) {
+        if ratio > self.store_above && source == RatioSource::Sample {
+            return (None, CompressionDecision::Store(source, ratio));
+        }
+        let lower_level = self
+            .lower_level
+            .unwrap_or_else(|| compression.lowest_level());
+        if ratio > self.lower_above && lower_level < compression.level() {
+            let lowered = compression.with_level(lower_level);
+            return (
+                Some(lowered),
+                CompressionDecision::Lower(source, ratio, lower_level),
+            );
+        }
+        (
+            Some(compression.clone()),
+            CompressionDecision::Keep(source, ratio),
+        )
+    }
+}
+
+/// Ratio `sample` compresses to with `compression`.
+pub fn sample_ratio(logger: &Logger, compression: &Compression, sample: &[u8]) -> io::Result<f64> {
+    if sample.is_empty() {
+        return Ok(1.0);
+    }
+    let mut compressor = Compressor::new(logger, CountingWriter::default(), compression)?;
+    compressor.write_all(sample)?;
+    let counter = compressor.finish()?;
+    Ok(counter.0 as f64 / sample.len() as f64)
+}
+
+#[derive(Default)]
+struct CountingWriter(u64);
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writer that compresses with the configured codec.
+pub enum Compressor<W: Write> {
+    Zstd(zstd::Encoder<W>),
+    Lz4(lz4::Encoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+    Gzip(flate2::write::GzEncoder<W>),
+}
+
+impl<W: Write> Compressor<W> {
+    pub fn new(logger: &Logger, output: W, compression: &Compression) -> io::Result<Self> {
+        let compressor = match compression {
+            Compression::Zstd(zstd) => {
+                let mut encoder = zstd::Encoder::new(output, zstd.level)?;
+                if let Err(e) = encoder.multithread(zstd.workers) {
+                    warn!(logger, "Failed to set zstd multithreading: {}", e);
+                }
+                encoder.include_checksum(zstd.checksum)?;
+                encoder.long_distance_matching(zstd.long)?;
+                if let Some(window_log) = zstd.window_log {
+                    encoder.window_log(window_log)?;
+                }
+                Compressor::Zstd(encoder)
+            }
+            Compression::Lz4(lz4) => {
+                Compressor::Lz4(lz4::EncoderBuilder::new().level(lz4.level).build(output)?)
+            }
+            Compression::Xz(xz) => Compressor::Xz(xz2::write::XzEncoder::new(output, xz.level)),
+            Compression::Gzip(gzip) => Compressor::Gzip(flate2::write::GzEncoder::new(
+                output,
+                flate2::Compression::new(gzip.level),
+            )),
+        };
+        Ok(compressor)
+    }
+
+    /// Write out the end of the stream and return the output.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Compressor::Zstd(encoder) => encoder.finish(),
+            Compressor::Lz4(encoder) => {
+                let (output, result) = encoder.finish();
+                result.map(|_| output)
+            }
+            Compressor::Xz(encoder) => encoder.finish(),
+            Compressor::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Compressor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Compressor::Zstd(encoder) => encoder.write(buf),
+            Compressor::Lz4(encoder) => encoder.write(buf),
+            Compressor::Xz(encoder) => encoder.write(buf),
+            Compressor::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Compressor::Zstd(encoder) => encoder.flush(),
+            Compressor::Lz4(encoder) => encoder.flush(),
+            Compressor::Xz(encoder) => encoder.flush(),
+            Compressor::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Decompress a backup made with `codec`, as recorded in its manifest.
+pub fn decoder<'a, R: Read + 'a>(
+    codec: &str,
+    window_log: Option<u32>,
+    input: R,
+) -> io::Result<Box<dyn Read + 'a>> {
+    let decoder: Box<dyn Read + 'a> = match codec {
+        "zstd" => {
+            let mut decoder = zstd::Decoder::new(input)?;
+            if let Some(window_log) = window_log.filter(|w| *w > ZSTD_DEFAULT_WINDOW_LOG_MAX) {
+                decoder.window_log_max(window_log)?;
+            }
+            Box::new(decoder)
+        }
+        "lz4" => Box::new(lz4::Decoder::new(input)?),
+        "xz" => Box::new(xz2::read::XzDecoder::new(input)),
+        "gzip" => Box::new(flate2::read::GzDecoder::new(input)),
+        codec => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown compression codec `{}`", codec),
+            ))
+        }
+    };
+    Ok(decoder)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn zstd(level: i32) -> ZstdCompression {
+        ZstdCompression {
+            level,
+            workers: 1,
+            long: false,
+            window_log: None,
+            checksum: true,
+            ultra: false,
+        }
+    }
+
+    #[test]
+    fn adaptive_decisions() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let adaptive = AdaptiveCompression {
+            sample: 1024 * 1024,
+            store_above: 0.95,
+            lower_above: 0.8,
+            lower_level: None,
+        };
+        let compression = Compression::Zstd(zstd(16));
+
+        let zeroes = vec![0u8; 1024 * 1024];
+        let ratio = sample_ratio(&logger, &compression, &zeroes).unwrap();
+        let (kept, decision) = adaptive.decide(&compression, RatioSource::Sample, ratio);
+        assert_eq!(kept.map(|c| c.level()), Some(16));
+        assert_eq!(
+            decision,
+            CompressionDecision::Keep(RatioSource::Sample, ratio)
+        );
+
+        // xorshift output doesn't compress at all.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let noise: Vec<u8> = (0..1024 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let ratio = sample_ratio(&logger, &compression, &noise).unwrap();
+        let (stored, _) = adaptive.decide(&compression, RatioSource::Sample, ratio);
+        assert!(stored.is_none());
+        let (lowered, decision) = adaptive.decide(&compression, RatioSource::History, ratio);
+        assert_eq!(lowered.map(|c| c.level()), Some(1));
+        assert_eq!(
+            decision,
+            CompressionDecision::Lower(RatioSource::History, ratio, 1)
+        );
+    }
+
+    #[test]
+    fn zstd_validation() {
+        assert!(zstd(19).validate().is_ok());
+        assert!(zstd(19).warnings().is_empty());
+        assert!(zstd(20).validate().is_ok());
+        assert!(zstd(20).ultra());
+        assert_eq!(zstd(20).warnings().len(), 1);
+        assert!(zstd(23).validate().is_err());
+        let ultra = ZstdCompression {
+            ultra: true,
+            ..zstd(22)
+        };
+        assert!(ultra.validate().is_ok());
+        assert!(ultra.warnings().is_empty());
+        let pointless = ZstdCompression {
+            ultra: true,
+            ..zstd(3)
+        };
+        assert!(pointless.validate().is_ok());
+        assert_eq!(pointless.warnings().len(), 1);
+        let window = ZstdCompression {
+            window_log: Some(30),
+            ..zstd(3)
+        };
+        assert!(window.validate().is_err());
+        let long = ZstdCompression {
+            long: true,
+            ..window
+        };
+        assert!(long.validate().is_ok());
+        assert_eq!(long.window_log(), Some(30));
+        assert_eq!(
+            ZstdCompression {
+                long: true,
+                ..zstd(3)
+            }
+            .window_log(),
+            Some(ZSTD_LONG_WINDOW_LOG)
+        );
+        assert!(ZstdCompression {
+            window_log: Some(9),
+            ..zstd(3)
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn codecs_roundtrip() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let data: Vec<u8> = (0..64 * 1024).map(|idx| (idx % 7) as u8).collect();
+        let codecs = vec![
+            Compression::Zstd(zstd(3)),
+            Compression::Zstd(ZstdCompression {
+                long: true,
+                window_log: Some(28),
+                ..zstd(3)
+            }),
+            Compression::Lz4(Lz4Compression { level: 1 }),
+            Compression::Xz(XzCompression { level: 6 }),
+            Compression::Gzip(GzipCompression { level: 6 }),
+        ];
+        for compression in codecs {
+            let mut compressor = Compressor::new(&logger, Vec::new(), &compression).unwrap();
+            compressor.write_all(&data).unwrap();
+            let compressed = compressor.finish().unwrap();
+            assert!(compressed.len() < data.len(), "{}", compression.codec());
+
+            let mut decompressed = Vec::new();
+            decoder(
+                compression.codec(),
+                compression.window_log(),
+                compressed.as_slice(),
+            )
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+            assert_eq!(decompressed, data, "{}", compression.codec());
+        }
+    }
+}
@@ -1,34 +1,190 @@
+use crate::daemon::compression::{AdaptiveCompression, Compression};
 use crate::daemon::destination::Destination;
+use crate::daemon::encryption::Encryption;
+use crate::daemon::notify::Notify;
 use crate::daemon::strategy::Strategy;
 use chrono::Duration;
 use std::collections::HashMap;
 use std::path::PathBuf;
-use uclicious::Uclicious;
+use uclicious::traits::TryInto;
+use uclicious::{FromObject, ObjectError, ObjectRef, Uclicious};
 
-#[derive(Uclicious, Clone, Debug)]
-#[ucl(skip_builder)]
-pub struct ZstdCompression {
-    #[ucl(default = "3")]
-    pub level: i32,
-    #[ucl(default = "1")]
-    pub workers: u32,
+/// Parse `destination` blocks of the configuration, rejecting ones that can't be used.
+fn destinations_config_from_object(
+    value: ObjectRef,
+) -> Result<HashMap<String, Destination>, ObjectError> {
+    let destinations: HashMap<String, Destination> = value.try_into()?;
+    for (name, destination) in destinations.iter() {
+        destination
+            .validate()
+            .map_err(|e| ObjectError::Other(format!("Destination `{}`: {}", name, e)))?;
+    }
+    Ok(destinations)
 }
 
-#[derive(Uclicious, Clone, Debug)]
-#[ucl(skip_builder)]
-pub struct Compression {
-    pub zstd: ZstdCompression,
+/// Destination a task sends its stream to.
+#[derive(Clone, Debug)]
+pub struct TaskDestination {
+    pub name: String,
+    /// Overrides compression of the task for this destination.
+    pub compression: Option<Compression>,
+    /// Overrides `fallback` of the destination.
+    pub fallback: Option<String>,
+}
+
+impl TaskDestination {
+    pub fn new(name: String) -> Self {
+        TaskDestination {
+            name,
+            compression: None,
+            fallback: None,
+        }
+    }
+}
+
+/// Parse `destination` of a task: a single name, a list of names, or named blocks.
+pub fn destinations_from_object(value: ObjectRef) -> Result<Vec<TaskDestination>, ObjectError> {
+    let destinations = value
+        .iter()
+        .map(|obj| {
+            if let Some(name) = obj.as_string() {
+                return Ok(TaskDestination::new(name));
+            }
+            let name = obj.key().ok_or_else(|| {
+                ObjectError::Other("Destination of a task must be named".to_string())
+            })?;
+            let compression = match obj.lookup("compression") {
+                Some(compression) => Some(compression.try_into()?),
+                None => None,
+            };
+            let fallback = match obj.lookup("fallback") {
+                Some(fallback) => Some(fallback.try_into()?),
+                None => None,
+            };
+            Ok(TaskDestination {
+                name,
+                compression,
+                fallback,
+            })
+        })
+        .collect::<Result<Vec<TaskDestination>, ObjectError>>()?;
+    if destinations.is_empty() {
+        return Err(ObjectError::Other(
+            "Please define destination to use".to_string(),
+        ));
+    }
+    for (idx, destination) in destinations.iter().enumerate() {
+        if destinations[..idx]
+            .iter()
+            .any(|other| other.name == destination.name)
+        {
+            return Err(ObjectError::Other(format!(
+                "Destination `{}` is listed more than once",
+                destination.name
+            )));
+        }
+    }
+    Ok(destinations)
+}
+
+/// Parse `dataset_rpo` of a task, RPO overrides keyed by dataset.
+pub fn dataset_rpo_from_object(value: ObjectRef) -> Result<HashMap<String, Duration>, ObjectError> {
+    value
+        .iter()
+        .map(|obj| {
+            let dataset = obj.key().ok_or_else(|| {
+                ObjectError::Other("RPO of a dataset must be keyed by its name".to_string())
+            })?;
+            let rpo = crate::utils::time_to_chrono(obj)?.ok_or_else(|| {
+                ObjectError::Other(format!("RPO of \"{}\" must be a duration", dataset))
+            })?;
+            Ok((dataset, rpo))
+        })
+        .collect()
+}
+
+/// What happens with a step when only some of the destinations received the stream.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DestinationPolicy {
+    /// Step fails unless every destination succeeded.
+    All,
+    /// Step succeeds if at least one destination succeeded.
+    Any,
+}
+
+impl DestinationPolicy {
+    pub fn is_satisfied(&self, succeeded: usize, total: usize) -> bool {
+        match self {
+            DestinationPolicy::All => succeeded == total,
+            DestinationPolicy::Any => succeeded > 0,
+        }
+    }
+}
+
+impl Default for DestinationPolicy {
+    fn default() -> Self {
+        DestinationPolicy::All
+    }
+}
+
+impl FromObject<ObjectRef> for DestinationPolicy {
+    fn try_from(value: ObjectRef) -> Result<Self, ObjectError> {
+        let policy: String = value.try_into()?;
+        match policy.as_str() {
+            "all" => Ok(DestinationPolicy::All),
+            "any" => Ok(DestinationPolicy::Any),
+            policy => Err(ObjectError::Other(format!(
+                "Destination policy \"{}\" is not supported.",
+                policy
+            ))),
+        }
+    }
 }
 
 #[derive(Uclicious, Clone, Debug)]
 #[ucl(skip_builder)]
 pub struct Task {
-    pub destination: String,
+    #[ucl(path = "destination", map = "destinations_from_object")]
+    pub destinations: Vec<TaskDestination>,
+    #[ucl(default)]
+    pub destination_policy: DestinationPolicy,
     pub strategy: Strategy,
     #[ucl(default)]
     pub compression: Option<Compression>,
+    /// Lower compression effort for streams that barely compress.
+    #[ucl(default)]
+    pub adaptive_compression: Option<AdaptiveCompression>,
+    /// Encrypt streams before they leave the host. Applies to every destination of the task.
+    #[ucl(default)]
+    pub encryption: Option<Encryption>,
     #[ucl(default = "1")]
     pub parallelism: u32,
+    /// Every dataset of the task should have a completed backup younger than this.
+    #[ucl(default, map = "crate::utils::time_to_chrono")]
+    pub rpo: Option<Duration>,
+    /// Overrides `rpo` for individual datasets.
+    #[ucl(default, map = "dataset_rpo_from_object")]
+    pub dataset_rpo: HashMap<String, Duration>,
+}
+
+impl Task {
+    /// RPO the dataset is held to, if any.
+    pub fn rpo_for(&self, dataset: &str) -> Option<Duration> {
+        self.dataset_rpo.get(dataset).cloned().or(self.rpo)
+    }
+
+    /// Whether any dataset of the task is held to an RPO.
+    pub fn has_rpo(&self) -> bool {
+        self.rpo.is_some() || !self.dataset_rpo.is_empty()
+    }
+
+    /// Compression to use for the given destination.
+    pub fn compression_for(&self, destination: &TaskDestination) -> Option<Compression> {
+        destination
+            .compression
+            .clone()
+            .or_else(|| self.compression.clone())
+    }
 }
 
 #[derive(Uclicious, Clone, Debug, Default)]
@@ -69,6 +225,21 @@ pub struct LogSysLog {
     pub socket: PathBuf,
 }
 
+/// Prometheus metrics export. Either output or both may be enabled.
+#[derive(Uclicious, Clone, Debug)]
+#[ucl(skip_builder)]
+pub struct Metrics {
+    /// Address to serve `/metrics` on, e.g. `127.0.0.1:9810`.
+    #[ucl(default)]
+    pub listen: Option<String>,
+    /// File to write metrics to for node_exporter's textfile collector.
+    #[ucl(default)]
+    pub textfile: Option<PathBuf>,
+    /// How often `textfile` is rewritten, every minute by default.
+    #[ucl(default, map = "crate::utils::time_to_chrono")]
+    pub interval: Option<Duration>,
+}
+
 #[derive(Uclicious, Clone, Debug)]
 #[ucl(skip_builder)]
 pub struct Daemon {
@@ -77,12 +248,17 @@ pub struct Daemon {
     pub cleanup_interval: Option<Duration>,
     #[ucl(default = "false")]
     pub cleanup_on_startup: bool,
+    /// Unix socket to accept control commands on, disabled if not set.
+    #[ucl(default)]
+    pub control_socket: Option<PathBuf>,
+    #[ucl(default)]
+    pub metrics: Option<Metrics>,
 }
 
 #[derive(Uclicious, Clone, Debug)]
 pub struct Configuration {
     pub daemon: Daemon,
-    #[ucl(path = "destination")]
+    #[ucl(path = "destination", map = "destinations_config_from_object")]
     pub destinations: HashMap<String, Destination>,
     #[ucl(path = "task")]
     pub tasks: HashMap<String, Task>,
@@ -90,4 +266,39 @@ pub struct Configuration {
     pub logging: Log,
     #[ucl(default = "1")]
     pub parallelism: u32,
+    #[ucl(default)]
+    pub notify: Option<Notify>,
+}
+
+impl Configuration {
+    /// Settings that are accepted, but likely not what was meant. Logged once logging is set up.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (name, task) in &self.tasks {
+            let compressions = task.compression.iter().chain(
+                task.destinations
+                    .iter()
+                    .filter_map(|d| d.compression.as_ref()),
+            );
+            for compression in compressions {
+                for warning in compression.warnings() {
+                    warnings.push(format!("Task `{}`: {}", name, warning));
+                }
+            }
+        }
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn destination_policy() {
+        assert!(DestinationPolicy::All.is_satisfied(2, 2));
+        assert!(!DestinationPolicy::All.is_satisfied(1, 2));
+        assert!(DestinationPolicy::Any.is_satisfied(1, 2));
+        assert!(!DestinationPolicy::Any.is_satisfied(0, 2));
+    }
 }
@@ -0,0 +1,174 @@
+use crate::daemon::measure::CHECKSUM_ALGORITHM;
+use crate::daemon::system::actors::destination_manager::DestinationManager;
+use crate::daemon::system::actors::task_manager::messages::{
+    GetProgress, GetRpoBreaches, GetStepChecksums, GetStepHistory,
+};
+use crate::daemon::system::actors::task_manager::TaskManager;
+use crate::daemon::system::messages::destination_manager::{TestDestination, VerifyBackup};
+use actix::Addr;
+use futures::executor::block_on;
+use slog::{debug, error, info, warn, Logger};
+use std::fs::Permissions;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::thread::JoinHandle;
+
+/// Steps listed by `history`.
+const HISTORY_LIMIT: u32 = 50;
+
+/// Addresses of actors control commands are forwarded to. Has to be built inside the actor system.
+#[derive(Clone)]
+pub struct ControlContext {
+    pub destinations: Addr<DestinationManager>,
+    pub tasks: Addr<TaskManager>,
+}
+
+/// Serve control commands on a unix socket, one command per connection.
+pub fn spawn(logger: Logger, path: PathBuf, ctx: ControlContext) -> io::Result<JoinHandle<()>> {
+    // Leftover from a previous run that didn't shut down cleanly.
+    if path.exists() {
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    // Anyone who can connect can run commands, keep it to the user the daemon runs as.
+    std::fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+    info!(
+        logger,
+        "Listening for control commands on {}",
+        path.display()
+    );
+    Ok(std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let logger = logger.clone();
+                    let ctx = ctx.clone();
+                    // Commands like destination self-test may take a while.
+                    std::thread::spawn(move || {
+                        if let Err(e) = handle_connection(&logger, &ctx, stream) {
+                            warn!(logger, "Control connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!(logger, "Failed to accept control connection: {}", e),
+            }
+        }
+    }))
+}
+
+fn handle_connection(
+    logger: &Logger,
+    ctx: &ControlContext,
+    mut stream: UnixStream,
+) -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    debug!(logger, "Received control command: {}", line.trim());
+    let response = execute(ctx, line.trim());
+    writeln!(stream, "{}", response)
+}
+
+fn execute(ctx: &ControlContext, command: &str) -> String {
+    let mut args = command.split_whitespace();
+    match (args.next(), args.next()) {
+        (Some("test-destination"), Some(name)) => {
+            match block_on(
+                ctx.destinations
+                    .send(TestDestination::new(name.to_string())),
+            ) {
+                Ok(Ok(report)) => report.to_string(),
+                Ok(Err(e)) => format!("Error: {}", e),
+                Err(e) => format!("Error: {}", e),
+            }
+        }
+        (Some("test-destination"), None) => String::from("Usage: test-destination <name>"),
+        (Some("verify"), Some(destination)) => match args.next() {
+            Some(target) => verify(ctx, destination, PathBuf::from(target)),
+            None => String::from("Usage: verify <destination> <path>"),
+        },
+        (Some("verify"), None) => String::from("Usage: verify <destination> <path>"),
+        (Some("history"), Some(task)) => {
+            let dataset = args.next().map(PathBuf::from);
+            let msg = GetStepHistory::new(task.to_string(), dataset, HISTORY_LIMIT);
+            match block_on(ctx.tasks.send(msg)) {
+                Ok(Ok(history)) if history.is_empty() => String::from("No history"),
+                Ok(Ok(history)) => history
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Ok(Err(e)) => format!("Error: {}", e),
+                Err(e) => format!("Error: {}", e),
+            }
+        }
+        (Some("history"), None) => String::from("Usage: history <task> [dataset]"),
+        (Some("progress"), task) => {
+            match block_on(ctx.tasks.send(GetProgress::new(task.map(String::from)))) {
+                Ok(progress) if progress.is_empty() => String::from("No running tasks"),
+                Ok(progress) => progress
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Err(e) => format!("Error: {}", e),
+            }
+        }
+        (Some("rpo"), task) => {
+            match block_on(ctx.tasks.send(GetRpoBreaches::new(task.map(String::from)))) {
+                Ok(breaches) if breaches.is_empty() => String::from("All datasets are within RPO"),
+                Ok(breaches) => breaches
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Err(e) => format!("Error: {}", e),
+            }
+        }
+        _ => format!("Unknown command: `{}`", command),
+    }
+}
+
+/// Read a backup back and compare it with checksums from its manifest and the step log. Manifest
+/// lives next to the backup, so only the step log is trusted to be untouched.
+fn verify(ctx: &ControlContext, destination: &str, target: PathBuf) -> String {
+    let msg = VerifyBackup::new(destination.to_string(), target.clone());
+    let stored = match block_on(ctx.destinations.send(msg)) {
+        Ok(Ok(stored)) => stored,
+        Ok(Err(e)) => return format!("Error: {}", e),
+        Err(e) => return format!("Error: {}", e),
+    };
+    let manifest = &stored.manifest;
+    let msg = GetStepChecksums::new(
+        manifest.task.clone(),
+        PathBuf::from(&manifest.dataset),
+        manifest.snapshot.clone(),
+    );
+    let recorded = match block_on(ctx.tasks.send(msg)) {
+        Ok(Ok(checksums)) => checksums.and_then(|mut c| c.written.remove(destination)),
+        Ok(Err(e)) => return format!("Error: {}", e),
+        Err(e) => return format!("Error: {}", e),
+    };
+    let compare = |expected: Option<&String>| match expected {
+        Some(expected) if *expected == stored.checksum => "ok",
+        Some(_) => "MISMATCH",
+        None => "not recorded",
+    };
+    let manifest_checksum = manifest.checksum.as_ref().map(|checksum| &checksum.written);
+    let size = if stored.bytes == manifest.bytes_written {
+        "ok"
+    } else {
+        "MISMATCH"
+    };
+    format!(
+        "{}: {} bytes, {} {}\nsize: {}\nmanifest: {}\nstep log: {}",
+        target.display(),
+        stored.bytes,
+        CHECKSUM_ALGORITHM,
+        stored.checksum,
+        size,
+        compare(manifest_checksum),
+        compare(recorded.as_ref())
+    )
+}
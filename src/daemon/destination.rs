@@ -1,14 +1,92 @@
-use std::net::SocketAddr;
+use crate::daemon::bandwidth::Bandwidth;
+use crate::daemon::template::Template;
+use chrono::Duration;
 use std::path::PathBuf;
-use uclicious::Uclicious;
+use uclicious::traits::TryInto;
+use uclicious::{FromObject, ObjectError, ObjectRef, Uclicious};
 
 #[derive(Uclicious, Clone, Debug, Hash)]
 #[ucl(skip_builder)]
 pub struct DestinationSsh {
     pub username: String,
-    pub identity_file: PathBuf,
+    /// Identity files to try in order. The key can be repeated to list several identities.
+    #[ucl(default, path = "identity_file")]
+    pub identity_files: Vec<Identity>,
+    /// File containing the passphrase for encrypted identity files that don't have their own.
+    #[ucl(default)]
+    pub passphrase_file: Option<PathBuf>,
+    /// Try identities offered by ssh-agent before identity files.
+    #[ucl(default = "false")]
+    pub agent: bool,
     pub folder: PathBuf,
-    pub host: SocketAddr,
+    /// Hostname, IPv4 or IPv6 address. Resolved at connect time.
+    pub host: String,
+    #[ucl(default = "22")]
+    pub port: u16,
+    /// How long an unused ssh session is kept open for reuse.
+    #[ucl(default, map = "crate::utils::time_to_chrono")]
+    pub idle_timeout: Option<Duration>,
+    /// Known hosts file used to verify host key. Defaults to `~/.ssh/known_hosts` and
+    /// `/etc/ssh/ssh_known_hosts`.
+    #[ucl(default)]
+    pub known_hosts: Option<PathBuf>,
+    /// Connect without verifying host key.
+    #[ucl(default = "false")]
+    pub insecure_skip_host_key: bool,
+    /// Bastion to tunnel the connection through.
+    #[ucl(default)]
+    pub jump: Option<DestinationSshJump>,
+}
+
+/// Private key to authenticate with. Configured as a path, or as a block with `path` and
+/// `passphrase_file` for a key with a passphrase of its own.
+#[derive(Clone, Debug, Hash)]
+pub struct Identity {
+    pub path: PathBuf,
+    /// Overrides `passphrase_file` of the host for this key.
+    pub passphrase_file: Option<PathBuf>,
+}
+
+impl FromObject<ObjectRef> for Identity {
+    fn try_from(value: ObjectRef) -> Result<Self, ObjectError> {
+        if let Some(path) = value.as_string() {
+            return Ok(Identity {
+                path: PathBuf::from(path),
+                passphrase_file: None,
+            });
+        }
+        let path = value
+            .lookup("path")
+            .ok_or_else(|| ObjectError::Other("Identity file must have a path".to_string()))?
+            .try_into()?;
+        let passphrase_file = match value.lookup("passphrase_file") {
+            Some(passphrase_file) => Some(passphrase_file.try_into()?),
+            None => None,
+        };
+        Ok(Identity {
+            path,
+            passphrase_file,
+        })
+    }
+}
+
+#[derive(Uclicious, Clone, Debug, Hash)]
+#[ucl(skip_builder)]
+pub struct DestinationSshJump {
+    pub username: String,
+    #[ucl(default, path = "identity_file")]
+    pub identity_files: Vec<Identity>,
+    #[ucl(default)]
+    pub passphrase_file: Option<PathBuf>,
+    #[ucl(default = "false")]
+    pub agent: bool,
+    pub host: String,
+    #[ucl(default = "22")]
+    pub port: u16,
+    #[ucl(default)]
+    pub known_hosts: Option<PathBuf>,
+    #[ucl(default = "false")]
+    pub insecure_skip_host_key: bool,
 }
 
 #[derive(Uclicious, Clone, Debug, Hash)]
@@ -17,6 +95,70 @@ pub struct DestinationLocal {
     pub folder: PathBuf,
 }
 
+/// Environment variable of a command destination, configured as `NAME=value`. Value is a template.
+#[derive(Clone, Debug, Hash)]
+pub struct CommandEnv {
+    pub name: String,
+    pub value: Template,
+}
+
+impl FromObject<ObjectRef> for CommandEnv {
+    fn try_from(value: ObjectRef) -> Result<Self, ObjectError> {
+        let var: String = value.try_into()?;
+        let idx = var.find('=').filter(|idx| *idx > 0).ok_or_else(|| {
+            ObjectError::Other(format!("\"{}\" is not in NAME=value format", var))
+        })?;
+        let value =
+            Template::parse(&var[idx + 1..]).map_err(|e| ObjectError::Other(e.to_string()))?;
+        Ok(CommandEnv {
+            name: var[..idx].to_string(),
+            value,
+        })
+    }
+}
+
+/// Program that gets the stream on stdin. Non-zero exit status fails the transfer.
+#[derive(Uclicious, Clone, Debug, Hash)]
+#[ucl(skip_builder)]
+pub struct DestinationCommand {
+    /// Path to the executable, `PATH` is not searched.
+    pub program: PathBuf,
+    /// See `template` module for placeholders.
+    #[ucl(default)]
+    pub args: Vec<Template>,
+    /// Added to the environment of the daemon.
+    #[ucl(default)]
+    pub env: Vec<CommandEnv>,
+    /// Program is killed if it runs for longer than this.
+    #[ucl(default, map = "crate::utils::time_to_chrono")]
+    pub timeout: Option<Duration>,
+}
+
+/// Space that must stay free on destination after a backup is written.
+#[derive(Uclicious, Clone, Debug, Hash, Default)]
+#[ucl(skip_builder)]
+pub struct FreeSpace {
+    /// Bytes.
+    #[ucl(default)]
+    pub min_free: u64,
+    /// Percent of the filesystem size.
+    #[ucl(default)]
+    pub min_free_percent: u8,
+    /// Keep checking for this long before skipping the dataset.
+    #[ucl(default, map = "crate::utils::time_to_chrono")]
+    pub defer: Option<Duration>,
+}
+
+impl FreeSpace {
+    /// Whether `required` bytes fit into `available` while keeping the reserve.
+    pub fn is_sufficient(&self, available: u64, total: u64, required: u64) -> bool {
+        let reserve = self
+            .min_free
+            .max(total / 100 * u64::from(self.min_free_percent));
+        available >= required.saturating_add(reserve)
+    }
+}
+
 #[derive(Uclicious, Clone, Debug, Hash)]
 #[ucl(skip_builder)]
 pub struct Destination {
@@ -26,8 +168,57 @@ pub struct Destination {
     pub chmod: i32,
     #[ucl(default = "0o700")]
     pub chmod_dir: i32,
+    /// Folders to create inside destination folder, see `template` module for placeholders.
+    #[ucl(default = "Template::default_path()")]
+    pub path_template: Template,
+    #[ucl(default = "Template::default_filename()")]
+    pub filename_template: Template,
+    /// Roll over to a new volume file (`.000`, `.001`, ...) once this many bytes are written.
+    #[ucl(default)]
+    pub split_size: Option<u64>,
+    /// Limit shared by all parallel transfers to this destination.
+    #[ucl(default)]
+    pub bandwidth: Option<Bandwidth>,
+    /// Size of each buffer between reading, compression and writing stages of a transfer.
+    #[ucl(default = "16 * 1024 * 1024")]
+    pub buffer_size: u64,
+    /// Record checksums of streams in manifests.
+    #[ucl(default = "true")]
+    pub checksum: bool,
+    /// Move streams into local files with `splice(2)` instead of copying them. Requires
+    /// `checksum = false`, streams that are compressed or encrypted are still copied.
+    #[ucl(default = "false")]
+    pub zero_copy: bool,
+    #[ucl(default)]
+    pub free_space: FreeSpace,
+    /// Destination to send to when this one can't be reached.
+    #[ucl(default)]
+    pub fallback: Option<String>,
     #[ucl(default)]
     pub ssh: Option<DestinationSsh>,
     #[ucl(default)]
     pub local: Option<DestinationLocal>,
+    #[ucl(default)]
+    pub command: Option<DestinationCommand>,
+}
+
+impl Destination {
+    /// Exactly one of `ssh`, `local` and `command` has to be set, and zero-copy leaves nothing to
+    /// checksum.
+    pub fn validate(&self) -> Result<(), String> {
+        let targets = [
+            self.ssh.is_some(),
+            self.local.is_some(),
+            self.command.is_some(),
+        ];
+        match targets.iter().filter(|set| **set).count() {
+            0 => Err("one of ssh, local or command is required".to_string()),
+            1 => Ok(()),
+            _ => Err("only one of ssh, local or command can be set".to_string()),
+        }?;
+        if self.zero_copy && self.checksum {
+            return Err("zero_copy can't be used with checksum, set checksum = false".to_string());
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,229 @@
+use age::stream::{StreamReader, StreamWriter};
+use age::{x25519, Decryptor, Encryptor, IdentityFile};
+use secrecy::SecretString;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use uclicious::traits::TryInto;
+use uclicious::{FromObject, ObjectError, ObjectRef, Uclicious};
+
+#[derive(Uclicious, Clone, Debug)]
+#[ucl(skip_builder)]
+pub struct AgeEncryption {
+    /// `age1...` public keys. Any of the matching identities can decrypt the file.
+    pub recipients: Vec<String>,
+}
+
+#[derive(Uclicious, Clone, Debug)]
+#[ucl(skip_builder)]
+pub struct PassphraseEncryption {
+    /// File with a passphrase. Trailing whitespace is ignored.
+    pub key_file: PathBuf,
+}
+
+/// Client-side encryption of the stream, both variants produce regular age files.
+#[derive(Clone, Debug)]
+pub enum Encryption {
+    Age(AgeEncryption),
+    Passphrase(PassphraseEncryption),
+}
+
+impl Encryption {
+    /// Name of the scheme recorded in manifests.
+    pub fn scheme(&self) -> &'static str {
+        match self {
+            Encryption::Age(_) => "age-x25519",
+            Encryption::Passphrase(_) => "age-scrypt",
+        }
+    }
+
+    /// Recipients needed to decrypt the file. Nothing derived from a passphrase is recorded, it
+    /// would be open to offline guessing.
+    pub fn key_id(&self) -> Option<String> {
+        match self {
+            Encryption::Age(age) => Some(age.recipients.join(",")),
+            Encryption::Passphrase(_) => None,
+        }
+    }
+
+    /// Wrap `output` in an age writer. The writer must be finished with `MaybeEncrypted::finish`.
+    pub fn wrap_output<W: Write>(&self, output: W) -> io::Result<StreamWriter<W>> {
+        let encryptor = match self {
+            Encryption::Age(age) => {
+                let recipients = parse_recipients(&age.recipients)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+                    .into_iter()
+                    .map(|recipient| Box::new(recipient) as Box<dyn age::Recipient>)
+                    .collect();
+                Encryptor::with_recipients(recipients)
+            }
+            Encryption::Passphrase(passphrase) => {
+                let key = read_key_file(&passphrase.key_file)?;
+                Encryptor::with_user_passphrase(SecretString::new(key))
+            }
+        };
+        encryptor.wrap_output(output).map_err(|e| match e {
+            age::EncryptError::Io(e) => e,
+        })
+    }
+}
+
+impl FromObject<ObjectRef> for Encryption {
+    fn try_from(value: ObjectRef) -> Result<Self, ObjectError> {
+        if value.iter().count() > 1 {
+            return Err(ObjectError::Other(
+                "Only one encryption scheme can be defined".to_string(),
+            ));
+        }
+        let ret = value
+            .iter()
+            .map(|obj| match obj.key().unwrap().as_str() {
+                "age" => {
+                    let age: AgeEncryption = obj.try_into()?;
+                    if age.recipients.is_empty() {
+                        return Err(ObjectError::Other(
+                            "Please define at least one age recipient".to_string(),
+                        ));
+                    }
+                    parse_recipients(&age.recipients).map_err(ObjectError::Other)?;
+                    Ok(Encryption::Age(age))
+                }
+                "passphrase" => {
+                    let passphrase: PassphraseEncryption = obj.try_into()?;
+                    Ok(Encryption::Passphrase(passphrase))
+                }
+                enc => Err(ObjectError::Other(format!(
+                    "Encryption \"{}\" is not supported.",
+                    enc
+                ))),
+            })
+            .next();
+        ret.unwrap_or_else(|| {
+            Err(ObjectError::Other(
+                "Please define encryption to use".to_string(),
+            ))
+        })
+    }
+}
+
+/// Writer that encrypts everything written to it, or passes it through as is.
+pub enum MaybeEncrypted<W: Write> {
+    Plain(W),
+    Encrypted(StreamWriter<W>),
+}
+
+impl<W: Write> MaybeEncrypted<W> {
+    pub fn new(output: W, encryption: &Option<Encryption>) -> io::Result<Self> {
+        match encryption {
+            Some(encryption) => encryption
+                .wrap_output(output)
+                .map(MaybeEncrypted::Encrypted),
+            None => Ok(MaybeEncrypted::Plain(output)),
+        }
+    }
+
+    /// Write the final chunk and return the underlying writer. Without it an encrypted file is
+    /// truncated and fails to decrypt.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            MaybeEncrypted::Plain(output) => Ok(output),
+            MaybeEncrypted::Encrypted(writer) => writer.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for MaybeEncrypted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MaybeEncrypted::Plain(output) => output.write(buf),
+            MaybeEncrypted::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MaybeEncrypted::Plain(output) => output.flush(),
+            MaybeEncrypted::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Open an encrypted backup for restore. `key_file` is an age identity file for files encrypted
+/// to recipients, or the passphrase file for files encrypted with a passphrase.
+pub fn decrypt<R: Read>(input: R, key_file: &Path) -> io::Result<StreamReader<R>> {
+    let decryptor = Decryptor::new(input).map_err(decrypt_error)?;
+    match decryptor {
+        Decryptor::Recipients(decryptor) => {
+            let identities = IdentityFile::from_file(key_file.to_string_lossy().to_string())?
+                .into_identities()
+                .into_iter()
+                .map(|identity| Box::new(identity) as Box<dyn age::Identity>);
+            decryptor.decrypt(identities).map_err(decrypt_error)
+        }
+        Decryptor::Passphrase(decryptor) => {
+            let key = SecretString::new(read_key_file(key_file)?);
+            decryptor.decrypt(&key, None).map_err(decrypt_error)
+        }
+    }
+}
+
+fn decrypt_error(e: age::DecryptError) -> io::Error {
+    match e {
+        age::DecryptError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)),
+    }
+}
+
+fn parse_recipients(recipients: &[String]) -> Result<Vec<x25519::Recipient>, String> {
+    recipients
+        .iter()
+        .map(|recipient| {
+            x25519::Recipient::from_str(recipient)
+                .map_err(|e| format!("Invalid age recipient \"{}\": {}", recipient, e))
+        })
+        .collect()
+}
+
+fn read_key_file(path: &Path) -> io::Result<String> {
+    let key = std::fs::read_to_string(path)?;
+    let key = key.trim_end().to_string();
+    if key.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Key file {} is empty", path.display()),
+        ));
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn roundtrip_with_recipients() {
+        let identity = x25519::Identity::generate();
+        let encryption = Encryption::Age(AgeEncryption {
+            recipients: vec![identity.to_public().to_string()],
+        });
+        let dir = TempDir::new("enc");
+        let identity_file = dir.join("identity.txt");
+        {
+            use secrecy::ExposeSecret;
+            std::fs::write(&identity_file, identity.to_string().expose_secret()).unwrap();
+        }
+
+        let mut writer = MaybeEncrypted::new(Vec::new(), &Some(encryption)).unwrap();
+        writer.write_all(b"zfs send stream").unwrap();
+        let encrypted = writer.finish().unwrap();
+        assert_ne!(&encrypted[..], b"zfs send stream");
+
+        let mut decrypted = Vec::new();
+        decrypt(&encrypted[..], &identity_file)
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(&decrypted[..], b"zfs send stream");
+    }
+}
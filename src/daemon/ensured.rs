@@ -1,13 +1,26 @@
-use crate::daemon::config::Compression;
+use crate::daemon::bandwidth::Throttle;
+use crate::daemon::compression::Compression;
 use crate::daemon::destination::{Destination, DestinationLocal, DestinationSsh};
-use chrono::{DateTime, Utc};
-use slog::{debug, error, trace, Logger};
-use ssh2::{File as SftpFile, OpenFlags, OpenType, Session, Sftp};
+use crate::daemon::encryption::Encryption;
+use crate::daemon::manifest::Manifest;
+use crate::daemon::measure::Measured;
+use crate::daemon::splice;
+use crate::daemon::template::TemplateContext;
+use filedescriptor::FileDescriptor;
+use pool::{PooledSession, SessionPool};
+use slog::{debug, trace, Logger};
+use ssh2::{File as SftpFile, OpenFlags, OpenType, Sftp};
 use std::fmt::{Display, Formatter};
-use std::fs::{File as LocalFile, File};
+use std::fs::{File as LocalFile, File, Permissions};
 use std::io::Write;
-use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod jump;
+pub mod pool;
+pub mod probe;
+mod ssh;
 
 pub enum EnsuredError {
     Ssh(ssh2::Error),
@@ -15,6 +28,16 @@ pub enum EnsuredError {
     MissingConfiguration,
     DuplicateConfiguration,
     RootFolderNotFound(PathBuf),
+    Resolve(String, std::io::Error),
+    Connect(String, std::io::Error),
+    Passphrase(PathBuf, std::io::Error),
+    HostKey(String, String),
+    Tunnel(String, String),
+    Authentication {
+        username: String,
+        host: String,
+        attempts: Vec<String>,
+    },
 }
 
 impl Display for EnsuredError {
@@ -29,6 +52,50 @@ impl Display for EnsuredError {
             EnsuredError::RootFolderNotFound(e) => {
                 write!(f, "Destination root folder `{}` doesn't exist", e.display())
             }
+            EnsuredError::Resolve(host, e) => write!(f, "Failed to resolve `{}`: {}", host, e),
+            EnsuredError::Connect(host, e) => write!(f, "Failed to connect to `{}`: {}", host, e),
+            EnsuredError::Passphrase(path, e) => write!(
+                f,
+                "Failed to read passphrase file `{}`: {}",
+                path.display(),
+                e
+            ),
+            EnsuredError::HostKey(host, reason) => {
+                write!(f, "Host key verification for `{}` failed: {}", host, reason)
+            }
+            EnsuredError::Tunnel(host, reason) => {
+                write!(
+                    f,
+                    "Failed to tunnel to `{}` through jump host: {}",
+                    host, reason
+                )
+            }
+            EnsuredError::Authentication {
+                username,
+                host,
+                attempts,
+            } => write!(
+                f,
+                "Failed to authenticate as `{}` on `{}`, tried: {}",
+                username,
+                host,
+                attempts.join("; ")
+            ),
+        }
+    }
+}
+
+impl EnsuredError {
+    /// Whether the destination couldn't be reached or refused to let us in, as opposed to
+    /// failing once connected.
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            EnsuredError::Resolve(..)
+            | EnsuredError::Connect(..)
+            | EnsuredError::HostKey(..)
+            | EnsuredError::Tunnel(..)
+            | EnsuredError::Authentication { .. } => true,
+            _ => false,
         }
     }
 }
@@ -45,67 +112,462 @@ impl From<std::io::Error> for EnsuredError {
     }
 }
 
-pub enum EnsuredDestination {
-    SftpFile(SftpFile, Session, Sftp),
-    LocalFile(LocalFile),
+/// Suffix of files that are still being written. Anything listing backups on a destination must
+/// skip them.
+pub const PARTIAL_EXTENSION: &str = "partial";
+
+/// Whether the file is an uncommitted backup.
+pub fn is_partial(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext == PARTIAL_EXTENSION)
+        .unwrap_or(false)
+}
+
+/// Create a file on a local destination with `mode`, regardless of umask.
+pub fn create_local_file(path: &Path, mode: i32) -> std::io::Result<File> {
+    let file = File::create(path)?;
+    file.set_permissions(Permissions::from_mode(mode as u32))?;
+    Ok(file)
+}
+
+/// Create a directory on a local destination with `mode`, regardless of umask.
+pub fn create_local_dir(path: &Path, mode: i32) -> std::io::Result<()> {
+    std::fs::create_dir(path)?;
+    std::fs::set_permissions(path, Permissions::from_mode(mode as u32))
+}
+
+/// Backup file written under a temporary name. It becomes visible under `target` only after
+/// it's committed.
+#[derive(Debug, Clone)]
+pub struct PendingFile {
+    pub partial: PathBuf,
+    pub target: PathBuf,
+}
+
+impl PendingFile {
+    pub fn new(target: PathBuf) -> Self {
+        let mut partial = target.clone().into_os_string();
+        partial.push(".");
+        partial.push(PARTIAL_EXTENSION);
+        PendingFile {
+            partial: PathBuf::from(partial),
+            target,
+        }
+    }
+
+    /// Rename the file into its final name.
+    pub fn commit(
+        &self,
+        logger: &Logger,
+        dst: &Destination,
+        pool: &SessionPool,
+    ) -> Result<(), EnsuredError> {
+        debug!(logger, "Committing {}", self.target.display());
+        match (&dst.ssh, &dst.local) {
+            (Some(dst_ssh), None) => {
+                let pooled = pool.checkout(logger, dst_ssh)?;
+                pooled.sftp.rename(&self.partial, &self.target, None)?;
+                pool.checkin(pooled);
+                Ok(())
+            }
+            (None, Some(_)) => {
+                std::fs::rename(&self.partial, &self.target)?;
+                if let Some(parent) = self.target.parent() {
+                    File::open(parent)?.sync_all()?;
+                }
+                Ok(())
+            }
+            (None, None) => Err(EnsuredError::MissingConfiguration),
+            (Some(_), Some(_)) => Err(EnsuredError::DuplicateConfiguration),
+        }
+    }
+
+    /// Remove the file after it was committed, when the rest of the backup couldn't be.
+    pub fn revert(
+        &self,
+        logger: &Logger,
+        dst: &Destination,
+        pool: &SessionPool,
+    ) -> Result<(), EnsuredError> {
+        debug!(logger, "Reverting {}", self.target.display());
+        match (&dst.ssh, &dst.local) {
+            (Some(dst_ssh), None) => {
+                let pooled = pool.checkout(logger, dst_ssh)?;
+                pooled.sftp.unlink(&self.target)?;
+                pool.checkin(pooled);
+                Ok(())
+            }
+            (None, Some(_)) => std::fs::remove_file(&self.target).map_err(Into::into),
+            (None, None) => Err(EnsuredError::MissingConfiguration),
+            (Some(_), Some(_)) => Err(EnsuredError::DuplicateConfiguration),
+        }
+    }
+
+    /// Remove the partially written file.
+    pub fn discard(
+        &self,
+        logger: &Logger,
+        dst: &Destination,
+        pool: &SessionPool,
+    ) -> Result<(), EnsuredError> {
+        debug!(logger, "Discarding {}", self.partial.display());
+        match (&dst.ssh, &dst.local) {
+            (Some(dst_ssh), None) => {
+                let pooled = pool.checkout(logger, dst_ssh)?;
+                pooled.sftp.unlink(&self.partial)?;
+                pool.checkin(pooled);
+                Ok(())
+            }
+            (None, Some(_)) => std::fs::remove_file(&self.partial).map_err(Into::into),
+            (None, None) => Err(EnsuredError::MissingConfiguration),
+            (Some(_), Some(_)) => Err(EnsuredError::DuplicateConfiguration),
+        }
+    }
+}
+
+/// Write manifest next to the committed backup. Manifest goes through the same partial file and
+/// rename dance as the backup itself.
+pub fn write_manifest(
+    logger: &Logger,
+    dst: &Destination,
+    pool: &SessionPool,
+    target: &Path,
+    manifest: &Manifest,
+) -> Result<(), EnsuredError> {
+    let data = manifest.to_json().map_err(|e| EnsuredError::Io(e.into()))?;
+    let manifest = PendingFile::new(Manifest::path_for(target));
+    debug!(logger, "Writing manifest {}", manifest.target.display());
+    match (&dst.ssh, &dst.local) {
+        (Some(dst_ssh), None) => {
+            let pooled = pool.checkout(logger, dst_ssh)?;
+            let open_flags = OpenFlags::WRITE | OpenFlags::TRUNCATE;
+            let mut file =
+                pooled
+                    .sftp
+                    .open_mode(&manifest.partial, open_flags, dst.chmod, OpenType::File)?;
+            file.write_all(&data)?;
+            file.fsync()?;
+            drop(file);
+            pooled
+                .sftp
+                .rename(&manifest.partial, &manifest.target, None)?;
+            pool.checkin(pooled);
+            Ok(())
+        }
+        (None, Some(_)) => {
+            let mut file = create_local_file(&manifest.partial, dst.chmod)?;
+            file.write_all(&data)?;
+            file.sync_all()?;
+            std::fs::rename(&manifest.partial, &manifest.target)?;
+            Ok(())
+        }
+        (None, None) => Err(EnsuredError::MissingConfiguration),
+        (Some(_), Some(_)) => Err(EnsuredError::DuplicateConfiguration),
+    }
+}
+
+/// Available and total bytes on the filesystem holding the destination folder. The folder itself
+/// may not exist yet, so the closest existing ancestor is checked.
+pub fn free_space(
+    logger: &Logger,
+    dst: &Destination,
+    pool: &SessionPool,
+) -> Result<(u64, u64), EnsuredError> {
+    match (&dst.ssh, &dst.local) {
+        (Some(dst_ssh), None) => {
+            let pooled = pool.checkout(logger, dst_ssh)?;
+            let folder = dst_ssh
+                .folder
+                .ancestors()
+                .find(|dir| pooled.sftp.stat(dir).is_ok())
+                .unwrap_or_else(|| Path::new("/"));
+            // statvfs@openssh.com on a directory handle.
+            let stat = pooled.sftp.opendir(folder)?.statvfs()?;
+            pool.checkin(pooled);
+            Ok((stat.f_bavail * stat.f_frsize, stat.f_blocks * stat.f_frsize))
+        }
+        (None, Some(dst_local)) => {
+            let folder = dst_local
+                .folder
+                .ancestors()
+                .find(|dir| dir.exists())
+                .unwrap_or_else(|| Path::new("/"));
+            local_statvfs(folder).map_err(Into::into)
+        }
+        (None, None) => Err(EnsuredError::MissingConfiguration),
+        (Some(_), Some(_)) => Err(EnsuredError::DuplicateConfiguration),
+    }
+}
+
+fn local_statvfs(path: &Path) -> std::io::Result<(u64, u64)> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let frsize = stat.f_frsize as u64;
+    Ok((stat.f_bavail as u64 * frsize, stat.f_blocks as u64 * frsize))
+}
+
+/// Extension of a backup file, `{ext}` in templates: `zfs`, `zfs.xz`, `zfs.zst.age`, etc.
+pub fn file_extension(
+    compression: &Option<Compression>,
+    encryption: &Option<Encryption>,
+) -> String {
+    let mut ext = String::from("zfs");
+    if let Some(compression) = compression {
+        ext.push('.');
+        ext.push_str(compression.extension());
+    }
+    if encryption.is_some() {
+        ext.push_str(".age");
+    }
+    ext
+}
+
+/// Name of volume `idx` of a split backup: `a.zfs.zst.000`, `a.zfs.zst.001`, etc.
+pub fn volume_path(target: &Path, idx: usize) -> PathBuf {
+    let mut path = target.to_path_buf().into_os_string();
+    path.push(format!(".{:03}", idx));
+    PathBuf::from(path)
+}
+
+/// Remove whatever was written for `target` after a failed transfer, be it a single partial file
+/// or a series of partial volumes. Volumes are created in order, so the first missing one ends
+/// the series.
+pub fn discard_partial(
+    logger: &Logger,
+    dst: &Destination,
+    pool: &SessionPool,
+    target: &Path,
+) -> Result<(), EnsuredError> {
+    if split_size(dst).is_none() {
+        return PendingFile::new(target.to_path_buf()).discard(logger, dst, pool);
+    }
+    for idx in 0.. {
+        let volume = PendingFile::new(volume_path(target, idx));
+        let exists = match (&dst.ssh, &dst.local) {
+            (Some(dst_ssh), None) => {
+                let pooled = pool.checkout(logger, dst_ssh)?;
+                let exists = pooled.sftp.stat(&volume.partial).is_ok();
+                pool.checkin(pooled);
+                exists
+            }
+            (None, Some(_)) => volume.partial.exists(),
+            (None, None) => return Err(EnsuredError::MissingConfiguration),
+            (Some(_), Some(_)) => return Err(EnsuredError::DuplicateConfiguration),
+        };
+        if !exists {
+            break;
+        }
+        volume.discard(logger, dst, pool)?;
+    }
+    Ok(())
+}
+
+/// Remove partial files last modified before `before` anywhere under the destination folder.
+/// Returns number of removed files.
+pub fn remove_stale_partials(
+    logger: &Logger,
+    dst: &Destination,
+    pool: &SessionPool,
+    before: SystemTime,
+) -> Result<usize, EnsuredError> {
+    match (&dst.ssh, &dst.local) {
+        (Some(dst_ssh), None) => {
+            let pooled = pool.checkout(logger, dst_ssh)?;
+            if pooled.sftp.stat(&dst_ssh.folder).is_err() {
+                pool.checkin(pooled);
+                return Ok(0);
+            }
+            let before = before
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or(0);
+            let mut removed = 0;
+            let mut folders = vec![dst_ssh.folder.clone()];
+            while let Some(folder) = folders.pop() {
+                for (path, stat) in pooled.sftp.readdir(&folder)? {
+                    if stat.is_dir() {
+                        folders.push(path);
+                    } else if is_partial(&path) && stat.mtime.map_or(false, |t| t < before) {
+                        debug!(logger, "Removing stale {}", path.display());
+                        pooled.sftp.unlink(&path)?;
+                        removed += 1;
+                    }
+                }
+            }
+            pool.checkin(pooled);
+            Ok(removed)
+        }
+        (None, Some(dst_local)) => {
+            if !dst_local.folder.exists() {
+                return Ok(0);
+            }
+            let mut removed = 0;
+            let mut folders = vec![dst_local.folder.clone()];
+            while let Some(folder) = folders.pop() {
+                for entry in std::fs::read_dir(&folder)? {
+                    let entry = entry?;
+                    let path = entry.path();
+                    let file_type = entry.file_type()?;
+                    if file_type.is_dir() {
+                        folders.push(path);
+                    } else if file_type.is_file()
+                        && is_partial(&path)
+                        && entry.metadata()?.modified()? < before
+                    {
+                        debug!(logger, "Removing stale {}", path.display());
+                        std::fs::remove_file(&path)?;
+                        removed += 1;
+                    }
+                }
+            }
+            Ok(removed)
+        }
+        (None, None) => Err(EnsuredError::MissingConfiguration),
+        (Some(_), Some(_)) => Err(EnsuredError::DuplicateConfiguration),
+    }
+}
+
+fn split_size(dst: &Destination) -> Option<u64> {
+    dst.split_size.filter(|size| *size > 0)
+}
+
+/// Finished file of a backup along with its size and checksum.
+#[derive(Debug, Clone)]
+pub struct PendingVolume {
+    pub file: PendingFile,
+    pub bytes: u64,
+    pub checksum: Option<String>,
+}
+
+enum Output {
+    Sftp(SftpFile, PooledSession),
+    Local(LocalFile),
+}
+
+impl Output {
+    fn sync(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Sftp(file, _) => {
+                file.flush()?;
+                file.fsync().map_err(Into::into)
+            }
+            Output::Local(file) => {
+                file.flush()?;
+                file.sync_all()
+            }
+        }
+    }
+}
+
+impl Write for Output {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::Sftp(f, _) => f.write(buf),
+            Output::Local(f) => f.write(buf),
+        }
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Sftp(f, _) => f.flush(),
+            Output::Local(f) => f.flush(),
+        }
+    }
+}
+
+/// Backup file opened for writing on a destination. With `split_size` set on the destination
+/// the stream is spread over numbered volumes, each one hashed separately.
+pub struct EnsuredDestination {
+    /// `None` only if rolling over to the next volume failed half-way.
+    output: Option<Measured<Output>>,
+    pending: PendingFile,
+    target: PathBuf,
+    split_size: Option<u64>,
+    chmod: i32,
+    /// Whether checksums of volumes are recorded.
+    checksum: bool,
+    volumes: Vec<PendingVolume>,
 }
 
 impl EnsuredDestination {
     pub fn ensure(
         logger: &Logger,
         dst: &Destination,
-        dataset: PathBuf,
+        pool: &SessionPool,
         compression: &Option<Compression>,
-        _logger: &Logger,
-        today: DateTime<Utc>,
+        encryption: &Option<Encryption>,
+        ctx: TemplateContext,
     ) -> Result<Self, EnsuredError> {
-        let file_ext = {
-            if compression.is_some() {
-                "zfs.zst"
-            } else {
-                "zfs"
-            }
-        };
+        let ext = file_extension(compression, encryption);
+        let ctx = TemplateContext { ext: &ext, ..ctx };
 
-        let dst_file_name = {
-            let date = today.format("%Y%m%d");
-            let timestamp = today.timestamp();
-            let basename = dataset.to_string_lossy().replace("/", "_");
-            let filename = format!("{}-{}-{}.{}", date, timestamp, basename, file_ext);
-            PathBuf::from(filename)
-        };
-        let date_folder = {
-            let mut path = PathBuf::new();
-            let year = today.format("%Y");
-            let month = today.format("%m");
-            let day = today.format("%d");
-            path.push(PathBuf::from(year.to_string()));
-            path.push(PathBuf::from(month.to_string()));
-            path.push(PathBuf::from(day.to_string()));
-            path
-        };
-        match (&dst.ssh, &dst.local) {
+        let dst_file_name = PathBuf::from(dst.filename_template.render(&ctx));
+        let date_folder = PathBuf::from(dst.path_template.render(&ctx));
+        let split_size = split_size(dst);
+        let (output, target) = match (&dst.ssh, &dst.local) {
             (None, None) => Err(EnsuredError::MissingConfiguration),
             (Some(dst_ssh), None) => Self::ensure_sftp_file(
                 logger,
+                pool,
                 dst_ssh,
                 date_folder,
                 dst_file_name,
+                split_size.is_some(),
+                dst.chmod,
+                dst.chmod_dir,
+            ),
+            (None, Some(dst_local)) => Self::ensure_local_file(
+                logger,
+                dst_local,
+                date_folder,
+                dst_file_name,
+                split_size.is_some(),
                 dst.chmod,
                 dst.chmod_dir,
             ),
-            (None, Some(dst_local)) => {
-                Self::ensure_local_file(logger, dst_local, date_folder, dst_file_name)
-            }
             (Some(_), Some(_)) => Err(EnsuredError::DuplicateConfiguration),
+        }?;
+        let pending = Self::first_file(&target, split_size.is_some());
+        Ok(EnsuredDestination {
+            output: Some(Measured::new(output)),
+            pending,
+            target,
+            split_size,
+            chmod: dst.chmod,
+            checksum: dst.checksum,
+            volumes: Vec::new(),
+        })
+    }
+
+    /// Path the backup is known by. Volumes of a split backup are named after it.
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+
+    fn first_file(target: &Path, split: bool) -> PendingFile {
+        if split {
+            PendingFile::new(volume_path(target, 0))
+        } else {
+            PendingFile::new(target.to_path_buf())
         }
     }
+
     fn ensure_local_file(
         _logger: &Logger,
         dst: &DestinationLocal,
         date_folder: PathBuf,
         dst_file: PathBuf,
-    ) -> Result<Self, EnsuredError> {
+        split: bool,
+        chmod: i32,
+        chmod_dir: i32,
+    ) -> Result<(Output, PathBuf), EnsuredError> {
         let dst_folder = {
             let mut path = PathBuf::from(&dst.folder);
             path.push(date_folder);
@@ -116,19 +578,37 @@ impl EnsuredDestination {
             path.push(dst_file);
             path
         };
-        std::fs::create_dir_all(&dst_folder)?;
-        let file = File::create(&full_dst_file_path)?;
-        Ok(EnsuredDestination::LocalFile(file))
+        // File name template may contain folders too.
+        let dst_folder = full_dst_file_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or(dst_folder);
+        let pending = Self::first_file(&full_dst_file_path, split);
+        // Root folder is created too, just like over ssh.
+        let missing: Vec<&Path> = dst_folder
+            .ancestors()
+            .filter(|dir| dir.starts_with(&dst.folder) && !dir.exists())
+            .collect();
+        for dir in missing.into_iter().rev() {
+            match create_local_dir(dir, chmod_dir) {
+                Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                res => res?,
+            }
+        }
+        let file = create_local_file(&pending.partial, chmod)?;
+        Ok((Output::Local(file), full_dst_file_path))
     }
 
     fn ensure_sftp_file(
         logger: &Logger,
+        pool: &SessionPool,
         dst: &DestinationSsh,
         date_folder: PathBuf,
         dst_file: PathBuf,
+        split: bool,
         chmod: i32,
         chmod_dir: i32,
-    ) -> Result<Self, EnsuredError> {
+    ) -> Result<(Output, PathBuf), EnsuredError> {
         let dst_folder = {
             let mut path = PathBuf::from(&dst.folder);
             path.push(date_folder);
@@ -139,30 +619,136 @@ impl EnsuredDestination {
             path.push(dst_file);
             path
         };
+        // File name template may contain folders too.
+        let dst_folder = full_dst_file_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or(dst_folder);
         debug!(
             logger,
             "Full path to destination: {}",
             full_dst_file_path.display()
         );
 
-        let mut sess = Session::new()?;
-        let tcp = TcpStream::connect(&dst.host)?;
-        sess.set_tcp_stream(tcp);
-        sess.handshake()?;
-        sess.userauth_pubkey_file(&dst.username, None, &dst.identity_file, None)?;
-        debug!(logger, "Established ssh session with remote server");
-        let sftp = sess.sftp().map_err(|e| {
-            error!(logger, "{}", e);
-            e
-        })?;
-        trace!(logger, "Established SFTP channel");
-        ensure_root_dir_ssh(&sftp, &dst, chmod_dir)?;
-        trace!(logger, "Ensured root folder");
-        ensure_dst_dir_ssh(&sftp, &dst, &dst_folder, chmod_dir)?;
-        trace!(logger, "Ensured dst folder");
-        let open_flags = OpenFlags::WRITE | OpenFlags::TRUNCATE;
-        let file = sftp.open_mode(&full_dst_file_path, open_flags, chmod, OpenType::File)?;
-        Ok(EnsuredDestination::SftpFile(file, sess, sftp))
+        let pending = Self::first_file(&full_dst_file_path, split);
+        let pooled = pool.checkout(logger, dst)?;
+        let open = |sftp: &Sftp| -> Result<SftpFile, EnsuredError> {
+            ensure_root_dir_ssh(sftp, &dst, chmod_dir)?;
+            trace!(logger, "Ensured root folder");
+            ensure_dst_dir_ssh(sftp, &dst, &dst_folder, chmod_dir)?;
+            trace!(logger, "Ensured dst folder");
+            let open_flags = OpenFlags::WRITE | OpenFlags::TRUNCATE;
+            let file = sftp.open_mode(&pending.partial, open_flags, chmod, OpenType::File)?;
+            Ok(file)
+        };
+        match open(&pooled.sftp) {
+            Ok(file) => Ok((Output::Sftp(file, pooled), full_dst_file_path)),
+            Err(EnsuredError::Ssh(e)) if pooled.is_reused() => {
+                debug!(logger, "Pooled ssh session failed, reconnecting: {}", e);
+                drop(pooled);
+                let pooled = pool.connect(logger, dst)?;
+                let file = open(&pooled.sftp)?;
+                Ok((Output::Sftp(file, pooled), full_dst_file_path))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sync the current volume and open the next one in the same folder, over the same session.
+    fn roll_over(&mut self) -> std::io::Result<()> {
+        let mut output = self.output.take().ok_or_else(closed)?;
+        output.get_mut().sync()?;
+        let next = PendingFile::new(volume_path(&self.target, self.volumes.len() + 1));
+        self.volumes.push(PendingVolume {
+            file: std::mem::replace(&mut self.pending, next),
+            bytes: output.bytes(),
+            checksum: Some(output.checksum()).filter(|_| self.checksum),
+        });
+        let output = match output.into_inner() {
+            Output::Sftp(file, pooled) => {
+                drop(file);
+                let open_flags = OpenFlags::WRITE | OpenFlags::TRUNCATE;
+                let file = pooled.sftp.open_mode(
+                    &self.pending.partial,
+                    open_flags,
+                    self.chmod,
+                    OpenType::File,
+                )?;
+                Output::Sftp(file, pooled)
+            }
+            Output::Local(file) => {
+                drop(file);
+                Output::Local(create_local_file(&self.pending.partial, self.chmod)?)
+            }
+        };
+        self.output = Some(Measured::new(output));
+        Ok(())
+    }
+
+    /// Move the whole pipe into the backup file with `splice(2)`. Only an unsplit local file on a
+    /// filesystem that supports it can take it, `None` is returned without touching the pipe
+    /// otherwise.
+    pub fn splice_from(
+        &mut self,
+        input: &FileDescriptor,
+        throttle: &Throttle,
+    ) -> Option<std::io::Result<u64>> {
+        if self.split_size.is_some() {
+            return None;
+        }
+        let output = self.output.as_mut()?;
+        let moved = match output.get_ref() {
+            Output::Local(file) => splice::splice_all(input, file, throttle).transpose()?,
+            Output::Sftp(..) => return None,
+        };
+        if let Ok(bytes) = moved {
+            output.skip(bytes);
+        }
+        Some(moved)
+    }
+
+    /// Flush and fsync the destination after a successful transfer. Ssh sessions go back to the
+    /// pool. Volumes still have to be committed.
+    pub fn finish(mut self, pool: &SessionPool) -> Result<Vec<PendingVolume>, EnsuredError> {
+        let mut output = self.output.take().ok_or_else(closed)?;
+        output.get_mut().sync()?;
+        self.volumes.push(PendingVolume {
+            file: self.pending,
+            bytes: output.bytes(),
+            checksum: Some(output.checksum()).filter(|_| self.checksum),
+        });
+        if let Output::Sftp(file, pooled) = output.into_inner() {
+            drop(file);
+            pool.checkin(pooled);
+        }
+        Ok(self.volumes)
+    }
+}
+
+fn closed() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::BrokenPipe,
+        "destination was closed after a failed roll over",
+    )
+}
+
+impl Write for EnsuredDestination {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let split_size = match self.split_size {
+            Some(split_size) => split_size,
+            None => return self.output.as_mut().ok_or_else(closed)?.write(buf),
+        };
+        let written = self.output.as_ref().ok_or_else(closed)?.bytes();
+        if written >= split_size {
+            self.roll_over()?;
+        }
+        let written = self.output.as_ref().ok_or_else(closed)?.bytes();
+        let room = (split_size - written).min(buf.len() as u64) as usize;
+        self.output.as_mut().ok_or_else(closed)?.write(&buf[..room])
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.as_mut().ok_or_else(closed)?.flush()
     }
 }
 
@@ -212,20 +798,53 @@ fn ensure_dst_dir_ssh(
     }
 }
 
-impl Write for EnsuredDestination {
-    #[inline(always)]
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        match self {
-            EnsuredDestination::SftpFile(f, _, _) => f.write(buf),
-            EnsuredDestination::LocalFile(f) => f.write(buf),
-        }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn pending_file_names() {
+        let pending = PendingFile::new(PathBuf::from("/backups/2020/03/01/a.zfs.zst"));
+        assert_eq!(
+            pending.partial,
+            PathBuf::from("/backups/2020/03/01/a.zfs.zst.partial")
+        );
+        assert!(is_partial(&pending.partial));
+        assert!(!is_partial(&pending.target));
     }
 
-    #[inline(always)]
-    fn flush(&mut self) -> std::io::Result<()> {
-        match self {
-            EnsuredDestination::SftpFile(f, _, _) => f.flush(),
-            EnsuredDestination::LocalFile(f) => f.flush(),
-        }
+    #[test]
+    fn local_free_space() {
+        let (available, total) = local_statvfs(&std::env::temp_dir()).unwrap();
+        assert!(total > 0);
+        assert!(available <= total);
+    }
+
+    #[test]
+    fn local_volumes_roll_over() {
+        let folder = TempDir::new("split");
+        let target = folder.join("a.zfs");
+        let pending = PendingFile::new(volume_path(&target, 0));
+        let mut ensured = EnsuredDestination {
+            output: Some(Measured::new(Output::Local(
+                File::create(&pending.partial).unwrap(),
+            ))),
+            pending,
+            target: target.clone(),
+            split_size: Some(4),
+            chmod: 0o600,
+            checksum: true,
+            volumes: Vec::new(),
+        };
+        ensured.write_all(b"0123456789").unwrap();
+        let volumes = ensured
+            .finish(&SessionPool::new(Default::default()))
+            .unwrap();
+
+        let sizes: Vec<u64> = volumes.iter().map(|volume| volume.bytes).collect();
+        assert_eq!(sizes, vec![4, 4, 2]);
+        assert_eq!(volumes[2].file.target, folder.join("a.zfs.002"));
+        assert_eq!(std::fs::read(&volumes[1].file.partial).unwrap(), b"4567");
     }
 }
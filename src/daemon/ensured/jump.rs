@@ -0,0 +1,171 @@
+use crate::daemon::ensured::EnsuredError;
+use slog::{debug, o, trace, Logger};
+use ssh2::{BlockDirections, Channel, Session};
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+
+/// Longest the pump sleeps when neither side made progress, in case libssh2 already took
+/// pending data off the socket.
+const IDLE_POLL_TIMEOUT_MS: libc::c_int = 1000;
+const BUFFER_SIZE: usize = 32 * 1024;
+
+/// Open a `direct-tcpip` channel from the bastion to `host:port` and expose it as a local socket
+/// that can be handed to another `Session`. A dedicated thread owns the bastion session, `socket`
+/// is the connection the bastion session runs over.
+pub fn tunnel(
+    logger: &Logger,
+    bastion: Session,
+    socket: TcpStream,
+    host: &str,
+    port: u16,
+) -> Result<UnixStream, EnsuredError> {
+    let (local, remote) = UnixStream::pair()?;
+    let (tx, rx) = mpsc::channel();
+    let logger = logger.new(o!("tunnel" => format!("{}:{}", host, port)));
+    let target = host.to_string();
+
+    std::thread::spawn(move || {
+        let channel = match bastion.channel_direct_tcpip(&target, port, None) {
+            Ok(channel) => {
+                let _ = tx.send(Ok(()));
+                channel
+            }
+            Err(e) => {
+                let _ = tx.send(Err(e.message().to_string()));
+                return;
+            }
+        };
+        debug!(logger, "Opened direct-tcpip channel");
+        pump(&logger, &bastion, &socket, channel, remote);
+        debug!(logger, "Tunnel closed");
+    });
+
+    match rx.recv() {
+        Ok(Ok(())) => Ok(local),
+        Ok(Err(reason)) => Err(EnsuredError::Tunnel(host.to_string(), reason)),
+        Err(_) => Err(EnsuredError::Tunnel(
+            host.to_string(),
+            "tunnel thread exited unexpectedly".to_string(),
+        )),
+    }
+}
+
+fn pump(
+    logger: &Logger,
+    bastion: &Session,
+    socket: &TcpStream,
+    mut channel: Channel,
+    mut local: UnixStream,
+) {
+    bastion.set_blocking(false);
+    if let Err(e) = local.set_nonblocking(true) {
+        debug!(logger, "Failed to make tunnel socket non-blocking: {}", e);
+        return;
+    }
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    let mut to_remote: Vec<u8> = Vec::new();
+    let mut to_local: Vec<u8> = Vec::new();
+
+    loop {
+        let mut progressed = false;
+
+        if to_remote.is_empty() {
+            match local.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    to_remote.extend_from_slice(&buf[..n]);
+                    progressed = true;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    trace!(logger, "Local side of the tunnel failed: {}", e);
+                    break;
+                }
+            }
+        }
+        if !to_remote.is_empty() {
+            match channel.write(&to_remote) {
+                Ok(n) => {
+                    to_remote.drain(..n);
+                    progressed = true;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    trace!(logger, "Remote side of the tunnel failed: {}", e);
+                    break;
+                }
+            }
+        }
+
+        if to_local.is_empty() {
+            match channel.read(&mut buf) {
+                Ok(0) if channel.eof() => break,
+                Ok(0) => {}
+                Ok(n) => {
+                    to_local.extend_from_slice(&buf[..n]);
+                    progressed = true;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    trace!(logger, "Remote side of the tunnel failed: {}", e);
+                    break;
+                }
+            }
+        }
+        if !to_local.is_empty() {
+            match local.write(&to_local) {
+                Ok(n) => {
+                    to_local.drain(..n);
+                    progressed = true;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    trace!(logger, "Local side of the tunnel failed: {}", e);
+                    break;
+                }
+            }
+        }
+
+        if !progressed {
+            // Sleep until whichever side blocked the pump is ready.
+            let mut local_events = 0;
+            if to_remote.is_empty() {
+                local_events |= libc::POLLIN;
+            }
+            if !to_local.is_empty() {
+                local_events |= libc::POLLOUT;
+            }
+            let mut socket_events = 0;
+            if to_local.is_empty() {
+                socket_events |= libc::POLLIN;
+            }
+            match bastion.block_directions() {
+                BlockDirections::Inbound => socket_events |= libc::POLLIN,
+                BlockDirections::Outbound => socket_events |= libc::POLLOUT,
+                BlockDirections::Both => socket_events |= libc::POLLIN | libc::POLLOUT,
+                BlockDirections::None => {}
+            }
+            let mut fds = [
+                libc::pollfd {
+                    fd: local.as_raw_fd(),
+                    events: local_events,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: socket.as_raw_fd(),
+                    events: socket_events,
+                    revents: 0,
+                },
+            ];
+            unsafe {
+                libc::poll(fds.as_mut_ptr(), 2, IDLE_POLL_TIMEOUT_MS);
+            }
+        }
+    }
+    bastion.set_blocking(true);
+    let _ = channel.send_eof();
+    let _ = channel.close();
+}
@@ -0,0 +1,116 @@
+use crate::daemon::destination::DestinationSsh;
+use crate::daemon::ensured::{ssh, EnsuredError};
+use slog::{debug, error, trace, Logger};
+use ssh2::{Session, Sftp};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Idle sessions are closed after this long unless `idle_timeout` is set on the destination.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Authenticated ssh session together with its SFTP channel.
+pub struct PooledSession {
+    pub session: Session,
+    pub sftp: Sftp,
+    last_used: Instant,
+    reused: bool,
+}
+
+impl PooledSession {
+    /// Whether this session was taken from the pool rather than freshly established.
+    pub fn is_reused(&self) -> bool {
+        self.reused
+    }
+
+    /// A round-trip over the SFTP channel. Catches both dead TCP connections and closed channels.
+    fn is_healthy(&self) -> bool {
+        self.sftp.realpath(Path::new(".")).is_ok()
+    }
+}
+
+/// Idle ssh sessions shared between all agents of a single destination. Every agent checks out
+/// a session for the duration of a transfer, so there are never more than `parallelism`
+/// sessions open at once.
+#[derive(Clone)]
+pub struct SessionPool {
+    idle: Arc<Mutex<Vec<PooledSession>>>,
+    idle_timeout: Duration,
+}
+
+impl SessionPool {
+    pub fn new(idle_timeout: Duration) -> Self {
+        SessionPool {
+            idle: Arc::new(Mutex::new(Vec::new())),
+            idle_timeout,
+        }
+    }
+
+    /// Build a pool with idle timeout taken from the destination configuration.
+    pub fn for_destination(dst: Option<&DestinationSsh>) -> Self {
+        let idle_timeout = dst
+            .and_then(|dst| dst.idle_timeout)
+            .and_then(|timeout| timeout.to_std().ok())
+            .unwrap_or(DEFAULT_IDLE_TIMEOUT);
+        Self::new(idle_timeout)
+    }
+
+    /// Take a healthy idle session from the pool or establish a new one.
+    pub fn checkout(
+        &self,
+        logger: &Logger,
+        dst: &DestinationSsh,
+    ) -> Result<PooledSession, EnsuredError> {
+        loop {
+            let candidate = self.idle.lock().unwrap().pop();
+            match candidate {
+                Some(mut pooled) => {
+                    if pooled.last_used.elapsed() < self.idle_timeout && pooled.is_healthy() {
+                        trace!(logger, "Reusing pooled ssh session");
+                        pooled.reused = true;
+                        return Ok(pooled);
+                    }
+                    debug!(logger, "Dropping stale pooled ssh session");
+                }
+                None => break,
+            }
+        }
+        self.connect(logger, dst)
+    }
+
+    /// Establish a brand-new session, bypassing idle ones.
+    pub fn connect(
+        &self,
+        logger: &Logger,
+        dst: &DestinationSsh,
+    ) -> Result<PooledSession, EnsuredError> {
+        let session = ssh::connect(logger, dst)?;
+        debug!(logger, "Established ssh session with remote server");
+        let sftp = session.sftp().map_err(|e| {
+            error!(logger, "{}", e);
+            e
+        })?;
+        trace!(logger, "Established SFTP channel");
+        Ok(PooledSession {
+            session,
+            sftp,
+            last_used: Instant::now(),
+            reused: false,
+        })
+    }
+
+    /// Return a session to the pool after a successful transfer.
+    pub fn checkin(&self, mut pooled: PooledSession) {
+        pooled.last_used = Instant::now();
+        self.idle.lock().unwrap().push(pooled);
+    }
+
+    /// Close sessions that were idle for longer than the timeout. Returns number of closed sessions.
+    pub fn close_idle(&self) -> usize {
+        let mut idle = self.idle.lock().unwrap();
+        let before = idle.len();
+        let timeout = self.idle_timeout;
+        idle.retain(|pooled| pooled.last_used.elapsed() < timeout);
+        before - idle.len()
+    }
+}
@@ -0,0 +1,412 @@
+use crate::daemon::destination::Destination;
+use crate::daemon::ensured::pool::SessionPool;
+use crate::daemon::ensured::{create_local_dir, create_local_file};
+use slog::{debug, Logger};
+use ssh2::{OpenFlags, OpenType, Sftp};
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Instant;
+
+/// Size of the probe file used to measure write throughput.
+pub const PROBE_SIZE: usize = 8 * 1024 * 1024;
+
+/// Outcome of a single self-test step.
+#[derive(Debug, Clone)]
+pub struct ProbeCheck {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+/// Result of a destination self-test, one entry per check in the order they ran. Checks that
+/// depend on a failed one are not run.
+#[derive(Debug, Clone)]
+pub struct ProbeReport {
+    pub destination: String,
+    pub checks: Vec<ProbeCheck>,
+}
+
+impl ProbeReport {
+    fn new(destination: &str) -> Self {
+        ProbeReport {
+            destination: destination.to_string(),
+            checks: Vec::new(),
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        !self.checks.is_empty() && self.checks.iter().all(|check| check.passed)
+    }
+
+    /// Record the outcome and hand back the value, if any, so dependent checks can be skipped.
+    fn record<T>(&mut self, name: &'static str, result: Result<(T, String), String>) -> Option<T> {
+        match result {
+            Ok((value, detail)) => {
+                self.checks.push(ProbeCheck {
+                    name,
+                    passed: true,
+                    detail,
+                });
+                Some(value)
+            }
+            Err(detail) => {
+                self.checks.push(ProbeCheck {
+                    name,
+                    passed: false,
+                    detail,
+                });
+                None
+            }
+        }
+    }
+}
+
+impl Display for ProbeReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Destination `{}`:", self.destination)?;
+        for check in &self.checks {
+            let status = if check.passed { "PASS" } else { "FAIL" };
+            writeln!(f, "  [{}] {}: {}", status, check.name, check.detail)?;
+        }
+        write!(
+            f,
+            "{}",
+            if self.passed() {
+                "All checks passed"
+            } else {
+                "Self-test failed"
+            }
+        )
+    }
+}
+
+/// File operations the self-test needs, over SFTP or on the local filesystem.
+trait ProbeTarget {
+    fn is_dir(&self, path: &Path) -> Result<bool, String>;
+    fn mkdir(&self, path: &Path, mode: i32) -> Result<(), String>;
+    fn write(&self, path: &Path, mode: i32, data: &[u8]) -> Result<(), String>;
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String>;
+    fn mode(&self, path: &Path) -> Result<u32, String>;
+    fn remove_file(&self, path: &Path) -> Result<(), String>;
+    fn remove_dir(&self, path: &Path) -> Result<(), String>;
+}
+
+struct SftpTarget<'a>(&'a Sftp);
+
+impl ProbeTarget for SftpTarget<'_> {
+    fn is_dir(&self, path: &Path) -> Result<bool, String> {
+        match self.0.stat(path) {
+            Ok(stat) => Ok(stat.is_dir()),
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn mkdir(&self, path: &Path, mode: i32) -> Result<(), String> {
+        self.0.mkdir(path, mode).map_err(|e| e.to_string())
+    }
+
+    fn write(&self, path: &Path, mode: i32, data: &[u8]) -> Result<(), String> {
+        // Same flags as a real transfer.
+        let open_flags = OpenFlags::WRITE | OpenFlags::TRUNCATE;
+        let mut file = self
+            .0
+            .open_mode(path, open_flags, mode, OpenType::File)
+            .map_err(|e| e.to_string())?;
+        file.write_all(data).map_err(|e| e.to_string())?;
+        file.fsync().map_err(|e| e.to_string())
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        self.0
+            .open(path)
+            .map_err(|e| e.to_string())?
+            .read_to_end(&mut data)
+            .map_err(|e| e.to_string())?;
+        Ok(data)
+    }
+
+    fn mode(&self, path: &Path) -> Result<u32, String> {
+        let stat = self.0.stat(path).map_err(|e| e.to_string())?;
+        stat.perm
+            .map(|perm| perm & 0o7777)
+            .ok_or_else(|| String::from("Server didn't report permissions"))
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), String> {
+        self.0.unlink(path).map_err(|e| e.to_string())
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<(), String> {
+        self.0.rmdir(path).map_err(|e| e.to_string())
+    }
+}
+
+struct LocalTarget;
+
+impl ProbeTarget for LocalTarget {
+    fn is_dir(&self, path: &Path) -> Result<bool, String> {
+        Ok(path.is_dir())
+    }
+
+    // Same calls as a real transfer.
+    fn mkdir(&self, path: &Path, mode: i32) -> Result<(), String> {
+        create_local_dir(path, mode).map_err(|e| e.to_string())
+    }
+
+    fn write(&self, path: &Path, mode: i32, data: &[u8]) -> Result<(), String> {
+        let mut file = create_local_file(path, mode).map_err(|e| e.to_string())?;
+        file.write_all(data).map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String> {
+        std::fs::read(path).map_err(|e| e.to_string())
+    }
+
+    fn mode(&self, path: &Path) -> Result<u32, String> {
+        std::fs::metadata(path)
+            .map(|meta| meta.permissions().mode() & 0o7777)
+            .map_err(|e| e.to_string())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), String> {
+        std::fs::remove_file(path).map_err(|e| e.to_string())
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<(), String> {
+        std::fs::remove_dir(path).map_err(|e| e.to_string())
+    }
+}
+
+/// Check that the destination is reachable and that backups can be written to it with configured
+/// permissions. Leaves nothing behind, besides the destination folder itself which a real
+/// transfer would create anyway.
+pub fn test_destination(
+    logger: &Logger,
+    name: &str,
+    dst: &Destination,
+    pool: &SessionPool,
+) -> ProbeReport {
+    let mut report = ProbeReport::new(name);
+    if let Some(ref command) = dst.command {
+        // Running the program would make it receive a bogus backup, so only check it's there.
+        let result = std::fs::metadata(&command.program)
+            .map_err(|e| format!("`{}`: {}", command.program.display(), e))
+            .and_then(|meta| {
+                if meta.is_file() && meta.permissions().mode() & 0o111 != 0 {
+                    Ok(((), format!("`{}` is executable", command.program.display())))
+                } else {
+                    Err(format!("`{}` is not executable", command.program.display()))
+                }
+            });
+        report.record("program", result);
+        return report;
+    }
+    match (&dst.ssh, &dst.local) {
+        (Some(dst_ssh), None) => {
+            // Always a fresh session: pooled one would hide connection and authentication issues.
+            let connected = pool.connect(logger, dst_ssh).map(|pooled| {
+                let detail = format!(
+                    "authenticated as `{}` on `{}:{}`",
+                    dst_ssh.username, dst_ssh.host, dst_ssh.port
+                );
+                (pooled, detail)
+            });
+            let pooled = report.record("connect", connected.map_err(|e| e.to_string()));
+            if let Some(pooled) = pooled {
+                run_checks(
+                    logger,
+                    &mut report,
+                    &SftpTarget(&pooled.sftp),
+                    &dst_ssh.folder,
+                    dst.chmod,
+                    dst.chmod_dir,
+                );
+                pool.checkin(pooled);
+            }
+        }
+        (None, Some(dst_local)) => {
+            run_checks(
+                logger,
+                &mut report,
+                &LocalTarget,
+                &dst_local.folder,
+                dst.chmod,
+                dst.chmod_dir,
+            );
+        }
+        (None, None) => {
+            report.record::<()>(
+                "configuration",
+                Err(String::from("Missing destination configuration")),
+            );
+        }
+        (Some(_), Some(_)) => {
+            report.record::<()>(
+                "configuration",
+                Err(String::from("Duplicate destination configuration")),
+            );
+        }
+    }
+    report
+}
+
+fn run_checks(
+    logger: &Logger,
+    report: &mut ProbeReport,
+    target: &dyn ProbeTarget,
+    folder: &Path,
+    chmod: i32,
+    chmod_dir: i32,
+) {
+    // Mirrors `ensure_root_dir_ssh`: parent has to exist, the folder itself is created if missing.
+    if let Some(parent) = folder.parent() {
+        let result = target.is_dir(parent).and_then(|exists| {
+            if exists {
+                Ok(((), format!("`{}` exists", parent.display())))
+            } else {
+                Err(format!("`{}` doesn't exist", parent.display()))
+            }
+        });
+        if report.record("parent folder", result).is_none() {
+            return;
+        }
+    }
+    let result = target.is_dir(folder).and_then(|exists| {
+        if exists {
+            Ok(((), format!("`{}` exists", folder.display())))
+        } else {
+            target.mkdir(folder, chmod_dir).map(|_| {
+                (
+                    (),
+                    format!("created `{}` with mode {:o}", folder.display(), chmod_dir),
+                )
+            })
+        }
+    });
+    if report.record("folder", result).is_none() {
+        return;
+    }
+
+    let probe_dir = folder.join(format!(".gazpacho-probe-{}", std::process::id()));
+    let probe_file = probe_dir.join("probe");
+    debug!(logger, "Writing probe file {}", probe_file.display());
+    let result = target
+        .mkdir(&probe_dir, chmod_dir)
+        .map(|_| ((), format!("created `{}`", probe_dir.display())));
+    if report.record("create directory", result).is_none() {
+        return;
+    }
+
+    let data = probe_data();
+    let started = Instant::now();
+    let result = target.write(&probe_file, chmod, &data).map(|_| {
+        let elapsed = started.elapsed().as_secs_f64();
+        let detail = format!(
+            "{} MiB in {:.2}s ({:.1} MiB/s)",
+            PROBE_SIZE / (1024 * 1024),
+            elapsed,
+            PROBE_SIZE as f64 / (1024.0 * 1024.0) / elapsed.max(f64::EPSILON)
+        );
+        ((), detail)
+    });
+    let written = report.record("write", result).is_some();
+    if written {
+        let result = check_mode(target, &probe_dir, chmod_dir).and_then(|_| {
+            check_mode(target, &probe_file, chmod).map(|_| {
+                let detail = format!("folders {:o}, files {:o}", chmod_dir, chmod);
+                ((), detail)
+            })
+        });
+        report.record("permissions", result);
+        let result = target.read(&probe_file).and_then(|read| {
+            if read == data {
+                Ok(((), format!("{} bytes match", read.len())))
+            } else {
+                Err(format!(
+                    "read back {} bytes that don't match {} bytes written",
+                    read.len(),
+                    data.len()
+                ))
+            }
+        });
+        report.record("read back", result);
+    }
+
+    // Remove whatever got created. A failed write may or may not have left a file behind.
+    let file_removed = match target.remove_file(&probe_file) {
+        Err(_) if !written => Ok(()),
+        result => result,
+    };
+    let result = file_removed
+        .and_then(|_| target.remove_dir(&probe_dir))
+        .map(|_| ((), format!("removed `{}`", probe_dir.display())));
+    report.record("delete", result);
+}
+
+fn check_mode(target: &dyn ProbeTarget, path: &Path, expected: i32) -> Result<(), String> {
+    let mode = target.mode(path)?;
+    if mode == expected as u32 {
+        Ok(())
+    } else {
+        Err(format!(
+            "`{}` has mode {:o} instead of {:o}",
+            path.display(),
+            mode,
+            expected
+        ))
+    }
+}
+
+/// Non-repeating enough to catch truncated or shuffled reads.
+fn probe_data() -> Vec<u8> {
+    (0..PROBE_SIZE)
+        .map(|idx| (idx ^ (idx >> 8) ^ (idx >> 16)) as u8)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn local_probe() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let parent = TempDir::new("probe");
+        let folder = parent.join("backups");
+        let mut report = ProbeReport::new("temp");
+        run_checks(&logger, &mut report, &LocalTarget, &folder, 0o640, 0o750);
+
+        assert!(report.passed(), "{}", report);
+        let names: Vec<&str> = report.checks.iter().map(|check| check.name).collect();
+        assert_eq!(
+            names,
+            vec![
+                "parent folder",
+                "folder",
+                "create directory",
+                "write",
+                "permissions",
+                "read back",
+                "delete"
+            ]
+        );
+        assert_eq!(std::fs::read_dir(&folder).unwrap().count(), 0);
+
+        let mut report = ProbeReport::new("temp");
+        run_checks(
+            &logger,
+            &mut report,
+            &LocalTarget,
+            &folder.join("missing").join("backups"),
+            0o640,
+            0o750,
+        );
+        assert!(!report.passed());
+        assert_eq!(report.checks.len(), 1);
+    }
+}
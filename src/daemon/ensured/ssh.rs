@@ -0,0 +1,337 @@
+use crate::daemon::destination::{DestinationSsh, DestinationSshJump, Identity};
+use crate::daemon::ensured::{jump, EnsuredError};
+use slog::{debug, o, trace, warn, Logger};
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, Session};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+
+/// `LIBSSH2_ERROR_FILE`, reported when a private key can't be read or decoded.
+const LIBSSH2_ERROR_FILE: i32 = -16;
+
+/// Known hosts file shared by every user of the system.
+const SYSTEM_KNOWN_HOSTS: &str = "/etc/ssh/ssh_known_hosts";
+
+/// Everything needed to authenticate on a single hop.
+pub struct Credentials<'a> {
+    pub username: &'a str,
+    pub identity_files: &'a [Identity],
+    pub passphrase_file: Option<&'a Path>,
+    pub agent: bool,
+}
+
+impl<'a> From<&'a DestinationSsh> for Credentials<'a> {
+    fn from(dst: &'a DestinationSsh) -> Self {
+        Credentials {
+            username: &dst.username,
+            identity_files: &dst.identity_files,
+            passphrase_file: dst.passphrase_file.as_deref(),
+            agent: dst.agent,
+        }
+    }
+}
+
+impl<'a> From<&'a DestinationSshJump> for Credentials<'a> {
+    fn from(jump: &'a DestinationSshJump) -> Self {
+        Credentials {
+            username: &jump.username,
+            identity_files: &jump.identity_files,
+            passphrase_file: jump.passphrase_file.as_deref(),
+            agent: jump.agent,
+        }
+    }
+}
+
+/// Split configured host into host name and port. Legacy `host:port` form takes precedence over
+/// `port`.
+pub fn host_and_port(host: &str, port: u16) -> (String, u16) {
+    match host.parse::<SocketAddr>() {
+        Ok(addr) => (addr.ip().to_string(), addr.port()),
+        Err(_) => (
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port,
+        ),
+    }
+}
+
+/// Resolve configured host into a list of addresses to try. A port in `host` wins over `port`.
+pub fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>, EnsuredError> {
+    if let Ok(addr) = host.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| EnsuredError::Resolve(host.to_string(), e))?
+        .collect();
+    if addrs.is_empty() {
+        let e = std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses found");
+        return Err(EnsuredError::Resolve(host.to_string(), e));
+    }
+    Ok(addrs)
+}
+
+/// Connect to the first reachable address of the host.
+pub fn connect_tcp(logger: &Logger, host: &str, port: u16) -> Result<TcpStream, EnsuredError> {
+    let mut last_error = None;
+    for addr in resolve(host, port)? {
+        trace!(logger, "Connecting to {}", addr);
+        match TcpStream::connect(addr) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => {
+                debug!(logger, "Failed to connect to {}: {}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    let e = last_error.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses to connect to")
+    });
+    Err(EnsuredError::Connect(host.to_string(), e))
+}
+
+/// Establish and authenticate an ssh session with the destination. If `jump` is configured the
+/// connection is tunneled through the bastion.
+pub fn connect(logger: &Logger, dst: &DestinationSsh) -> Result<Session, EnsuredError> {
+    let mut sess = Session::new()?;
+    match &dst.jump {
+        None => {
+            let tcp = connect_tcp(logger, &dst.host, dst.port)?;
+            sess.set_tcp_stream(tcp);
+        }
+        Some(jump) => {
+            let (host, port) = host_and_port(&dst.host, dst.port);
+            let (bastion, socket) = connect_jump(logger, jump)?;
+            let stream = jump::tunnel(logger, bastion, socket, &host, port)?;
+            sess.set_tcp_stream(stream);
+        }
+    }
+    sess.handshake()?;
+    verify_host_key(
+        logger,
+        &sess,
+        &dst.host,
+        dst.port,
+        dst.known_hosts.as_deref(),
+        dst.insecure_skip_host_key,
+    )?;
+    authenticate(logger, &sess, &dst.host, dst.into())?;
+    Ok(sess)
+}
+
+/// Establish and authenticate an ssh session with the bastion. Returns the session along with the
+/// connection it runs over.
+fn connect_jump(
+    logger: &Logger,
+    jump: &DestinationSshJump,
+) -> Result<(Session, TcpStream), EnsuredError> {
+    let logger = logger.new(o!("jump" => jump.host.clone()));
+    let tcp = connect_tcp(&logger, &jump.host, jump.port)?;
+    let socket = tcp.try_clone()?;
+    let mut sess = Session::new()?;
+    sess.set_tcp_stream(tcp);
+    sess.handshake()?;
+    verify_host_key(
+        &logger,
+        &sess,
+        &jump.host,
+        jump.port,
+        jump.known_hosts.as_deref(),
+        jump.insecure_skip_host_key,
+    )?;
+    authenticate(&logger, &sess, &jump.host, jump.into())?;
+    debug!(logger, "Established ssh session with jump host");
+    Ok((sess, socket))
+}
+
+/// Check the host key presented by the server against `known_hosts` if it's configured, otherwise
+/// against `~/.ssh/known_hosts` and the system-wide known hosts file, whichever exist. Fails if
+/// there is no known hosts file, unless `skip` is set.
+pub fn verify_host_key(
+    logger: &Logger,
+    sess: &Session,
+    host: &str,
+    port: u16,
+    known_hosts: Option<&Path>,
+    skip: bool,
+) -> Result<(), EnsuredError> {
+    if skip {
+        warn!(
+            logger,
+            "Host key verification for `{}` is disabled by `insecure_skip_host_key`", host
+        );
+        return Ok(());
+    }
+    let candidates = match known_hosts {
+        Some(file) => vec![file.to_path_buf()],
+        None => default_known_hosts(),
+    };
+    let files: Vec<&PathBuf> = candidates.iter().filter(|file| file.exists()).collect();
+    if files.is_empty() {
+        return Err(EnsuredError::HostKey(
+            host.to_string(),
+            format!(
+                "no known hosts file, {} doesn't exist. Set `known_hosts` or `insecure_skip_host_key`",
+                describe(&candidates)
+            ),
+        ));
+    }
+    let (host, port) = host_and_port(host, port);
+    let (key, _) = sess.host_key().ok_or_else(|| {
+        EnsuredError::HostKey(host.clone(), "server didn't present host key".to_string())
+    })?;
+    let mut known = sess.known_hosts()?;
+    for file in &files {
+        known
+            .read_file(file, KnownHostFileKind::OpenSSH)
+            .map_err(|e| {
+                EnsuredError::HostKey(
+                    host.clone(),
+                    format!("failed to read `{}`: {}", file.display(), e.message()),
+                )
+            })?;
+    }
+    let checked: Vec<PathBuf> = files.into_iter().cloned().collect();
+    match known.check_port(&host, port, key) {
+        CheckResult::Match => {
+            trace!(logger, "Host key for `{}` matched", host);
+            Ok(())
+        }
+        CheckResult::NotFound => Err(EnsuredError::HostKey(
+            host,
+            format!("host is not in {}", describe(&checked)),
+        )),
+        CheckResult::Mismatch => Err(EnsuredError::HostKey(
+            host,
+            format!("host key doesn't match the one in {}", describe(&checked)),
+        )),
+        CheckResult::Failure => Err(EnsuredError::HostKey(
+            host,
+            "failed to check host key".to_string(),
+        )),
+    }
+}
+
+/// Known hosts files used when `known_hosts` isn't configured, the way OpenSSH looks them up.
+fn default_known_hosts() -> Vec<PathBuf> {
+    let mut files = Vec::with_capacity(2);
+    if let Some(home) = std::env::var_os("HOME") {
+        let mut path = PathBuf::from(home);
+        path.push(".ssh/known_hosts");
+        files.push(path);
+    }
+    files.push(PathBuf::from(SYSTEM_KNOWN_HOSTS));
+    files
+}
+
+fn describe(files: &[PathBuf]) -> String {
+    let files: Vec<String> = files
+        .iter()
+        .map(|file| format!("`{}`", file.display()))
+        .collect();
+    files.join(" or ")
+}
+
+/// Try every configured authentication method in order: ssh-agent first (if enabled), then
+/// identity files. Stops at the first method that succeeds.
+pub fn authenticate(
+    logger: &Logger,
+    sess: &Session,
+    host: &str,
+    credentials: Credentials,
+) -> Result<(), EnsuredError> {
+    let mut attempts = Vec::new();
+
+    if credentials.agent {
+        match sess.userauth_agent(credentials.username) {
+            Ok(()) if sess.authenticated() => {
+                debug!(logger, "Authenticated with ssh-agent");
+                return Ok(());
+            }
+            Ok(()) => attempts.push("ssh-agent: no identity was accepted".to_string()),
+            Err(e) => attempts.push(format!("ssh-agent: {}", e.message())),
+        }
+    }
+
+    for identity in credentials.identity_files {
+        let path = &identity.path;
+        if !path.exists() {
+            attempts.push(format!("{}: file doesn't exist", path.display()));
+            continue;
+        }
+        let passphrase = match identity
+            .passphrase_file
+            .as_deref()
+            .or(credentials.passphrase_file)
+        {
+            Some(passphrase_file) => Some(read_passphrase(passphrase_file)?),
+            None => None,
+        };
+        match sess.userauth_pubkey_file(credentials.username, None, path, passphrase.as_deref()) {
+            Ok(()) if sess.authenticated() => {
+                debug!(logger, "Authenticated with {}", path.display());
+                return Ok(());
+            }
+            Ok(()) => attempts.push(format!("{}: key was not accepted", path.display())),
+            Err(e) => {
+                let undecodable = e.code() == ErrorCode::Session(LIBSSH2_ERROR_FILE);
+                let hint = if undecodable && passphrase.is_none() {
+                    " (is the key encrypted? set `passphrase_file`)"
+                } else {
+                    ""
+                };
+                attempts.push(format!("{}: {}{}", path.display(), e.message(), hint));
+            }
+        }
+    }
+
+    if attempts.is_empty() {
+        attempts.push(
+            "no authentication methods configured, set `agent` or `identity_file`".to_string(),
+        );
+    }
+    Err(EnsuredError::Authentication {
+        username: credentials.username.to_string(),
+        host: host.to_string(),
+        attempts,
+    })
+}
+
+fn read_passphrase(path: &Path) -> Result<String, EnsuredError> {
+    let passphrase = std::fs::read_to_string(path)
+        .map_err(|e| EnsuredError::Passphrase(path.to_path_buf(), e))?;
+    Ok(passphrase.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolve_legacy_socket_addr() {
+        let addrs = resolve("192.168.86.13:22", 2222).unwrap();
+        assert_eq!(addrs, vec!["192.168.86.13:22".parse().unwrap()]);
+    }
+
+    #[test]
+    fn legacy_host_and_port() {
+        assert_eq!(
+            host_and_port("10.0.0.1:2222", 22),
+            ("10.0.0.1".to_string(), 2222)
+        );
+        assert_eq!(host_and_port("[::1]:2222", 22), ("::1".to_string(), 2222));
+        assert_eq!(
+            host_and_port("backup.lan", 22),
+            ("backup.lan".to_string(), 22)
+        );
+    }
+
+    #[test]
+    fn resolve_ipv6_with_port() {
+        let addrs = resolve("::1", 2222).unwrap();
+        assert_eq!(addrs, vec!["[::1]:2222".parse().unwrap()]);
+        let addrs = resolve("[::1]", 2222).unwrap();
+        assert_eq!(addrs, vec!["[::1]:2222".parse().unwrap()]);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::daemon::compression::Compression;
+use crate::daemon::ensured::PendingVolume;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Suffix appended to a backup file name to get its manifest.
+pub const MANIFEST_SUFFIX: &str = "manifest.json";
+
+/// Metadata written next to every backup file, so it can be identified and restored without the
+/// local database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub gazpacho_version: String,
+    pub host: String,
+    pub task: String,
+    pub pool: String,
+    pub dataset: String,
+    pub snapshot: String,
+    pub guid: Option<u64>,
+    /// `full` or `incremental`.
+    pub kind: String,
+    /// Source snapshot of incremental stream.
+    pub source: Option<String>,
+    pub send_flags: Vec<String>,
+    pub compression: Option<ManifestCompression>,
+    pub encryption: Option<ManifestEncryption>,
+    /// Size of `zfs send` stream.
+    pub bytes_raw: u64,
+    /// Size of the file on destination.
+    pub bytes_written: u64,
+    /// Not recorded if the destination has checksums turned off.
+    pub checksum: Option<ManifestChecksum>,
+    /// Volumes of a split backup in order. Empty if the backup is a single file.
+    #[serde(default)]
+    pub volumes: Vec<ManifestVolume>,
+    pub started_at: DateTime<Utc>,
+    pub completed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestCompression {
+    pub codec: String,
+    pub level: i32,
+    pub workers: u32,
+    /// Decoder window limit the stream needs, zstd only.
+    #[serde(default)]
+    pub window_log: Option<u32>,
+}
+
+impl From<&Compression> for ManifestCompression {
+    fn from(compression: &Compression) -> Self {
+        ManifestCompression {
+            codec: compression.codec().to_string(),
+            level: compression.level(),
+            workers: compression.workers(),
+            window_log: compression.window_log(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEncryption {
+    pub scheme: String,
+    /// Recipients the file is encrypted to. Not recorded for passphrases.
+    #[serde(default)]
+    pub key_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestChecksum {
+    pub algorithm: String,
+    /// Hash of `zfs send` stream, same for every destination of the step.
+    pub raw: String,
+    /// Hash of the file as stored on destination.
+    pub written: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestVolume {
+    /// File name of the volume, relative to the manifest.
+    pub name: String,
+    pub bytes: u64,
+    pub checksum: Option<String>,
+}
+
+impl From<&PendingVolume> for ManifestVolume {
+    fn from(volume: &PendingVolume) -> Self {
+        ManifestVolume {
+            name: volume
+                .file
+                .target
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            bytes: volume.bytes,
+            checksum: volume.checksum.clone(),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn to_json(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec_pretty(self)
+    }
+
+    pub fn from_json(data: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(data)
+    }
+
+    /// Path of manifest for the given backup file.
+    pub fn path_for(file: &Path) -> PathBuf {
+        let mut path = file.to_path_buf().into_os_string();
+        path.push(".");
+        path.push(MANIFEST_SUFFIX);
+        PathBuf::from(path)
+    }
+}
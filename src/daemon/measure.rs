@@ -0,0 +1,141 @@
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Name of the hash recorded in manifests and the step log.
+pub const CHECKSUM_ALGORITHM: &str = "blake3";
+
+/// Wrapper that counts and hashes every byte going through it, either read or written.
+pub struct Measured<T> {
+    inner: T,
+    bytes: u64,
+    hasher: blake3::Hasher,
+}
+
+impl<T> Measured<T> {
+    pub fn new(inner: T) -> Self {
+        Measured {
+            inner,
+            bytes: 0,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// BLAKE3 hash of everything seen so far as a hex string.
+    pub fn checksum(&self) -> String {
+        self.hasher.finalize().to_hex().to_string()
+    }
+
+    /// Account for bytes that went around this reader or writer. They're not part of the checksum.
+    pub fn skip(&mut self, bytes: u64) {
+        self.bytes += bytes;
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read> Read for Measured<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Measured<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Number of bytes that can be watched from other threads while a transfer runs. Cloning shares
+/// the count.
+#[derive(Clone, Debug, Default)]
+pub struct ByteCounter(Arc<AtomicU64>);
+
+impl ByteCounter {
+    pub fn add(&self, bytes: u64) {
+        self.0.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Reader that adds every byte read to a `ByteCounter`.
+pub struct Counted<R> {
+    inner: R,
+    counter: ByteCounter,
+}
+
+impl<R> Counted<R> {
+    pub fn new(inner: R, counter: ByteCounter) -> Self {
+        Counted { inner, counter }
+    }
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.counter.add(n as u64);
+        Ok(n)
+    }
+}
+
+/// Size and checksum of everything `reader` yields. Used to verify a backup against the values
+/// recorded when it was written.
+pub fn checksum_of<R: Read>(reader: R) -> std::io::Result<(u64, String)> {
+    let mut measured = Measured::new(reader);
+    std::io::copy(&mut measured, &mut std::io::sink())?;
+    Ok((measured.bytes(), measured.checksum()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_and_hashes_both_ways() {
+        let data = b"gazpacho".to_vec();
+        let mut reader = Measured::new(&data[..]);
+        let mut writer = Measured::new(Vec::new());
+        std::io::copy(&mut reader, &mut writer).unwrap();
+        assert_eq!(reader.bytes(), 8);
+        assert_eq!(writer.bytes(), 8);
+        assert_eq!(reader.checksum(), writer.checksum());
+        assert_eq!(
+            writer.checksum(),
+            blake3::hash(b"gazpacho").to_hex().to_string()
+        );
+        assert_eq!(writer.into_inner(), data);
+        assert_eq!(checksum_of(&data[..]).unwrap(), (8, reader.checksum()));
+
+        let counter = ByteCounter::default();
+        let mut counted = Counted::new(&data[..], counter.clone());
+        std::io::copy(&mut counted, &mut std::io::sink()).unwrap();
+        assert_eq!(counter.get(), 8);
+    }
+}
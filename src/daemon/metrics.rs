@@ -0,0 +1,250 @@
+use crate::daemon::config::Metrics;
+use crate::daemon::system::actors::maid::Maid;
+use crate::daemon::system::actors::task_manager::messages::GetMetrics;
+use crate::daemon::system::actors::task_manager::TaskManager;
+use crate::daemon::system::messages::maid::CountSnapshots;
+use actix::Addr;
+use futures::executor::block_on;
+use slog::{error, info, warn, Logger};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+
+/// How often the textfile is rewritten unless configured otherwise.
+const DEFAULT_TEXTFILE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How long a scrape may take to send its request or read the response.
+const CONNECTION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MetricKind {
+    Gauge,
+    Counter,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+}
+
+/// Metric with all of its labelled samples, in Prometheus terms.
+#[derive(Clone, Debug)]
+pub struct MetricFamily {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+    pub samples: Vec<Sample>,
+}
+
+impl MetricFamily {
+    pub fn gauge(name: &'static str, help: &'static str) -> Self {
+        MetricFamily {
+            name,
+            help,
+            kind: MetricKind::Gauge,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn counter(name: &'static str, help: &'static str) -> Self {
+        MetricFamily {
+            name,
+            help,
+            kind: MetricKind::Counter,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn sample(&mut self, labels: &[(&'static str, &str)], value: f64) {
+        self.samples.push(Sample {
+            labels: labels
+                .iter()
+                .map(|(name, value)| (*name, value.to_string()))
+                .collect(),
+            value,
+        });
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Prometheus text exposition format.
+pub fn render(families: &[MetricFamily]) -> String {
+    let mut out = String::new();
+    for family in families {
+        let kind = match family.kind {
+            MetricKind::Gauge => "gauge",
+            MetricKind::Counter => "counter",
+        };
+        let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+        let _ = writeln!(out, "# TYPE {} {}", family.name, kind);
+        for sample in &family.samples {
+            out.push_str(family.name);
+            if !sample.labels.is_empty() {
+                let labels: Vec<String> = sample
+                    .labels
+                    .iter()
+                    .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+                    .collect();
+                let _ = write!(out, "{{{}}}", labels.join(","));
+            }
+            let _ = writeln!(out, " {}", sample.value);
+        }
+    }
+    out
+}
+
+/// Addresses of actors metrics are collected from. Has to be built inside the actor system.
+#[derive(Clone)]
+pub struct MetricsContext {
+    pub tasks: Addr<TaskManager>,
+    pub maid: Addr<Maid>,
+}
+
+/// Gather metrics from every actor. A failing source is logged and left out.
+pub fn collect(logger: &Logger, ctx: &MetricsContext) -> String {
+    let mut families = match block_on(ctx.tasks.send(GetMetrics)) {
+        Ok(Ok(families)) => families,
+        Ok(Err(e)) => {
+            warn!(logger, "Failed to collect task metrics: {}", e);
+            Vec::new()
+        }
+        Err(e) => {
+            warn!(logger, "Failed to collect task metrics: {}", e);
+            Vec::new()
+        }
+    };
+    match block_on(ctx.maid.send(CountSnapshots)) {
+        Ok(counts) => {
+            let mut snapshots = MetricFamily::gauge(
+                "gazpacho_snapshots",
+                "Snapshots made by gazpacho that still exist.",
+            );
+            for count in counts {
+                snapshots.sample(
+                    &[
+                        ("task", count.task.as_str()),
+                        ("dataset", count.dataset.as_str()),
+                    ],
+                    count.count as f64,
+                );
+            }
+            families.push(snapshots);
+        }
+        Err(e) => warn!(logger, "Failed to count snapshots: {}", e),
+    }
+    render(&families)
+}
+
+/// Start exporting metrics as configured: serve them over HTTP, write them for node_exporter's
+/// textfile collector, or both.
+pub fn spawn(logger: Logger, config: &Metrics, ctx: MetricsContext) -> io::Result<()> {
+    if let Some(ref address) = config.listen {
+        let listener = TcpListener::bind(address)?;
+        info!(logger, "Serving metrics on http://{}/metrics", address);
+        let logger = logger.clone();
+        let ctx = ctx.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    // A connection per thread, so a stalled client doesn't hold up the rest.
+                    Ok(stream) => {
+                        let logger = logger.clone();
+                        let ctx = ctx.clone();
+                        std::thread::spawn(move || {
+                            if let Err(e) = serve(&logger, &ctx, stream) {
+                                warn!(logger, "Metrics request failed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => error!(logger, "Failed to accept metrics connection: {}", e),
+                }
+            }
+        });
+    }
+    if let Some(ref path) = config.textfile {
+        let path = path.clone();
+        let interval = config
+            .interval
+            .and_then(|interval| interval.to_std().ok())
+            .unwrap_or(DEFAULT_TEXTFILE_INTERVAL);
+        info!(logger, "Writing metrics to {}", path.display());
+        std::thread::spawn(move || loop {
+            let metrics = collect(&logger, &ctx);
+            if let Err(e) = write_textfile(&path, &metrics) {
+                warn!(
+                    logger,
+                    "Failed to write metrics to {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+            std::thread::sleep(interval);
+        });
+    }
+    Ok(())
+}
+
+/// Answer a single request. Only `GET /metrics` is served.
+fn serve(logger: &Logger, ctx: &MetricsContext, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Headers are of no interest, but have to be read before the response.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim() != "" {
+        header.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", collect(logger, ctx)),
+        _ => ("404 Not Found", String::from("Not found\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// node_exporter may read the file at any moment, so it's replaced in one go.
+fn write_textfile(path: &Path, metrics: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, metrics)?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_text_format() {
+        let mut running = MetricFamily::gauge("gazpacho_task_running", "Whether the task runs.");
+        running.sample(&[("task", "hourly")], 1.0);
+        let mut errors = MetricFamily::counter("gazpacho_destination_errors_total", "Errors.");
+        errors.sample(&[("task", "hourly"), ("destination", "off\"site")], 3.0);
+
+        assert_eq!(
+            render(&[running, errors]),
+            "# HELP gazpacho_task_running Whether the task runs.\n\
+             # TYPE gazpacho_task_running gauge\n\
+             gazpacho_task_running{task=\"hourly\"} 1\n\
+             # HELP gazpacho_destination_errors_total Errors.\n\
+             # TYPE gazpacho_destination_errors_total counter\n\
+             gazpacho_destination_errors_total{task=\"hourly\",destination=\"off\\\"site\"} 3\n"
+        );
+    }
+}
@@ -0,0 +1,594 @@
+//! Notifications about finished tasks and RPO breaches.
+//!
+//! ```ucl
+//! notify {
+//!     on = ["failed", "completed_with_errors", "rpo_breach"];
+//!     webhook {
+//!         url = "http://127.0.0.1:9000/hooks/gazpacho";
+//!     }
+//!     sendmail {
+//!         to = ["ops@example.com"];
+//!     }
+//!     smtp {
+//!         server = "127.0.0.1:25";
+//!         from = "gazpacho@example.com";
+//!         to = ["ops@example.com"];
+//!     }
+//! }
+//! ```
+use crate::daemon::CURRENT_CONFIGURATION;
+use crate::utils::hostname;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use slog::{debug, warn, Logger};
+use std::fmt::{Display, Formatter};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use uclicious::traits::TryInto;
+use uclicious::{FromObject, ObjectError, ObjectRef, Uclicious};
+
+/// Webhooks and SMTP servers that don't answer within this are given up on.
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Events a notification is sent for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NotifyOn {
+    Failed,
+    CompletedWithErrors,
+    /// Every finished task, whatever the outcome.
+    Always,
+    RpoBreach,
+}
+
+impl NotifyOn {
+    pub fn matches(&self, event: Event) -> bool {
+        match self {
+            NotifyOn::Failed => event == Event::Failed,
+            NotifyOn::CompletedWithErrors => event == Event::CompletedWithErrors,
+            NotifyOn::Always => event != Event::RpoBreach,
+            NotifyOn::RpoBreach => event == Event::RpoBreach,
+        }
+    }
+}
+
+impl FromObject<ObjectRef> for NotifyOn {
+    fn try_from(value: ObjectRef) -> Result<Self, ObjectError> {
+        let on: String = value.try_into()?;
+        match on.as_str() {
+            "failed" => Ok(NotifyOn::Failed),
+            "completed_with_errors" => Ok(NotifyOn::CompletedWithErrors),
+            "always" => Ok(NotifyOn::Always),
+            "rpo_breach" => Ok(NotifyOn::RpoBreach),
+            on => Err(ObjectError::Other(format!(
+                "Notification condition \"{}\" is not supported.",
+                on
+            ))),
+        }
+    }
+}
+
+/// Parse `on` of `notify`, either a single condition or a list of them.
+pub fn notify_on_from_object(value: ObjectRef) -> Result<Vec<NotifyOn>, ObjectError> {
+    value.iter().map(NotifyOn::try_from).collect()
+}
+
+/// POST notifications as JSON. Only plain `http://` URLs are supported.
+#[derive(Uclicious, Clone, Debug)]
+#[ucl(skip_builder)]
+pub struct Webhook {
+    pub url: String,
+}
+
+/// Pipe notifications into a local `sendmail`.
+#[derive(Uclicious, Clone, Debug)]
+#[ucl(skip_builder)]
+pub struct Sendmail {
+    #[ucl(default = "::std::path::PathBuf::from(\"/usr/sbin/sendmail\")")]
+    pub command: PathBuf,
+    #[ucl(default)]
+    pub from: Option<String>,
+    pub to: Vec<String>,
+}
+
+/// Deliver notifications to an SMTP relay, without TLS or authentication.
+#[derive(Uclicious, Clone, Debug)]
+#[ucl(skip_builder)]
+pub struct Smtp {
+    pub server: String,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+#[derive(Uclicious, Clone, Debug)]
+#[ucl(skip_builder)]
+pub struct Notify {
+    #[ucl(default = "vec![NotifyOn::Failed]", map = "notify_on_from_object")]
+    pub on: Vec<NotifyOn>,
+    #[ucl(default)]
+    pub webhook: Option<Webhook>,
+    #[ucl(default)]
+    pub sendmail: Option<Sendmail>,
+    #[ucl(default)]
+    pub smtp: Option<Smtp>,
+}
+
+impl Notify {
+    pub fn wants(&self, event: Event) -> bool {
+        self.on.iter().any(|on| on.matches(event))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Completed,
+    CompletedWithErrors,
+    Failed,
+    RpoBreach,
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::Completed => write!(f, "completed"),
+            Event::CompletedWithErrors => write!(f, "completed with errors"),
+            Event::Failed => write!(f, "failed"),
+            Event::RpoBreach => write!(f, "breached RPO"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DatasetNotice {
+    pub dataset: String,
+    pub error: String,
+}
+
+/// What is sent to every backend. Webhooks get it as JSON.
+#[derive(Clone, Debug, Serialize)]
+pub struct Notification {
+    pub host: String,
+    pub task: String,
+    pub event: Event,
+    pub timestamp: DateTime<Utc>,
+    /// Error that stopped the whole task.
+    pub error: Option<String>,
+    pub datasets: Vec<DatasetNotice>,
+}
+
+impl Notification {
+    pub fn new(task: String, event: Event) -> Self {
+        Notification {
+            host: hostname(),
+            task,
+            event,
+            timestamp: Utc::now(),
+            error: None,
+            datasets: Vec::new(),
+        }
+    }
+
+    pub fn subject(&self) -> String {
+        format!(
+            "[gazpacho] {}: task {} {}",
+            self.host, self.task, self.event
+        )
+    }
+}
+
+impl Display for Notification {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Task {} {} on {} at {}.",
+            self.task,
+            self.event,
+            self.host,
+            self.timestamp.to_rfc3339()
+        )?;
+        if let Some(ref error) = self.error {
+            writeln!(f, "\n{}", error)?;
+        }
+        if !self.datasets.is_empty() {
+            writeln!(f)?;
+        }
+        for dataset in &self.datasets {
+            writeln!(f, "{}: {}", dataset.dataset, dataset.error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Send the notification to every configured backend if the configuration asks for it. Delivery
+/// happens on its own thread and failures are only logged.
+pub fn dispatch(logger: &Logger, notification: Notification) {
+    let notify = match CURRENT_CONFIGURATION
+        .get()
+        .and_then(|conf| conf.notify.clone())
+    {
+        Some(notify) => notify,
+        None => return,
+    };
+    if !notify.wants(notification.event) {
+        return;
+    }
+    let logger = logger.clone();
+    std::thread::spawn(move || {
+        for (backend, result) in deliver(&notify, &notification) {
+            match result {
+                Ok(()) => debug!(logger, "Sent notification via {}", backend),
+                Err(e) => warn!(logger, "Failed to send notification via {}: {}", backend, e),
+            }
+        }
+    });
+}
+
+/// Send the notification to every configured backend, regardless of conditions.
+pub fn deliver(
+    notify: &Notify,
+    notification: &Notification,
+) -> Vec<(&'static str, io::Result<()>)> {
+    let mut results = Vec::new();
+    if let Some(ref webhook) = notify.webhook {
+        let result = serde_json::to_vec(notification)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .and_then(|body| post_json(&webhook.url, &body));
+        results.push(("webhook", result));
+    }
+    if let Some(ref sendmail) = notify.sendmail {
+        let from = sendmail
+            .from
+            .clone()
+            .unwrap_or_else(|| format!("gazpacho@{}", notification.host));
+        let message = email(&from, &sendmail.to, notification);
+        results.push(("sendmail", send_sendmail(&sendmail.command, &message)));
+    }
+    if let Some(ref smtp) = notify.smtp {
+        let message = email(&smtp.from, &smtp.to, notification);
+        results.push((
+            "smtp",
+            send_smtp(&smtp.server, &smtp.from, &smtp.to, &message),
+        ));
+    }
+    results
+}
+
+fn email(from: &str, to: &[String], notification: &Notification) -> String {
+    format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}",
+        from,
+        to.join(", "),
+        notification.subject(),
+        notification.timestamp.to_rfc2822(),
+        notification.to_string().replace('\n', "\r\n")
+    )
+}
+
+/// Connect to the first address `address` resolves to that answers within `NETWORK_TIMEOUT`.
+fn connect(address: &str) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, NETWORK_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("\"{}\" didn't resolve to any address", address),
+        )
+    }))
+}
+
+fn post_json(url: &str, body: &[u8]) -> io::Result<()> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Only http:// webhooks are supported, got \"{}\"", url),
+        )
+    };
+    if !url.starts_with("http://") {
+        return Err(invalid());
+    }
+    let rest = &url["http://".len()..];
+    let (host, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    let mut stream = connect(&address)?;
+    stream.set_read_timeout(Some(NETWORK_TIMEOUT))?;
+    stream.set_write_timeout(Some(NETWORK_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    let mut status = String::new();
+    BufReader::new(&stream).read_line(&mut status)?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Webhook responded with \"{}\"", status.trim()),
+        )),
+    }
+}
+
+fn send_sendmail(command: &Path, message: &str) -> io::Result<()> {
+    // Recipients are taken from the headers.
+    let mut child = Command::new(command)
+        .arg("-t")
+        .arg("-i")
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let written = match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(message.as_bytes()),
+        None => Ok(()),
+    };
+    // Reaped even if it didn't take the message, stdin is closed by now.
+    let status = child.wait()?;
+    written?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} exited with {}", command.display(), status),
+        ))
+    }
+}
+
+/// Read a reply and fail unless it has the expected code.
+fn smtp_expect<R: BufRead>(reader: &mut R, code: &str) -> io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "SMTP server closed the connection",
+            ));
+        }
+        if !line.starts_with(code) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Unexpected SMTP reply \"{}\"", line.trim()),
+            ));
+        }
+        // Multiline replies continue with "250-".
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+fn smtp_command<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    command: &str,
+    code: &str,
+) -> io::Result<()> {
+    write!(writer, "{}\r\n", command)?;
+    smtp_expect(reader, code)
+}
+
+fn send_smtp(server: &str, from: &str, to: &[String], message: &str) -> io::Result<()> {
+    let stream = connect(server)?;
+    stream.set_read_timeout(Some(NETWORK_TIMEOUT))?;
+    stream.set_write_timeout(Some(NETWORK_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    smtp_expect(&mut reader, "220")?;
+    smtp_command(
+        &mut reader,
+        &mut writer,
+        &format!("HELO {}", hostname()),
+        "250",
+    )?;
+    smtp_command(
+        &mut reader,
+        &mut writer,
+        &format!("MAIL FROM:<{}>", from),
+        "250",
+    )?;
+    for rcpt in to {
+        smtp_command(
+            &mut reader,
+            &mut writer,
+            &format!("RCPT TO:<{}>", rcpt),
+            "25",
+        )?;
+    }
+    smtp_command(&mut reader, &mut writer, "DATA", "354")?;
+    for line in message.lines() {
+        // Dot-stuffing, so a line with a single dot doesn't end the message early.
+        if line.starts_with('.') {
+            writer.write_all(b".")?;
+        }
+        write!(writer, "{}\r\n", line.trim_end_matches('\r'))?;
+    }
+    smtp_command(&mut reader, &mut writer, ".", "250")?;
+    smtp_command(&mut reader, &mut writer, "QUIT", "221")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    fn notification() -> Notification {
+        let mut notification = Notification::new("hourly".to_string(), Event::CompletedWithErrors);
+        notification.datasets.push(DatasetNotice {
+            dataset: "z/usr".to_string(),
+            error: "broken pipe".to_string(),
+        });
+        notification
+    }
+
+    fn notify() -> Notify {
+        Notify {
+            on: vec![NotifyOn::Failed],
+            webhook: None,
+            sendmail: None,
+            smtp: None,
+        }
+    }
+
+    /// Stand-in server for a single connection, returns whatever `serve` makes of it.
+    fn stand_in<F>(serve: F) -> (String, JoinHandle<String>)
+    where
+        F: FnOnce(BufReader<TcpStream>, TcpStream) -> String + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve(BufReader::new(stream.try_clone().unwrap()), stream)
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn conditions() {
+        let notify = Notify {
+            on: vec![NotifyOn::CompletedWithErrors, NotifyOn::RpoBreach],
+            ..notify()
+        };
+        assert!(notify.wants(Event::CompletedWithErrors));
+        assert!(notify.wants(Event::RpoBreach));
+        assert!(!notify.wants(Event::Failed));
+        assert!(NotifyOn::Always.matches(Event::Completed));
+        assert!(!NotifyOn::Always.matches(Event::RpoBreach));
+    }
+
+    #[test]
+    fn posts_json_to_webhook() {
+        let (address, server) = stand_in(|mut reader, mut writer| {
+            let mut head = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.to_lowercase().starts_with("content-length:") {
+                    length = line[15..].trim().parse().unwrap();
+                }
+                head.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            writer
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            head + &String::from_utf8(body).unwrap()
+        });
+        let notify = Notify {
+            webhook: Some(Webhook {
+                url: format!("http://{}/hooks/gazpacho", address),
+            }),
+            ..notify()
+        };
+
+        let results = deliver(&notify, &notification());
+        assert_eq!(results.len(), 1);
+        results.into_iter().for_each(|(_, result)| result.unwrap());
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /hooks/gazpacho HTTP/1.1\r\n"));
+        assert!(request.contains("\"task\":\"hourly\""));
+        assert!(request.contains("\"event\":\"completed_with_errors\""));
+        assert!(request.contains("\"dataset\":\"z/usr\""));
+    }
+
+    #[test]
+    fn rejects_failing_webhook() {
+        let (address, server) = stand_in(|mut reader, mut writer| {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            writer
+                .write_all(b"HTTP/1.1 500 Internal Server Error\r\n\r\n")
+                .unwrap();
+            line
+        });
+        let url = format!("http://{}/", address);
+        assert!(post_json(&url, b"{}").is_err());
+        server.join().unwrap();
+        assert!(post_json("https://example.com/", b"{}").is_err());
+    }
+
+    #[test]
+    fn delivers_over_smtp() {
+        let (address, server) = stand_in(|mut reader, mut writer| {
+            fn reply(writer: &mut TcpStream, line: &str) {
+                writer.write_all(line.as_bytes()).unwrap();
+            }
+            reply(&mut writer, "220 stand-in ESMTP\r\n");
+            let mut received = String::new();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                received.push_str(&line);
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        reply(&mut writer, "250 queued\r\n");
+                    }
+                    continue;
+                }
+                match &line[..4] {
+                    "HELO" => reply(&mut writer, "250-stand-in\r\n250 HELP\r\n"),
+                    "DATA" => {
+                        in_data = true;
+                        reply(&mut writer, "354 go ahead\r\n");
+                    }
+                    "QUIT" => {
+                        reply(&mut writer, "221 bye\r\n");
+                        break;
+                    }
+                    _ => reply(&mut writer, "250 ok\r\n"),
+                }
+            }
+            received
+        });
+        let notify = Notify {
+            smtp: Some(Smtp {
+                server: address,
+                from: "gazpacho@example.com".to_string(),
+                to: vec!["ops@example.com".to_string()],
+            }),
+            ..notify()
+        };
+
+        let mut notification = notification();
+        notification.error = Some(".zfs is gone".to_string());
+        let results = deliver(&notify, &notification);
+        results.into_iter().for_each(|(_, result)| result.unwrap());
+        let received = server.join().unwrap();
+        assert!(received.contains("MAIL FROM:<gazpacho@example.com>\r\n"));
+        assert!(received.contains("RCPT TO:<ops@example.com>\r\n"));
+        assert!(received.contains("Subject: [gazpacho] "));
+        assert!(received.contains("task hourly completed with errors\r\n"));
+        assert!(received.contains("\r\n..zfs is gone\r\n"));
+        assert!(received.contains("z/usr: broken pipe\r\n"));
+    }
+}
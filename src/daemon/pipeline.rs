@@ -0,0 +1,404 @@
+use crate::daemon::compression::{Compression, Compressor};
+use crate::daemon::encryption::{Encryption, MaybeEncrypted};
+use slog::Logger;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Unit of data passed between stages.
+pub const CHUNK_SIZE: usize = 128 * 1024;
+
+/// Where a stage spent its time.
+#[derive(Clone, Debug)]
+pub struct StageMetrics {
+    pub name: &'static str,
+    /// Bytes that went into the stage.
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Time blocked reading input. For the first stage that's waiting for `zfs send`.
+    pub input_wait: Duration,
+    /// Time blocked writing output. For the last stage that's the destination itself.
+    pub output_wait: Duration,
+}
+
+impl StageMetrics {
+    pub fn busy(&self) -> Duration {
+        self.elapsed
+            .checked_sub(self.input_wait + self.output_wait)
+            .unwrap_or_default()
+    }
+
+    /// Bytes per second over the whole lifetime of the stage.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+impl fmt::Display for StageMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Stage {}: {} bytes in {:.1}s ({:.1} MiB/s), busy {:.1}s, waited {:.1}s for input and {:.1}s for output",
+            self.name,
+            self.bytes,
+            self.elapsed.as_secs_f64(),
+            self.throughput() / (1024.0 * 1024.0),
+            self.busy().as_secs_f64(),
+            self.input_wait.as_secs_f64(),
+            self.output_wait.as_secs_f64()
+        )
+    }
+}
+
+/// Reader or writer that keeps track of time spent in it.
+struct Timed<T> {
+    inner: T,
+    bytes: u64,
+    spent: Duration,
+}
+
+impl<T> Timed<T> {
+    fn new(inner: T) -> Self {
+        Timed {
+            inner,
+            bytes: 0,
+            spent: Duration::default(),
+        }
+    }
+}
+
+impl<T: Read> Read for Timed<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        let result = self.inner.read(buf);
+        self.spent += started.elapsed();
+        if let Ok(n) = result {
+            self.bytes += n as u64;
+        }
+        result
+    }
+}
+
+impl<T: Write> Write for Timed<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        let result = self.inner.write(buf);
+        self.spent += started.elapsed();
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let started = Instant::now();
+        let result = self.inner.flush();
+        self.spent += started.elapsed();
+        result
+    }
+}
+
+/// Create a buffer between two stages that holds up to `size` bytes. Writer blocks when it's
+/// full, reader blocks when it's empty.
+fn buffer(size: usize) -> (BufferWriter, BufferReader) {
+    let (tx, rx) = sync_channel((size / CHUNK_SIZE).max(1));
+    (
+        BufferWriter {
+            tx,
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+        },
+        BufferReader {
+            rx,
+            chunk: Vec::new(),
+            pos: 0,
+        },
+    )
+}
+
+struct BufferWriter {
+    tx: SyncSender<Vec<u8>>,
+    chunk: Vec<u8>,
+}
+
+impl BufferWriter {
+    fn send(&mut self) -> io::Result<()> {
+        let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+        self.tx
+            .send(chunk)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, NextStageGone))
+    }
+}
+
+/// A stage fails with this when a later stage failed, that error is the one to report.
+#[derive(Debug)]
+struct NextStageGone;
+
+impl fmt::Display for NextStageGone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Next stage of the pipeline is gone")
+    }
+}
+
+impl std::error::Error for NextStageGone {}
+
+fn is_next_stage_gone(e: &io::Error) -> bool {
+    e.get_ref()
+        .map_or(false, |inner| inner.is::<NextStageGone>())
+}
+
+impl Write for BufferWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(CHUNK_SIZE - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..n]);
+        if self.chunk.len() == CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(n)
+    }
+
+    /// Hand a partially filled chunk over to the next stage.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            Ok(())
+        } else {
+            self.send()
+        }
+    }
+}
+
+/// Yields EOF once the previous stage is done, whether it succeeded or not.
+struct BufferReader {
+    rx: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for BufferReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn pump<R: Read, W: Write>(input: &mut R, output: &mut W) -> io::Result<()> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        output.write_all(&buf[..n])?;
+    }
+}
+
+fn join<T>(name: &str, handle: JoinHandle<io::Result<T>>) -> io::Result<T> {
+    handle.join().unwrap_or_else(|_| {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Stage {} panicked", name),
+        ))
+    })
+}
+
+/// Push `input` through compression and encryption into `output`, each stage on its own thread
+/// with buffers of `buffer_size` bytes in between. Returns `input` and `output` back along with
+/// metrics of every stage.
+pub fn run<R, W>(
+    logger: &Logger,
+    input: R,
+    compression: &Option<Compression>,
+    encryption: &Option<Encryption>,
+    buffer_size: usize,
+    output: W,
+) -> io::Result<(R, W, Vec<StageMetrics>)>
+where
+    R: Read + Send + 'static,
+    W: Write,
+{
+    let (read_tx, read_rx) = buffer(buffer_size);
+    let read = std::thread::spawn(move || {
+        let started = Instant::now();
+        let mut input = Timed::new(input);
+        let mut output = Timed::new(read_tx);
+        pump(&mut input, &mut output)?;
+        output.flush()?;
+        let metrics = StageMetrics {
+            name: "read",
+            bytes: input.bytes,
+            elapsed: started.elapsed(),
+            input_wait: input.spent,
+            output_wait: output.spent,
+        };
+        Ok((input.inner, metrics))
+    });
+
+    // Plain stream has nothing to encode, the writer takes it straight from the reader.
+    let (encode, write_rx) = if compression.is_some() || encryption.is_some() {
+        let (encode_tx, encode_rx) = buffer(buffer_size);
+        let logger = logger.clone();
+        let compression = compression.clone();
+        let encryption = encryption.clone();
+        let encode = std::thread::spawn(move || {
+            let started = Instant::now();
+            let mut input = Timed::new(read_rx);
+            // Compression has to happen before encryption.
+            let mut sink = MaybeEncrypted::new(Timed::new(encode_tx), &encryption)?;
+            let sink = match compression {
+                Some(ref compression) => {
+                    let mut compressor = Compressor::new(&logger, sink, compression)?;
+                    pump(&mut input, &mut compressor)?;
+                    compressor.finish()?
+                }
+                None => {
+                    pump(&mut input, &mut sink)?;
+                    sink
+                }
+            };
+            let mut output = sink.finish()?;
+            output.flush()?;
+            Ok(StageMetrics {
+                name: "encode",
+                bytes: input.bytes,
+                elapsed: started.elapsed(),
+                input_wait: input.spent,
+                output_wait: output.spent,
+            })
+        });
+        (Some(encode), encode_rx)
+    } else {
+        (None, read_rx)
+    };
+
+    let started = Instant::now();
+    let mut input = Timed::new(write_rx);
+    let mut output = Timed::new(output);
+    let written = pump(&mut input, &mut output).and_then(|_| output.flush());
+    let write_metrics = StageMetrics {
+        name: "write",
+        bytes: input.bytes,
+        elapsed: started.elapsed(),
+        input_wait: input.spent,
+        output_wait: output.spent,
+    };
+    // Unblock stages still trying to hand data over.
+    drop(input);
+
+    let read = join("read", read);
+    let encode = encode.map(|encode| join("encode", encode)).transpose();
+    match (read, encode, written) {
+        (Ok((input, read_metrics)), Ok(encode_metrics), Ok(())) => {
+            let mut metrics = vec![read_metrics];
+            metrics.extend(encode_metrics);
+            metrics.push(write_metrics);
+            Ok((input, output.inner, metrics))
+        }
+        (read, encode, written) => {
+            let mut errors: Vec<io::Error> = vec![read.err(), encode.err(), written.err()]
+                .into_iter()
+                .flatten()
+                .collect();
+            let cause = errors
+                .iter()
+                .position(|e| !is_next_stage_gone(e))
+                .unwrap_or(0);
+            Err(errors.swap_remove(cause))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::daemon::compression::{decoder, GzipCompression};
+
+    #[test]
+    fn passes_stream_through_stages() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let data: Vec<u8> = (0..CHUNK_SIZE * 5 + 17)
+            .map(|idx| (idx % 13) as u8)
+            .collect();
+
+        let (_, plain, metrics) = run(
+            &logger,
+            io::Cursor::new(data.clone()),
+            &None,
+            &None,
+            0,
+            Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(plain, data);
+        assert_eq!(metrics.len(), 2);
+
+        let compression = Some(Compression::Gzip(GzipCompression { level: 6 }));
+        let (input, compressed, metrics) = run(
+            &logger,
+            io::Cursor::new(data.clone()),
+            &compression,
+            &None,
+            CHUNK_SIZE * 2,
+            Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(input.position(), data.len() as u64);
+        let names: Vec<_> = metrics.iter().map(|stage| stage.name).collect();
+        assert_eq!(names, vec!["read", "encode", "write"]);
+        assert_eq!(metrics[0].bytes, data.len() as u64);
+        assert_eq!(metrics[2].bytes, compressed.len() as u64);
+
+        let mut decompressed = Vec::new();
+        decoder("gzip", None, compressed.as_slice())
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[derive(Debug)]
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::Other, "broken"))
+        }
+    }
+
+    #[test]
+    fn fails_when_a_stage_fails() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let compression = Some(Compression::Gzip(GzipCompression { level: 6 }));
+        let err = run(&logger, Broken, &compression, &None, 0, Vec::new()).unwrap_err();
+        assert_eq!(err.to_string(), "broken");
+
+        // Destination that is full after 16 bytes.
+        let data = vec![0u8; CHUNK_SIZE * 4];
+        let mut full = [0u8; 16];
+        let err = run(
+            &logger,
+            io::Cursor::new(data),
+            &compression,
+            &None,
+            0,
+            io::Cursor::new(&mut full[..]),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+}
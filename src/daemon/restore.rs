@@ -0,0 +1,202 @@
+use crate::daemon::compression::decoder;
+use crate::daemon::destination::Destination;
+use crate::daemon::encryption::decrypt;
+use crate::daemon::ensured::pool::{PooledSession, SessionPool};
+use crate::daemon::ensured::EnsuredError;
+use crate::daemon::manifest::Manifest;
+use slog::{debug, Logger};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Where volumes of a backup are read from. Sessions go back to their pool once read.
+enum Source {
+    Local,
+    Sftp(PooledSession, SessionPool),
+}
+
+/// Reads volumes of a split backup one after another as a single stream.
+pub struct VolumeReader {
+    volumes: std::vec::IntoIter<PathBuf>,
+    current: Option<Box<dyn Read>>,
+    source: Source,
+}
+
+impl VolumeReader {
+    pub fn local(volumes: Vec<PathBuf>) -> Self {
+        VolumeReader::new(volumes, Source::Local)
+    }
+
+    pub fn sftp(volumes: Vec<PathBuf>, pooled: PooledSession, pool: &SessionPool) -> Self {
+        VolumeReader::new(volumes, Source::Sftp(pooled, pool.clone()))
+    }
+
+    fn new(volumes: Vec<PathBuf>, source: Source) -> Self {
+        VolumeReader {
+            volumes: volumes.into_iter(),
+            current: None,
+            source,
+        }
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        match &self.source {
+            Source::Local => Ok(Box::new(File::open(path)?)),
+            Source::Sftp(pooled, _) => Ok(Box::new(pooled.sftp.open(path)?)),
+        }
+    }
+}
+
+impl Drop for VolumeReader {
+    fn drop(&mut self) {
+        // Volume is closed before its session is handed to someone else.
+        self.current = None;
+        if let Source::Sftp(pooled, pool) = std::mem::replace(&mut self.source, Source::Local) {
+            pool.checkin(pooled);
+        }
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(file) = self.current.as_mut() {
+                let n = file.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+            }
+            match self.volumes.next() {
+                Some(path) => self.current = Some(self.open(&path)?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Files that make up the backup known as `target`, in order.
+pub fn volumes(manifest: &Manifest, target: &Path) -> Vec<PathBuf> {
+    if manifest.volumes.is_empty() {
+        return vec![target.to_path_buf()];
+    }
+    let folder = target.parent().unwrap_or_else(|| Path::new(""));
+    manifest
+        .volumes
+        .iter()
+        .map(|volume| folder.join(&volume.name))
+        .collect()
+}
+
+/// Manifest is written after every file of the backup is in place, a backup without one is
+/// incomplete and can't be restored.
+fn missing_manifest(target: &Path) -> EnsuredError {
+    EnsuredError::Io(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "Backup {} has no manifest, it's either missing or incomplete",
+            target.display()
+        ),
+    ))
+}
+
+fn parse_manifest(data: &[u8]) -> Result<Manifest, EnsuredError> {
+    Manifest::from_json(data)
+        .map_err(|e| EnsuredError::Io(io::Error::new(io::ErrorKind::InvalidData, e)))
+}
+
+/// Open a backup on a destination as a single stream, whether it's split or not. The stream is
+/// returned as stored, along with the manifest needed to make sense of it.
+pub fn open(
+    logger: &Logger,
+    dst: &Destination,
+    pool: &SessionPool,
+    target: &Path,
+) -> Result<(Manifest, VolumeReader), EnsuredError> {
+    debug!(logger, "Opening backup {}", target.display());
+    match (&dst.ssh, &dst.local) {
+        (Some(dst_ssh), None) => {
+            let pooled = pool.checkout(logger, dst_ssh)?;
+            let mut data = Vec::new();
+            let read = match pooled.sftp.open(&Manifest::path_for(target)) {
+                Ok(mut file) => file.read_to_end(&mut data).map_err(EnsuredError::from),
+                Err(_) => Err(missing_manifest(target)),
+            };
+            let manifest = match read.and_then(|_| parse_manifest(&data)) {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    pool.checkin(pooled);
+                    return Err(e);
+                }
+            };
+            let reader = VolumeReader::sftp(volumes(&manifest, target), pooled, pool);
+            Ok((manifest, reader))
+        }
+        (None, Some(_)) => {
+            let data = match std::fs::read(Manifest::path_for(target)) {
+                Ok(data) => data,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(missing_manifest(target))
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let manifest = parse_manifest(&data)?;
+            let reader = VolumeReader::local(volumes(&manifest, target));
+            Ok((manifest, reader))
+        }
+        (None, None) => Err(EnsuredError::MissingConfiguration),
+        (Some(_), Some(_)) => Err(EnsuredError::DuplicateConfiguration),
+    }
+}
+
+/// Open a backup as the `zfs send` stream it was made from: volumes are joined, decrypted with
+/// `key_file` and decompressed as recorded in the manifest.
+pub fn open_stream(
+    logger: &Logger,
+    dst: &Destination,
+    pool: &SessionPool,
+    target: &Path,
+    key_file: Option<&Path>,
+) -> Result<(Manifest, Box<dyn Read>), EnsuredError> {
+    let (manifest, reader) = open(logger, dst, pool, target)?;
+    let reader: Box<dyn Read> = match (&manifest.encryption, key_file) {
+        (Some(_), Some(key_file)) => Box::new(decrypt(reader, key_file)?),
+        (Some(encryption), None) => {
+            return Err(EnsuredError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Backup is encrypted with {}, key file is required",
+                    encryption.scheme
+                ),
+            )))
+        }
+        (None, _) => Box::new(reader),
+    };
+    let reader = match manifest.compression {
+        Some(ref compression) => decoder(&compression.codec, compression.window_log, reader)?,
+        None => reader,
+    };
+    Ok((manifest, reader))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::daemon::ensured::volume_path;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn concatenates_volumes() {
+        let folder = TempDir::new("restore");
+        let target = folder.join("a.zfs");
+        let volumes: Vec<PathBuf> = (0..3).map(|idx| volume_path(&target, idx)).collect();
+        for (volume, part) in volumes.iter().zip(["0123", "4567", "89"].iter()) {
+            std::fs::write(volume, part).unwrap();
+        }
+
+        let mut data = String::new();
+        VolumeReader::local(volumes)
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, "0123456789");
+    }
+}
@@ -0,0 +1,88 @@
+use crate::daemon::bandwidth::Throttle;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+/// Whether `splice_all` works on this platform.
+pub const SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// Most bytes moved by a single `splice(2)` call.
+const SPLICE_CHUNK: usize = 1024 * 1024;
+
+/// Move everything from pipe `input` into `output` with `splice(2)`, without copying it through
+/// userspace. Returns number of bytes moved, or `None` if `output` can't be spliced into, e.g. a
+/// filesystem without splice support, in which case nothing is read from `input`.
+#[cfg(target_os = "linux")]
+pub fn splice_all<I: AsRawFd, O: AsRawFd>(
+    input: &I,
+    output: &O,
+    throttle: &Throttle,
+) -> io::Result<Option<u64>> {
+    let mut total = 0;
+    loop {
+        let n = unsafe {
+            libc::splice(
+                input.as_raw_fd(),
+                std::ptr::null_mut(),
+                output.as_raw_fd(),
+                std::ptr::null_mut(),
+                SPLICE_CHUNK,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_MORE,
+            )
+        };
+        match n {
+            0 => return Ok(Some(total)),
+            n if n < 0 => {
+                let e = io::Error::last_os_error();
+                if total == 0 && e.raw_os_error() == Some(libc::EINVAL) {
+                    return Ok(None);
+                }
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            n => {
+                total += n as u64;
+                // Bucket may go into debt, so paying after the fact keeps the average in check.
+                throttle.acquire(n as usize);
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn splice_all<I: AsRawFd, O: AsRawFd>(
+    _input: &I,
+    _output: &O,
+    _throttle: &Throttle,
+) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+    use filedescriptor::Pipe;
+    use std::io::Write;
+
+    #[test]
+    fn splices_pipe_into_file() {
+        let dir = TempDir::new("splice");
+        let path = dir.join("stream");
+        let file = std::fs::File::create(&path).unwrap();
+        let data: Vec<u8> = (0..SPLICE_CHUNK * 2 + 17)
+            .map(|idx| (idx % 11) as u8)
+            .collect();
+
+        let Pipe { read, mut write } = Pipe::new().unwrap();
+        let expected = data.clone();
+        let writer = std::thread::spawn(move || write.write_all(&expected));
+        let moved = splice_all(&read, &file, &Throttle::new(None))
+            .unwrap()
+            .unwrap();
+        writer.join().unwrap().unwrap();
+
+        assert_eq!(moved, data.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+    }
+}
@@ -1,12 +1,16 @@
+use crate::daemon::control::{self, ControlContext};
 use crate::daemon::logging::GlobalLogger;
+use crate::daemon::metrics::{self, MetricsContext};
+use crate::daemon::system::actors::destination_manager::DestinationManager;
 use crate::daemon::system::actors::lifecycle::LifecycleManager;
 use crate::daemon::system::actors::maid::Maid;
 use crate::daemon::system::actors::task_manager::messages::ExecuteTask;
 use crate::daemon::system::actors::task_manager::TaskManager;
 use crate::daemon::system::messages::lifecycle::Signals;
+use crate::daemon::CURRENT_CONFIGURATION;
 use actix::prelude::*;
 use actix::{System, SystemService};
-use slog::{debug, o};
+use slog::{debug, error, o};
 use std::sync::mpsc;
 use std::thread::{sleep, JoinHandle};
 use std::time::Duration;
@@ -27,6 +31,30 @@ pub fn bootstrap_system(tx: mpsc::Sender<(Addr<LifecycleManager>, Addr<Maid>)>)
         .expect("Failed to install SIGINT handler");
 
         let task_registry = TaskManager::from_registry();
+        let control_socket = CURRENT_CONFIGURATION
+            .get()
+            .and_then(|conf| conf.daemon.control_socket.clone());
+        if let Some(path) = control_socket {
+            let ctx = ControlContext {
+                destinations: DestinationManager::from_registry(),
+                tasks: TaskManager::from_registry(),
+            };
+            if let Err(e) = control::spawn(log.clone(), path, ctx) {
+                error!(log, "Failed to open control socket: {}", e);
+            }
+        }
+        let metrics_conf = CURRENT_CONFIGURATION
+            .get()
+            .and_then(|conf| conf.daemon.metrics.clone());
+        if let Some(metrics_conf) = metrics_conf {
+            let ctx = MetricsContext {
+                tasks: TaskManager::from_registry(),
+                maid: Maid::from_registry(),
+            };
+            if let Err(e) = metrics::spawn(log.clone(), &metrics_conf, ctx) {
+                error!(log, "Failed to start metrics exporter: {}", e);
+            }
+        }
         std::thread::spawn(move || {
             sleep(Duration::from_secs(5));
             task_registry.do_send(ExecuteTask(String::from("test")));
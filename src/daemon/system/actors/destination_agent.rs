@@ -1,22 +1,130 @@
+use crate::daemon::bandwidth::Throttle;
+use crate::daemon::command::CommandSink;
+use crate::daemon::compression::{
+    sample_ratio, AdaptiveCompression, Compression, CompressionDecision, RatioSource,
+};
 use crate::daemon::destination::Destination;
-use crate::daemon::ensured::EnsuredDestination;
+use crate::daemon::encryption::Encryption;
+use crate::daemon::ensured::pool::SessionPool;
+use crate::daemon::ensured::probe::{test_destination, ProbeReport};
+use crate::daemon::ensured::{
+    discard_partial, file_extension, free_space, remove_stale_partials, write_manifest,
+    EnsuredDestination,
+};
 use crate::daemon::logging::GlobalLogger;
-use crate::daemon::system::messages::destination_manager::SaveFromPipe;
+use crate::daemon::manifest::{
+    Manifest, ManifestChecksum, ManifestCompression, ManifestEncryption, ManifestVolume,
+};
+use crate::daemon::measure::{checksum_of, Counted, Measured, CHECKSUM_ALGORITHM};
+use crate::daemon::pipeline;
+use crate::daemon::restore;
+use crate::daemon::splice;
+use crate::daemon::system::messages::destination_manager::{
+    CheckFreeSpace, FinalizeSave, RemoveStalePartials, SaveError, SaveFromPipe, SavedFile,
+    SpaceCheck, StoredBackup, TestDestination, VerifyBackup,
+};
+use crate::daemon::template::TemplateContext;
 use actix::{Actor, Handler, Supervised, SyncContext};
-use slog::{debug, o, warn, Logger};
-use zstd::Encoder;
+use filedescriptor::FileDescriptor;
+use slog::{debug, info, o, warn, Logger};
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+
 pub struct DestinationAgent {
     logger: Logger,
     config: Destination,
+    pool: SessionPool,
+    throttle: Throttle,
+    hostname: String,
 }
 
 impl DestinationAgent {
-    pub fn new(name: String, config: Destination) -> Self {
+    pub fn new(name: String, config: Destination, pool: SessionPool, throttle: Throttle) -> Self {
         let actor_name = format!("DestinationAgent[{}]", &name);
         let logger = GlobalLogger::get().new(o!("module" => module_path!(), "actor" => actor_name));
-        DestinationAgent { logger, config }
+        DestinationAgent {
+            logger,
+            config,
+            pool,
+            throttle,
+            hostname: crate::utils::hostname(),
+        }
+    }
+}
+/// Push the stream through compression, encryption and throttle into `dst`. Returns `rx` and
+/// `dst` along with size and checksum of what was written to it.
+fn transfer<R: Read + Send + 'static, W: Write>(
+    logger: &Logger,
+    rx: R,
+    compression: &Option<Compression>,
+    encryption: &Option<Encryption>,
+    throttle: &Throttle,
+    buffer_size: u64,
+    dst: W,
+) -> std::io::Result<(R, W, u64, String)> {
+    let measured_dst = Measured::new(throttle.wrap(dst));
+    let (rx, measured_dst, stages) = pipeline::run(
+        logger,
+        rx,
+        compression,
+        encryption,
+        buffer_size as usize,
+        measured_dst,
+    )?;
+    for stage in &stages {
+        info!(logger, "{}", stage);
+    }
+    let bytes_written = measured_dst.bytes();
+    let checksum = measured_dst.checksum();
+    Ok((
+        rx,
+        measured_dst.into_inner().into_inner(),
+        bytes_written,
+        checksum,
+    ))
+}
+
+/// Settle on compression of the stream. If adaptive compression wants a sample, the start of the
+/// stream is read into `head`, which may already hold a sample taken by another agent.
+fn choose_compression(
+    logger: &Logger,
+    compression: &Option<Compression>,
+    adaptive: &Option<AdaptiveCompression>,
+    history_ratio: Option<f64>,
+    rx: &mut FileDescriptor,
+    head: &mut Vec<u8>,
+) -> std::io::Result<(Option<Compression>, Option<CompressionDecision>)> {
+    let (compression, adaptive) = match (compression, adaptive) {
+        (Some(compression), Some(adaptive)) => (compression, adaptive),
+        _ => return Ok((compression.clone(), None)),
+    };
+    let (source, ratio) = if adaptive.sample > 0 {
+        let missing = adaptive.sample.saturating_sub(head.len() as u64);
+        rx.take(missing).read_to_end(head)?;
+        (
+            RatioSource::Sample,
+            sample_ratio(logger, compression, head)?,
+        )
+    } else if let Some(ratio) = history_ratio {
+        (RatioSource::History, ratio)
+    } else {
+        return Ok((Some(compression.clone()), None));
+    };
+    let (compression, decision) = adaptive.decide(compression, source, ratio);
+    info!(logger, "Adaptive compression {}", decision);
+    Ok((compression, Some(decision)))
+}
+
+/// Snapshot name without dataset: `gazpacho-20200301-1583020800` for
+/// `z/usr/ports@gazpacho-20200301-1583020800`.
+fn snapshot_name(snapshot: &Path) -> String {
+    let snapshot = snapshot.to_string_lossy();
+    match snapshot.find('@') {
+        Some(idx) => snapshot[idx + 1..].to_string(),
+        None => snapshot.to_string(),
     }
 }
+
 impl Actor for DestinationAgent {
     type Context = SyncContext<Self>;
 
@@ -32,36 +140,345 @@ impl Supervised for DestinationAgent {
 }
 
 impl Handler<SaveFromPipe> for DestinationAgent {
-    type Result = Result<(), String>;
+    type Result = Result<SavedFile, SaveError>;
 
     fn handle(&mut self, mut msg: SaveFromPipe, _ctx: &mut SyncContext<Self>) -> Self::Result {
         let logger = self
             .logger
             .new(o!("dataset" => msg.dataset.display().to_string(), "snapshot" => msg.snapshot.display().to_string()));
         debug!(logger, "Saving from pipe");
-        let mut ensured_dst = EnsuredDestination::ensure(
+        let snapshot = snapshot_name(&msg.snapshot);
+        let source = msg.source.as_ref().map(|source| snapshot_name(source));
+        let ctx = TemplateContext {
+            host: &self.hostname,
+            task: &msg.task,
+            pool: &msg.pool,
+            dataset: &msg.dataset,
+            snapshot: &snapshot,
+            source: source.as_deref(),
+            date: msg.date,
+            ext: "",
+        };
+        let encryption = match msg.encryption {
+            Some(ref encryption) => Some(ManifestEncryption {
+                scheme: encryption.scheme().to_string(),
+                key_id: encryption.key_id(),
+            }),
+            None => None,
+        };
+        let started_at = chrono::Utc::now();
+        let (compression, compression_decision) = choose_compression(
             &logger,
-            &self.config,
-            msg.dataset,
             &msg.compression,
-            &self.logger,
-            msg.date,
+            &msg.adaptive,
+            msg.history_ratio,
+            &mut msg.rx,
+            &mut msg.head,
         )
-        .map_err(|e| format!("{}", e))?;
-        debug!(logger, "Destination ensured");
-        if let Some(ref compression) = msg.compression {
-            let mut encoder = Encoder::new(ensured_dst, compression.zstd.level).unwrap();
-
-            if let Err(e) = encoder.multithread(compression.zstd.workers) {
-                warn!(logger, "Failed to set zstd multithreading: {}", e);
+        .map_err(|e| format!("Failed to sample the stream: {}", e))?;
+        let (target, volumes, bytes_raw, bytes_written, checksums) = match self.config.command {
+            Some(ref command) => {
+                if self.config.ssh.is_some() || self.config.local.is_some() {
+                    return Err(SaveError::Failed(String::from(
+                        "Duplicate destination configuration",
+                    )));
+                }
+                let ext = file_extension(&compression, &msg.encryption);
+                let ctx = TemplateContext { ext: &ext, ..ctx };
+                // Name the backup would have on a file destination.
+                let target = Path::new(&self.config.path_template.render(&ctx))
+                    .join(self.config.filename_template.render(&ctx));
+                let sink = CommandSink::spawn(&logger, command, &ctx).map_err(|e| e.to_string())?;
+                debug!(logger, "Command spawned");
+                let head = std::mem::take(&mut msg.head);
+                let rx = Measured::new(Counted::new(
+                    Cursor::new(head).chain(msg.rx),
+                    msg.progress.clone(),
+                ));
+                let (rx, sink, bytes_written, checksum) = transfer(
+                    &logger,
+                    rx,
+                    &compression,
+                    &msg.encryption,
+                    &self.throttle,
+                    self.config.buffer_size,
+                    sink,
+                )
+                .map_err(|e| e.to_string())?;
+                sink.finish().map_err(|e| e.to_string())?;
+                let checksums = Some((rx.checksum(), checksum));
+                (target, Vec::new(), rx.bytes(), bytes_written, checksums)
             }
-            let mut encoder = encoder.auto_finish();
-
-            std::io::copy(&mut msg.rx, &mut encoder).unwrap();
+            None => {
+                let mut ensured_dst = match EnsuredDestination::ensure(
+                    &logger,
+                    &self.config,
+                    &self.pool,
+                    &compression,
+                    &msg.encryption,
+                    ctx,
+                ) {
+                    Ok(ensured_dst) => ensured_dst,
+                    Err(e) if e.is_unreachable() => {
+                        return Err(SaveError::Unreachable(e.to_string(), msg));
+                    }
+                    Err(e) => return Err(SaveError::Failed(e.to_string())),
+                };
+                debug!(logger, "Destination ensured");
+                let target = ensured_dst.target().to_path_buf();
+                // Nothing to do with the stream on the way, let the kernel move it.
+                let zero_copy = splice::SUPPORTED
+                    && self.config.zero_copy
+                    && compression.is_none()
+                    && msg.encryption.is_none()
+                    && msg.head.is_empty();
+                let spliced = if zero_copy {
+                    ensured_dst.splice_from(&msg.rx, &self.throttle)
+                } else {
+                    None
+                };
+                let result = match spliced {
+                    Some(result) => {
+                        debug!(logger, "Spliced stream into destination");
+                        result.map(|bytes| {
+                            msg.progress.add(bytes);
+                            (bytes, ensured_dst, bytes, None)
+                        })
+                    }
+                    None => {
+                        let head = std::mem::take(&mut msg.head);
+                        let rx = Measured::new(Counted::new(
+                            Cursor::new(head).chain(msg.rx),
+                            msg.progress.clone(),
+                        ));
+                        transfer(
+                            &logger,
+                            rx,
+                            &compression,
+                            &msg.encryption,
+                            &self.throttle,
+                            self.config.buffer_size,
+                            ensured_dst,
+                        )
+                        .map(
+                            |(rx, ensured_dst, bytes_written, checksum)| {
+                                let checksums = Some((rx.checksum(), checksum));
+                                (rx.bytes(), ensured_dst, bytes_written, checksums)
+                            },
+                        )
+                    }
+                };
+                // On failure the destination is dropped by now, along with its possibly broken
+                // session.
+                let result = result.map_err(|e| e.to_string()).and_then(
+                    |(bytes_raw, ensured_dst, bytes_written, checksums)| {
+                        ensured_dst
+                            .finish(&self.pool)
+                            .map(|volumes| (bytes_raw, volumes, bytes_written, checksums))
+                            .map_err(|e| e.to_string())
+                    },
+                );
+                debug!(logger, "Closing pipe");
+                match result {
+                    Ok((bytes_raw, volumes, bytes_written, checksums)) => {
+                        (target, volumes, bytes_raw, bytes_written, checksums)
+                    }
+                    Err(e) => {
+                        if let Err(e) = discard_partial(&logger, &self.config, &self.pool, &target)
+                        {
+                            warn!(logger, "Failed to remove partial file: {}", e);
+                        }
+                        return Err(SaveError::Failed(e));
+                    }
+                }
+            }
+        };
+        // Unsplit backup is a single file named after the target.
+        let manifest_volumes = if volumes.iter().any(|volume| volume.file.target != target) {
+            volumes.iter().map(ManifestVolume::from).collect()
         } else {
-            std::io::copy(&mut msg.rx, &mut ensured_dst).unwrap();
+            Vec::new()
+        };
+        let manifest = Manifest {
+            gazpacho_version: crate::VERSION.to_string(),
+            host: self.hostname.clone(),
+            task: msg.task,
+            pool: msg.pool,
+            dataset: msg.dataset.display().to_string(),
+            snapshot,
+            guid: None,
+            kind: if source.is_some() {
+                "incremental"
+            } else {
+                "full"
+            }
+            .to_string(),
+            source,
+            // Filled in along with guid once the send is done.
+            send_flags: Vec::new(),
+            compression: compression.as_ref().map(ManifestCompression::from),
+            encryption,
+            bytes_raw,
+            bytes_written,
+            checksum: checksums
+                .filter(|_| self.config.checksum)
+                .map(|(raw, written)| ManifestChecksum {
+                    algorithm: CHECKSUM_ALGORITHM.to_string(),
+                    raw,
+                    written,
+                }),
+            volumes: manifest_volumes,
+            started_at,
+            completed_at: chrono::Utc::now(),
+        };
+        Ok(SavedFile {
+            destination: msg.destination,
+            target,
+            files: volumes.into_iter().map(|volume| volume.file).collect(),
+            manifest,
+            compression_decision,
+        })
+    }
+}
+
+impl Handler<FinalizeSave> for DestinationAgent {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: FinalizeSave, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        if !msg.commit {
+            for file in &msg.files {
+                file.discard(&self.logger, &self.config, &self.pool)
+                    .map_err(|e| e.to_string())?;
+            }
+            return Ok(());
+        }
+        // The program of a command destination got the stream, there is nowhere to put the manifest.
+        if self.config.command.is_some() {
+            return Ok(());
+        }
+        // Backup is complete only once its manifest is in place, so it goes last. Anything
+        // committed before a failure is taken back.
+        let mut committed = 0;
+        let mut result = Ok(());
+        for file in &msg.files {
+            result = file.commit(&self.logger, &self.config, &self.pool);
+            if result.is_err() {
+                break;
+            }
+            committed += 1;
+        }
+        if let (Ok(()), Some(manifest)) = (&result, &msg.manifest) {
+            result = write_manifest(
+                &self.logger,
+                &self.config,
+                &self.pool,
+                &msg.target,
+                manifest,
+            );
+        }
+        if let Err(e) = result {
+            let (committed, pending) = msg.files.split_at(committed);
+            for file in committed {
+                if let Err(e) = file.revert(&self.logger, &self.config, &self.pool) {
+                    warn!(
+                        self.logger,
+                        "Failed to remove {}: {}",
+                        file.target.display(),
+                        e
+                    );
+                }
+            }
+            // The one that failed to commit may or may not be there under its partial name.
+            for file in pending {
+                if let Err(e) = file.discard(&self.logger, &self.config, &self.pool) {
+                    debug!(
+                        self.logger,
+                        "Failed to remove {}: {}",
+                        file.partial.display(),
+                        e
+                    );
+                }
+            }
+            return Err(e.to_string());
         }
-        debug!(logger, "Closing pipe");
         Ok(())
     }
 }
+
+impl Handler<CheckFreeSpace> for DestinationAgent {
+    type Result = Result<SpaceCheck, String>;
+
+    fn handle(&mut self, msg: CheckFreeSpace, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        // Nothing to check for command destinations, treat them as having unlimited space.
+        if self.config.command.is_some() {
+            return Ok(SpaceCheck {
+                available: u64::MAX,
+                total: u64::MAX,
+                required: msg.required,
+                sufficient: true,
+                defer: None,
+            });
+        }
+        let config = &self.config.free_space;
+        let (available, total) =
+            free_space(&self.logger, &self.config, &self.pool).map_err(|e| e.to_string())?;
+        Ok(SpaceCheck {
+            available,
+            total,
+            required: msg.required,
+            sufficient: config.is_sufficient(available, total, msg.required),
+            defer: config.defer.and_then(|defer| defer.to_std().ok()),
+        })
+    }
+}
+
+impl Handler<TestDestination> for DestinationAgent {
+    type Result = Result<ProbeReport, String>;
+
+    fn handle(&mut self, msg: TestDestination, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        info!(self.logger, "Running destination self-test");
+        Ok(test_destination(
+            &self.logger,
+            &msg.destination,
+            &self.config,
+            &self.pool,
+        ))
+    }
+}
+
+impl Handler<RemoveStalePartials> for DestinationAgent {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoveStalePartials, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        // Command destinations don't keep files around.
+        if self.config.command.is_some() {
+            return;
+        }
+        match remove_stale_partials(&self.logger, &self.config, &self.pool, msg.before) {
+            Ok(0) => {}
+            Ok(removed) => info!(self.logger, "Removed {} stale partial files", removed),
+            Err(e) => warn!(self.logger, "Failed to remove stale partial files: {}", e),
+        }
+    }
+}
+
+impl Handler<VerifyBackup> for DestinationAgent {
+    type Result = Result<StoredBackup, String>;
+
+    fn handle(&mut self, msg: VerifyBackup, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        if self.config.command.is_some() {
+            return Err(String::from(
+                "Backups of a command destination can't be read back",
+            ));
+        }
+        info!(self.logger, "Verifying {}", msg.target.display());
+        let (manifest, reader) = restore::open(&self.logger, &self.config, &self.pool, &msg.target)
+            .map_err(|e| e.to_string())?;
+        let (bytes, checksum) = checksum_of(reader).map_err(|e| e.to_string())?;
+        Ok(StoredBackup {
+            manifest,
+            bytes,
+            checksum,
+        })
+    }
+}
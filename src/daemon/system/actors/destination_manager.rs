@@ -1,16 +1,35 @@
+use crate::daemon::bandwidth::Throttle;
+use crate::daemon::ensured::pool::SessionPool;
+use crate::daemon::ensured::probe::ProbeReport;
 use crate::daemon::logging::GlobalLogger;
 use crate::daemon::system::actors::destination_agent::DestinationAgent;
-use crate::daemon::system::messages::destination_manager::{NewDestinations, SaveFromPipe};
+use crate::daemon::system::messages::destination_manager::{
+    CheckFreeSpace, FinalizeSave, GetAgentSlots, NewDestinations, RemoveStalePartials, SaveError,
+    SaveFromPipe, SavedFile, SpaceCheck, StoredBackup, TestDestination, VerifyBackup,
+};
 use actix::{
-    Actor, Addr, Context, Handler, ResponseActFuture, Supervised, SyncArbiter, SystemService,
-    WrapFuture,
+    Actor, Addr, AsyncContext, Context, Handler, MessageResult, ResponseActFuture, Supervised,
+    SyncArbiter, SystemService, WrapFuture,
 };
 use slog::{debug, o, warn, Logger};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Semaphore;
+
+/// How often idle ssh sessions are checked for expiration.
+const IDLE_SESSIONS_CHECK_INTERVAL: Duration = Duration::from_secs(30);
 
 pub struct DestinationManager {
     logger: Logger,
     destinations: HashMap<String, Addr<DestinationAgent>>,
+    pools: HashMap<String, SessionPool>,
+    /// Destination-level `fallback` of each destination that has one.
+    fallbacks: HashMap<String, String>,
+    /// One permit per agent of each destination.
+    slots: HashMap<String, Arc<Semaphore>>,
+    /// Partial files older than this were left by a previous run.
+    started_at: SystemTime,
 }
 impl Default for DestinationManager {
     fn default() -> Self {
@@ -19,12 +38,27 @@ impl Default for DestinationManager {
         DestinationManager {
             logger,
             destinations: HashMap::new(),
+            pools: HashMap::new(),
+            fallbacks: HashMap::new(),
+            slots: HashMap::new(),
+            started_at: SystemTime::now(),
         }
     }
 }
 
 impl Actor for DestinationManager {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(IDLE_SESSIONS_CHECK_INTERVAL, |act, _ctx| {
+            for (name, pool) in &act.pools {
+                let closed = pool.close_idle();
+                if closed > 0 {
+                    debug!(act.logger, "Closed {} idle ssh sessions", closed; "destination" => name);
+                }
+            }
+        });
+    }
 }
 impl Supervised for DestinationManager {
     fn restarting(&mut self, _ctx: &mut Self::Context) {
@@ -39,32 +73,180 @@ impl Handler<NewDestinations> for DestinationManager {
 
     fn handle(&mut self, msg: NewDestinations, _ctx: &mut Context<Self>) -> Self::Result {
         debug!(self.logger, "Updating destination list");
+        let mut pools = HashMap::new();
+        let mut fallbacks = HashMap::new();
+        let mut slots = HashMap::new();
+        let started_at = self.started_at;
         let destinations = msg
             .0
             .into_iter()
             .map(|(name, conf)| {
                 let n = name.clone();
+                let pool = SessionPool::for_destination(conf.ssh.as_ref());
+                pools.insert(name.clone(), pool.clone());
+                if let Some(fallback) = conf.fallback.clone() {
+                    fallbacks.insert(name.clone(), fallback);
+                }
+                slots.insert(
+                    name.clone(),
+                    Arc::new(Semaphore::new(conf.parallelism as usize)),
+                );
+                let throttle = Throttle::new(conf.bandwidth.clone());
                 let addr = SyncArbiter::start(conf.parallelism as usize, move || {
-                    DestinationAgent::new(name.clone(), conf.clone())
+                    DestinationAgent::new(
+                        name.clone(),
+                        conf.clone(),
+                        pool.clone(),
+                        throttle.clone(),
+                    )
                 });
+                addr.do_send(RemoveStalePartials::new(started_at));
                 (n, addr)
             })
             .collect();
+        self.pools = pools;
+        self.fallbacks = fallbacks;
+        self.slots = slots;
         self.destinations = destinations;
     }
 }
 
 impl Handler<SaveFromPipe> for DestinationManager {
-    type Result = ResponseActFuture<Self, Result<(), String>>;
+    type Result = ResponseActFuture<Self, Result<SavedFile, SaveError>>;
 
     fn handle(&mut self, msg: SaveFromPipe, _ctx: &mut Context<Self>) -> Self::Result {
+        let dst = msg.destination.clone();
+        let maybe_addr = self.destinations.get(msg.destination.as_str()).cloned();
+        let fallback = msg
+            .fallback
+            .clone()
+            .or_else(|| self.fallbacks.get(msg.destination.as_str()).cloned())
+            .map(|fallback| {
+                let addr = self.destinations.get(fallback.as_str()).cloned();
+                (fallback, addr)
+            });
+        let logger = self.logger.clone();
+        Box::pin(
+            async move {
+                let addr = match maybe_addr {
+                    Some(addr) => addr,
+                    None => {
+                        return Err(SaveError::Failed(format!(
+                            "Destination {} not found",
+                            dst
+                        )))
+                    }
+                };
+                match addr.send(msg).await.map_err(|e| e.to_string())? {
+                    Err(SaveError::Unreachable(e, mut msg)) => match fallback {
+                        Some((fallback, Some(addr))) => {
+                            warn!(logger, "Destination is unreachable, sending to fallback `{}`: {}", fallback, e; "destination" => &dst);
+                            msg.destination = fallback;
+                            match addr.send(msg).await.map_err(|e| e.to_string())? {
+                                // Fallback of a fallback is not followed.
+                                Err(SaveError::Unreachable(e, _)) => Err(SaveError::Failed(e)),
+                                res => res,
+                            }
+                        }
+                        Some((fallback, None)) => Err(SaveError::Failed(format!(
+                            "{}; fallback destination {} not found",
+                            e, fallback
+                        ))),
+                        None => Err(SaveError::Failed(e)),
+                    },
+                    res => res,
+                }
+            }
+            .into_actor(self),
+        )
+    }
+}
+
+impl Handler<GetAgentSlots> for DestinationManager {
+    type Result = MessageResult<GetAgentSlots>;
+
+    fn handle(&mut self, msg: GetAgentSlots, _ctx: &mut Context<Self>) -> Self::Result {
+        // Sorted by name, so every step acquires them in the same order.
+        let mut slots = BTreeMap::new();
+        for (destination, fallback) in msg.destinations {
+            let fallback = fallback.or_else(|| self.fallbacks.get(&destination).cloned());
+            for name in std::iter::once(destination).chain(fallback) {
+                if let Some(slot) = self.slots.get(&name) {
+                    slots.insert(name, slot.clone());
+                }
+            }
+        }
+        MessageResult(slots.into_iter().map(|(_, slot)| slot).collect())
+    }
+}
+
+impl Handler<FinalizeSave> for DestinationManager {
+    type Result = ResponseActFuture<Self, Result<(), String>>;
+
+    fn handle(&mut self, msg: FinalizeSave, _ctx: &mut Context<Self>) -> Self::Result {
+        let dst = msg.destination.clone();
+        let maybe_addr = self.destinations.get(msg.destination.as_str()).cloned();
+        Box::pin(
+            async move {
+                if let Some(addr) = maybe_addr {
+                    addr.send(msg).await.map_err(|e| e.to_string())?
+                } else {
+                    Err(format!("Destination {} not found", dst))
+                }
+            }
+            .into_actor(self),
+        )
+    }
+}
+
+impl Handler<CheckFreeSpace> for DestinationManager {
+    type Result = ResponseActFuture<Self, Result<SpaceCheck, String>>;
+
+    fn handle(&mut self, msg: CheckFreeSpace, _ctx: &mut Context<Self>) -> Self::Result {
+        let dst = msg.destination.clone();
+        let maybe_addr = self.destinations.get(msg.destination.as_str()).cloned();
+        Box::pin(
+            async move {
+                if let Some(addr) = maybe_addr {
+                    addr.send(msg).await.map_err(|e| e.to_string())?
+                } else {
+                    Err(format!("Destination {} not found", dst))
+                }
+            }
+            .into_actor(self),
+        )
+    }
+}
+
+impl Handler<TestDestination> for DestinationManager {
+    type Result = ResponseActFuture<Self, Result<ProbeReport, String>>;
+
+    fn handle(&mut self, msg: TestDestination, _ctx: &mut Context<Self>) -> Self::Result {
+        let dst = msg.destination.clone();
+        let maybe_addr = self.destinations.get(msg.destination.as_str()).cloned();
+        Box::pin(
+            async move {
+                if let Some(addr) = maybe_addr {
+                    addr.send(msg).await.map_err(|e| e.to_string())?
+                } else {
+                    Err(format!("Destination {} not found", dst))
+                }
+            }
+            .into_actor(self),
+        )
+    }
+}
+
+impl Handler<VerifyBackup> for DestinationManager {
+    type Result = ResponseActFuture<Self, Result<StoredBackup, String>>;
+
+    fn handle(&mut self, msg: VerifyBackup, _ctx: &mut Context<Self>) -> Self::Result {
         let dst = msg.destination.clone();
         let maybe_addr = self.destinations.get(msg.destination.as_str()).cloned();
         Box::pin(
             async move {
                 if let Some(addr) = maybe_addr {
-                    let resp = addr.send(msg).await.unwrap();
-                    resp
+                    addr.send(msg).await.map_err(|e| e.to_string())?
                 } else {
                     Err(format!("Destination {} not found", dst))
                 }
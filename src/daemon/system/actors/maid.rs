@@ -1,15 +1,24 @@
 use crate::daemon::config::Configuration;
 use crate::daemon::logging::GlobalLogger;
-use crate::daemon::system::messages::maid::Cleanup;
+use crate::daemon::system::actors::task_manager::SNAPSHOT_PREFIX;
+use crate::daemon::system::messages::maid::{Cleanup, CountSnapshots, SnapshotCount};
 use crate::daemon::CURRENT_CONFIGURATION;
-use actix::{Actor, Context, Handler, Supervised, SystemService};
-use libzetta::zfs::DelegatingZfsEngine;
+use actix::{Actor, Context, Handler, MessageResult, Supervised, SystemService};
+use libzetta::zfs::{DelegatingZfsEngine, ZfsEngine};
+use regex::Regex;
 use slog::{error, info, o, trace, Logger};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// How often snapshots are counted for metrics, listing them is too slow to do on every scrape.
+const SNAPSHOT_COUNT_INTERVAL: Duration = Duration::from_secs(300);
 
 pub struct Maid {
     logger: Logger,
     z: DelegatingZfsEngine,
     configuration: Configuration,
+    /// Counted on startup, after each cleanup and every `SNAPSHOT_COUNT_INTERVAL`.
+    snapshot_counts: Vec<SnapshotCount>,
 }
 
 impl Default for Maid {
@@ -27,6 +36,7 @@ impl Default for Maid {
             logger,
             z,
             configuration,
+            snapshot_counts: Vec::new(),
         }
     }
 }
@@ -36,6 +46,10 @@ impl Actor for Maid {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         trace!(&self.logger, "Actor started");
+        self.snapshot_counts = self.count_snapshots();
+        ctx.run_interval(SNAPSHOT_COUNT_INTERVAL, |act, _ctx| {
+            act.snapshot_counts = act.count_snapshots();
+        });
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
@@ -52,5 +66,57 @@ impl Handler<Cleanup> for Maid {
 
     fn handle(&mut self, msg: Cleanup, _ctx: &mut Context<Self>) -> Self::Result {
         info!(self.logger, "Performing cleanup");
+        self.snapshot_counts = self.count_snapshots();
+    }
+}
+
+impl Handler<CountSnapshots> for Maid {
+    type Result = MessageResult<CountSnapshots>;
+
+    fn handle(&mut self, _msg: CountSnapshots, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.snapshot_counts.clone())
+    }
+}
+
+impl Maid {
+    fn count_snapshots(&self) -> Vec<SnapshotCount> {
+        let mut counts = Vec::new();
+        for (task_name, task) in &self.configuration.tasks {
+            let (zpool, filter) = task.strategy.get_zpool_and_filter();
+            let re = match Regex::new(&filter) {
+                Ok(re) => re,
+                Err(e) => {
+                    error!(self.logger, "Invalid filter of task {}: {}", task_name, e);
+                    continue;
+                }
+            };
+            let snapshots = match self.z.list_snapshots(&zpool) {
+                Ok(snapshots) => snapshots,
+                Err(e) => {
+                    error!(self.logger, "Failed to list snapshots of {}: {}", zpool, e);
+                    continue;
+                }
+            };
+            let mut per_dataset: BTreeMap<String, u64> = BTreeMap::new();
+            for snapshot in snapshots {
+                let snapshot = snapshot.to_string_lossy();
+                let mut parts = snapshot.splitn(2, '@');
+                if let (Some(dataset), Some(name)) = (parts.next(), parts.next()) {
+                    if name.starts_with(SNAPSHOT_PREFIX) && re.is_match(dataset) {
+                        *per_dataset.entry(dataset.to_string()).or_default() += 1;
+                    }
+                }
+            }
+            counts.extend(
+                per_dataset
+                    .into_iter()
+                    .map(|(dataset, count)| SnapshotCount {
+                        task: task_name.clone(),
+                        dataset,
+                        count,
+                    }),
+            );
+        }
+        counts
     }
 }
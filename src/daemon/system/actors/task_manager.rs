@@ -1,40 +1,61 @@
 use crate::daemon::config::Task;
 use crate::daemon::logging::GlobalLogger;
+use crate::daemon::metrics::MetricFamily;
+use crate::daemon::notify::{self, DatasetNotice, Event, Notification};
 use crate::daemon::strategy::Strategy;
 use crate::daemon::system::actors::destination_manager::DestinationManager;
 use crate::daemon::system::actors::task_manager::errors::RepositoryError;
 pub use crate::daemon::system::actors::task_manager::steps::StepError;
 use crate::daemon::system::actors::zfs_manager::ZfsManager;
 use crate::daemon::system::messages::destination_manager::NewDestinations;
+use crate::daemon::system::messages::zfs_manager::GetDatasetsForTask;
 use crate::daemon::system::shutdown;
 use crate::daemon::CURRENT_CONFIGURATION;
 use actix::fut::wrap_future;
 use actix::{
-    Actor, ActorFuture, Addr, AsyncContext, Context, Handler, ResponseFuture, SpawnHandle,
-    Supervised, SyncArbiter, SystemService,
+    Actor, ActorFuture, Addr, AsyncContext, Context, Handler, MessageResult, ResponseFuture,
+    SpawnHandle, Supervised, SyncArbiter, SystemService,
 };
 use chrono::Utc;
 use messages::{
-    ExecuteTask, GetSources, NeedsReset, RowId, StepLog, StepLogMessage, TaskLog, TaskLogMessage,
+    CompletionState, ExecuteTask, GetCompressionRatio, GetMetrics, GetProgress, GetRpoBreaches,
+    GetSources, GetStepChecksums, GetStepHistory, NeedsReset, RowId, RpoStatus, StepChecksums,
+    StepHistory, StepLog, StepLogMessage, TaskLog, TaskLogMessage, TaskProgress, UpdateProgress,
     UpdateResetCountsMessage,
 };
+use progress::RunningTask;
 use rusqlite::Connection;
 use slog::Logger;
-use slog::{debug, error, o, warn};
+use slog::{debug, error, info, o, warn};
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::Duration;
 
 pub mod errors;
 pub mod messages;
+mod progress;
 mod repository;
+mod rpo;
 mod steps;
 
+/// Every snapshot made by a task is named with this prefix.
+pub const SNAPSHOT_PREFIX: &str = "gazpacho-";
+
+/// How often progress of running tasks is logged.
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often datasets are checked against the RPO of their task.
+const RPO_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
 pub struct TaskManager {
     logger: Logger,
     db: Option<Connection>,
     tasks: HashMap<String, Task>,
     zfs_manager: Addr<ZfsManager>,
     active_runners: HashMap<String, SpawnHandle>,
+    progress: HashMap<String, RunningTask>,
+    /// Latest RPO check of every task that has one.
+    rpo: HashMap<String, Vec<RpoStatus>>,
 }
 impl Default for TaskManager {
     fn default() -> Self {
@@ -70,6 +91,8 @@ impl Default for TaskManager {
             tasks: HashMap::new(),
             zfs_manager,
             active_runners: HashMap::new(),
+            progress: HashMap::new(),
+            rpo: HashMap::new(),
         }
     }
 }
@@ -79,6 +102,11 @@ impl Actor for TaskManager {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         debug!(self.logger, "Actor started");
+        ctx.run_interval(PROGRESS_LOG_INTERVAL, |actor, _ctx| {
+            for (name, running) in &actor.progress {
+                info!(actor.logger, "{}", running.snapshot(name));
+            }
+        });
 
         if let Some(configuration) = CURRENT_CONFIGURATION.get() {
             let tasks: HashMap<String, Task> = configuration
@@ -86,15 +114,20 @@ impl Actor for TaskManager {
                 .clone()
                 .into_iter()
                 .filter(|(name, task)| {
-                    let dst = task.destination.as_str();
-                    if configuration.destinations.contains_key(dst) {
+                    let missing: Vec<&str> = task
+                        .destinations
+                        .iter()
+                        .map(|dst| dst.name.as_str())
+                        .filter(|dst| !configuration.destinations.contains_key(*dst))
+                        .collect();
+                    if missing.is_empty() {
                         true
                     } else {
                         error!(
                             self.logger,
-                            "Task '{}' specified a non-existent destination '{}' and will be skipped.",
+                            "Task '{}' specified non-existent destinations '{}' and will be skipped.",
                             name,
-                            dst
+                            missing.join("', '")
                         );
                         false
                     }
@@ -107,7 +140,7 @@ impl Actor for TaskManager {
             );
             let used_destinations: Vec<String> = tasks
                 .iter()
-                .map(|(_, task)| task.destination.clone())
+                .flat_map(|(_, task)| task.destinations.iter().map(|dst| dst.name.clone()))
                 .collect();
             self.tasks = tasks;
 
@@ -121,6 +154,8 @@ impl Actor for TaskManager {
             let dst_manager = DestinationManager::from_registry();
             dst_manager.do_send(new_destinations);
         }
+        self.check_rpo(ctx);
+        ctx.run_interval(RPO_CHECK_INTERVAL, |actor, ctx| actor.check_rpo(ctx));
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
@@ -136,6 +171,76 @@ impl Actor for TaskManager {
     }
 }
 
+impl TaskManager {
+    /// Compare the latest completed step of every dataset to the RPO of its task.
+    fn check_rpo(&mut self, ctx: &mut Context<Self>) {
+        for (task_name, task) in self.tasks.iter().filter(|(_, task)| task.has_rpo()) {
+            let (zpool, filter) = task.strategy.get_zpool_and_filter();
+            let task_name = task_name.clone();
+            let request = self
+                .zfs_manager
+                .send(GetDatasetsForTask::new(zpool, filter));
+            ctx.spawn(
+                wrap_future(request).map(move |datasets, actor: &mut TaskManager, _ctx| {
+                    match datasets {
+                        Ok(datasets) => actor.update_rpo(&task_name, &datasets),
+                        Err(e) => {
+                            error!(actor.logger, "Failed to check RPO of {}: {}", task_name, e)
+                        }
+                    }
+                }),
+            );
+        }
+    }
+
+    fn update_rpo(&mut self, task_name: &str, datasets: &[PathBuf]) {
+        let task = match self.tasks.get(task_name) {
+            Some(task) => task,
+            None => return,
+        };
+        let conn = self.db.as_ref().unwrap();
+        let history = repository::get_last_successes(conn, task_name).and_then(|successes| {
+            repository::get_first_runs(conn, task_name).map(|first_runs| (successes, first_runs))
+        });
+        let (last_successes, first_runs) = match history {
+            Ok(history) => history,
+            Err(e) => {
+                error!(self.logger, "Failed to check RPO of {}: {}", task_name, e);
+                return;
+            }
+        };
+        let previous = self.rpo.remove(task_name).unwrap_or_default();
+        let current = rpo::evaluate(
+            task_name,
+            task,
+            datasets,
+            &last_successes,
+            &first_runs,
+            &previous,
+            Utc::now(),
+        );
+        let (breached, recovered) = rpo::changes(&previous, &current);
+        for status in &breached {
+            error!(self.logger, "RPO breached: {}", status);
+        }
+        if !breached.is_empty() {
+            let mut notification = Notification::new(task_name.to_string(), Event::RpoBreach);
+            notification.datasets = breached
+                .iter()
+                .map(|status| DatasetNotice {
+                    dataset: status.dataset.clone(),
+                    error: status.standing(),
+                })
+                .collect();
+            notify::dispatch(&self.logger, notification);
+        }
+        for status in recovered {
+            info!(self.logger, "RPO restored: {}", status);
+        }
+        self.rpo.insert(task_name.to_string(), current);
+    }
+}
+
 impl SystemService for TaskManager {}
 
 impl Supervised for TaskManager {
@@ -174,10 +279,13 @@ impl Handler<ExecuteTask> for TaskManager {
         })
         .map(|key, actor: &mut TaskManager, _ctx| {
             actor.active_runners.remove(&key);
+            actor.progress.remove(&key);
         });
 
         let handler = ctx.spawn(runner_wrapped);
         self.active_runners.insert(name.clone(), handler);
+        self.progress
+            .insert(name.clone(), RunningTask::new(Utc::now()));
         Box::pin(async move {
             if let Ok(ret) = rx.await {
                 ret
@@ -239,9 +347,38 @@ impl Handler<StepLogMessage> for TaskManager {
                     msg.timestamp,
                 )
             }
-            StepLog::Completed { row_id, state } => {
-                repository::update_step_log(conn, row_id, state, msg.timestamp)
-            }
+            StepLog::Completed {
+                row_id,
+                state,
+                error,
+                checksum_raw,
+                encryption_key_id,
+                stats,
+            } => repository::update_step_log(
+                conn,
+                row_id,
+                state,
+                &error,
+                &checksum_raw,
+                &encryption_key_id,
+                &stats,
+                msg.timestamp,
+            ),
+            StepLog::DestinationCompleted {
+                row_id,
+                destination,
+                state,
+                error,
+                transfer,
+            } => repository::insert_step_destination_log(
+                conn,
+                row_id,
+                &destination,
+                state,
+                &error,
+                &transfer,
+                msg.timestamp,
+            ),
         }
     }
 }
@@ -273,6 +410,243 @@ impl Handler<GetSources> for TaskManager {
     }
 }
 
+impl Handler<GetCompressionRatio> for TaskManager {
+    type Result = Result<Option<f64>, rusqlite::Error>;
+
+    fn handle(&mut self, msg: GetCompressionRatio, _ctx: &mut Context<Self>) -> Self::Result {
+        let conn = self.db.as_ref().unwrap();
+        let dataset = msg.dataset.to_string_lossy();
+        repository::get_compression_ratio(
+            &conn,
+            &msg.task_name,
+            &dataset,
+            &msg.destination,
+            &msg.codec,
+        )
+    }
+}
+
+impl Handler<GetStepHistory> for TaskManager {
+    type Result = Result<Vec<StepHistory>, rusqlite::Error>;
+
+    fn handle(&mut self, msg: GetStepHistory, _ctx: &mut Context<Self>) -> Self::Result {
+        let conn = self.db.as_ref().unwrap();
+        let dataset = msg
+            .dataset
+            .as_ref()
+            .map(|dataset| dataset.to_string_lossy().to_string());
+        repository::get_step_history(&conn, &msg.task_name, dataset.as_deref(), msg.limit)
+    }
+}
+
+impl Handler<UpdateProgress> for TaskManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdateProgress, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(running) = self.progress.get_mut(&msg.task_name) {
+            running.apply(msg.dataset, msg.update);
+        }
+    }
+}
+
+impl Handler<GetProgress> for TaskManager {
+    type Result = MessageResult<GetProgress>;
+
+    fn handle(&mut self, msg: GetProgress, _ctx: &mut Context<Self>) -> Self::Result {
+        let mut progress: Vec<TaskProgress> = self
+            .progress
+            .iter()
+            .filter(|(name, _)| msg.task_name.as_ref().map_or(true, |task| task == *name))
+            .map(|(name, running)| running.snapshot(name))
+            .collect();
+        progress.sort_by(|a, b| a.task.cmp(&b.task));
+        MessageResult(progress)
+    }
+}
+
+impl Handler<GetRpoBreaches> for TaskManager {
+    type Result = MessageResult<GetRpoBreaches>;
+
+    fn handle(&mut self, msg: GetRpoBreaches, _ctx: &mut Context<Self>) -> Self::Result {
+        let mut breaches: Vec<RpoStatus> = self
+            .rpo
+            .iter()
+            .filter(|(name, _)| msg.task_name.as_ref().map_or(true, |task| task == *name))
+            .flat_map(|(_, statuses)| statuses.iter().filter(|status| status.is_breached()))
+            .cloned()
+            .collect();
+        breaches.sort_by(|a, b| (&a.task, &a.dataset).cmp(&(&b.task, &b.dataset)));
+        MessageResult(breaches)
+    }
+}
+
+impl Handler<GetMetrics> for TaskManager {
+    type Result = Result<Vec<MetricFamily>, rusqlite::Error>;
+
+    fn handle(&mut self, _msg: GetMetrics, _ctx: &mut Context<Self>) -> Self::Result {
+        let conn = self.db.as_ref().unwrap();
+        let states: Vec<String> = [
+            CompletionState::Pending,
+            CompletionState::Completed,
+            CompletionState::CompletedWithErrors,
+            CompletionState::Failed,
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+
+        let mut running =
+            MetricFamily::gauge("gazpacho_task_running", "Whether the task is running.");
+        let mut queued = MetricFamily::gauge(
+            "gazpacho_task_queued_datasets",
+            "Datasets of a running task waiting for a free slot.",
+        );
+        let mut task_names: Vec<&String> = self.tasks.keys().collect();
+        task_names.sort();
+        for task in task_names {
+            let progress = self
+                .progress
+                .get(task)
+                .map(|progress| progress.snapshot(task));
+            running.sample(&[("task", task.as_str())], progress.is_some() as u8 as f64);
+            let waiting = progress.map_or(0, |progress| progress.queued.len());
+            queued.sample(&[("task", task.as_str())], waiting as f64);
+        }
+
+        let mut task_state = MetricFamily::gauge(
+            "gazpacho_task_last_state",
+            "State the latest finished run of the task ended in.",
+        );
+        for (task, last) in repository::get_last_task_states(conn)? {
+            for state in &states {
+                task_state.sample(
+                    &[("task", task.as_str()), ("state", state.as_str())],
+                    (state == &last) as u8 as f64,
+                );
+            }
+        }
+
+        let mut dataset_state = MetricFamily::gauge(
+            "gazpacho_dataset_last_state",
+            "State the latest finished step of the dataset ended in.",
+        );
+        let mut last_success = MetricFamily::gauge(
+            "gazpacho_dataset_last_success_timestamp_seconds",
+            "When the dataset was last backed up successfully.",
+        );
+        let mut last_sent = MetricFamily::gauge(
+            "gazpacho_dataset_last_sent_bytes",
+            "Bytes sent by the latest finished step of the dataset.",
+        );
+        let mut last_written = MetricFamily::gauge(
+            "gazpacho_dataset_last_written_bytes",
+            "Bytes written to all destinations by the latest finished step of the dataset.",
+        );
+        let mut last_duration = MetricFamily::gauge(
+            "gazpacho_dataset_last_duration_seconds",
+            "How long the latest finished step of the dataset took.",
+        );
+        let mut last_ratio = MetricFamily::gauge(
+            "gazpacho_dataset_last_compression_ratio",
+            "Written to sent bytes of the latest finished step of the dataset.",
+        );
+        let mut sent_total = MetricFamily::counter(
+            "gazpacho_dataset_sent_bytes_total",
+            "Bytes sent by completed steps of the dataset.",
+        );
+        for dataset in repository::get_dataset_metrics(conn)? {
+            let labels = [
+                ("task", dataset.task.as_str()),
+                ("dataset", dataset.dataset.as_str()),
+            ];
+            for state in &states {
+                let labels = [labels[0], labels[1], ("state", state.as_str())];
+                dataset_state.sample(&labels, (state == &dataset.last_state) as u8 as f64);
+            }
+            if let Some(at) = dataset.last_success {
+                last_success.sample(&labels, at.timestamp() as f64);
+            }
+            if let Some(ref stats) = dataset.last_stats {
+                last_sent.sample(&labels, stats.bytes_raw as f64);
+                last_written.sample(&labels, stats.bytes_written as f64);
+                last_duration.sample(&labels, stats.elapsed.num_milliseconds() as f64 / 1000.0);
+                if stats.bytes_raw > 0 {
+                    last_ratio.sample(&labels, stats.bytes_written as f64 / stats.bytes_raw as f64);
+                }
+            }
+            sent_total.sample(&labels, dataset.sent_total as f64);
+        }
+
+        let mut destination_errors = MetricFamily::counter(
+            "gazpacho_destination_errors_total",
+            "Transfers that failed at the destination.",
+        );
+        for (task, destination, count) in repository::get_destination_errors(conn)? {
+            destination_errors.sample(
+                &[
+                    ("task", task.as_str()),
+                    ("destination", destination.as_str()),
+                ],
+                count as f64,
+            );
+        }
+
+        let now = Utc::now();
+        let mut success_age = MetricFamily::gauge(
+            "gazpacho_dataset_last_success_age_seconds",
+            "How old the latest completed backup of the dataset is.",
+        );
+        let mut rpo_seconds = MetricFamily::gauge(
+            "gazpacho_dataset_rpo_seconds",
+            "RPO the dataset is held to.",
+        );
+        let mut rpo_breached = MetricFamily::gauge(
+            "gazpacho_dataset_rpo_breached",
+            "Whether the dataset breached its RPO as of the latest check.",
+        );
+        let mut statuses: Vec<&RpoStatus> = self.rpo.values().flatten().collect();
+        statuses.sort_by(|a, b| (&a.task, &a.dataset).cmp(&(&b.task, &b.dataset)));
+        for status in statuses {
+            let labels = [
+                ("task", status.task.as_str()),
+                ("dataset", status.dataset.as_str()),
+            ];
+            if let Some(at) = status.last_success {
+                success_age.sample(&labels, (now - at).num_seconds() as f64);
+            }
+            rpo_seconds.sample(&labels, status.rpo.num_seconds() as f64);
+            rpo_breached.sample(&labels, status.is_breached() as u8 as f64);
+        }
+
+        Ok(vec![
+            running,
+            queued,
+            task_state,
+            dataset_state,
+            last_success,
+            last_sent,
+            last_written,
+            last_duration,
+            last_ratio,
+            sent_total,
+            destination_errors,
+            success_age,
+            rpo_seconds,
+            rpo_breached,
+        ])
+    }
+}
+
+impl Handler<GetStepChecksums> for TaskManager {
+    type Result = Result<Option<StepChecksums>, rusqlite::Error>;
+
+    fn handle(&mut self, msg: GetStepChecksums, _ctx: &mut Context<Self>) -> Self::Result {
+        let conn = self.db.as_ref().unwrap();
+        let dataset = msg.dataset.to_string_lossy();
+        repository::get_step_checksums(&conn, &msg.task_name, &dataset, &msg.snapshot)
+    }
+}
+
 impl Handler<UpdateResetCountsMessage> for TaskManager {
     type Result = Result<(), rusqlite::Error>;
 
@@ -1,8 +1,11 @@
 use crate::daemon::config::Task;
+use crate::daemon::manifest::ManifestCompression;
+use crate::daemon::measure::ByteCounter;
+use crate::daemon::metrics::MetricFamily;
 use crate::daemon::system::actors::task_manager::errors::RepositoryError;
 use crate::daemon::system::actors::task_manager::StepError;
 use actix::Message;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::path::PathBuf;
@@ -76,6 +79,56 @@ impl TimestampedMessage<TaskLog> {
     }
 }
 
+/// Stream received by a single destination.
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    /// Destination that actually got the stream. Differs from the task destination if it was
+    /// unreachable and the stream went to its fallback.
+    pub received_by: String,
+    /// Checksum of the file as written to the destination, if the destination records them.
+    pub checksum: Option<String>,
+    pub bytes_raw: u64,
+    pub bytes_written: u64,
+    /// Time it took the destination to receive the stream.
+    pub elapsed: Duration,
+    /// Codec the file was compressed with, needed to restore it.
+    pub compression: Option<ManifestCompression>,
+    /// What adaptive compression made of the stream, if it was enabled.
+    pub compression_decision: Option<String>,
+}
+
+impl Transfer {
+    /// Raw bytes per second.
+    pub fn throughput(&self) -> f64 {
+        bytes_per_second(self.bytes_raw, self.elapsed)
+    }
+}
+
+/// How much a step sent and how long it took.
+#[derive(Debug, Clone)]
+pub struct StepStats {
+    /// Size of `zfs send` stream.
+    pub bytes_raw: u64,
+    /// Total size of files on every destination that received the stream.
+    pub bytes_written: u64,
+    /// From the start of `zfs send` until the last destination was done.
+    pub elapsed: Duration,
+}
+
+impl StepStats {
+    /// Raw bytes per second.
+    pub fn throughput(&self) -> f64 {
+        bytes_per_second(self.bytes_raw, self.elapsed)
+    }
+}
+
+fn bytes_per_second(bytes: u64, elapsed: Duration) -> f64 {
+    match elapsed.num_milliseconds() {
+        ms if ms > 0 => bytes as f64 * 1000.0 / ms as f64,
+        _ => 0.0,
+    }
+}
+
 pub enum StepLog {
     Started {
         run_id: RowId,
@@ -88,6 +141,22 @@ pub enum StepLog {
     Completed {
         row_id: RowId,
         state: CompletionState,
+        error: Option<String>,
+        /// Checksum of `zfs send` stream.
+        checksum_raw: Option<String>,
+        /// Key required to decrypt the files of the step.
+        encryption_key_id: Option<String>,
+        /// Set if any destination received the stream.
+        stats: Option<StepStats>,
+    },
+    /// Outcome of the transfer to a single destination of the step.
+    DestinationCompleted {
+        row_id: RowId,
+        destination: String,
+        state: CompletionState,
+        error: Option<String>,
+        /// What was written, for destinations that received the stream.
+        transfer: Option<Transfer>,
     },
 }
 
@@ -124,15 +193,64 @@ impl TimestampedMessage<StepLog> {
         Self::started(run_id, task, pool, dataset, snapshot, source, Utc::now())
     }
 
-    pub fn completed(row_id: RowId, state: CompletionState, timestamp: DateTime<Utc>) -> Self {
+    pub fn completed(
+        row_id: RowId,
+        state: CompletionState,
+        error: Option<String>,
+        checksum_raw: Option<String>,
+        encryption_key_id: Option<String>,
+        stats: Option<StepStats>,
+        timestamp: DateTime<Utc>,
+    ) -> Self {
         Self {
             timestamp,
-            payload: StepLog::Completed { row_id, state },
+            payload: StepLog::Completed {
+                row_id,
+                state,
+                error,
+                checksum_raw,
+                encryption_key_id,
+                stats,
+            },
         }
     }
 
-    pub fn completed_now(row_id: RowId, state: CompletionState) -> Self {
-        Self::completed(row_id, state, Utc::now())
+    pub fn completed_now(
+        row_id: RowId,
+        state: CompletionState,
+        error: Option<String>,
+        checksum_raw: Option<String>,
+        encryption_key_id: Option<String>,
+        stats: Option<StepStats>,
+    ) -> Self {
+        Self::completed(
+            row_id,
+            state,
+            error,
+            checksum_raw,
+            encryption_key_id,
+            stats,
+            Utc::now(),
+        )
+    }
+
+    pub fn destination_completed_now(
+        row_id: RowId,
+        destination: String,
+        state: CompletionState,
+        error: Option<String>,
+        transfer: Option<Transfer>,
+    ) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            payload: StepLog::DestinationCompleted {
+                row_id,
+                destination,
+                state,
+                error,
+                transfer,
+            },
+        }
     }
 }
 
@@ -140,6 +258,127 @@ impl Message for TimestampedMessage<StepLog> {
     type Result = Result<RowId, rusqlite::Error>;
 }
 
+/// Checksums recorded for a completed step, used to verify backups before restoring them.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StepChecksums {
+    pub algorithm: String,
+    pub raw: String,
+    /// Checksum of the file keyed by destination that actually received it.
+    pub written: HashMap<String, String>,
+}
+
+/// Look up checksums of the latest completed step that produced `snapshot` of `dataset`.
+pub struct GetStepChecksums {
+    pub task_name: String,
+    pub dataset: PathBuf,
+    pub snapshot: String,
+}
+
+impl GetStepChecksums {
+    pub fn new(task_name: String, dataset: PathBuf, snapshot: String) -> Self {
+        GetStepChecksums {
+            task_name,
+            dataset,
+            snapshot,
+        }
+    }
+}
+
+impl Message for GetStepChecksums {
+    type Result = Result<Option<StepChecksums>, rusqlite::Error>;
+}
+
+/// Historical ratio of written to raw bytes for a dataset on a destination, over streams
+/// compressed with `codec`.
+pub struct GetCompressionRatio {
+    pub task_name: String,
+    pub dataset: PathBuf,
+    pub destination: String,
+    pub codec: String,
+}
+
+impl GetCompressionRatio {
+    pub fn new(task_name: String, dataset: PathBuf, destination: String, codec: String) -> Self {
+        GetCompressionRatio {
+            task_name,
+            dataset,
+            destination,
+            codec,
+        }
+    }
+}
+
+impl Message for GetCompressionRatio {
+    type Result = Result<Option<f64>, rusqlite::Error>;
+}
+
+/// Step as recorded in history.
+#[derive(Debug, Clone)]
+pub struct StepHistory {
+    pub dataset: String,
+    pub snapshot: String,
+    pub state: String,
+    pub started_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+    /// Not recorded for steps that sent nothing or ran before stats were recorded.
+    pub stats: Option<StepStats>,
+}
+
+impl StepHistory {
+    /// Ratio of written to raw bytes, over every destination of the step.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        self.stats
+            .as_ref()
+            .filter(|stats| stats.bytes_raw > 0)
+            .map(|stats| stats.bytes_written as f64 / stats.bytes_raw as f64)
+    }
+}
+
+impl Display for StepHistory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}@{} {}",
+            self.started_at.to_rfc3339(),
+            self.dataset,
+            self.snapshot,
+            self.state
+        )?;
+        if let Some(ref stats) = self.stats {
+            write!(
+                f,
+                ": {} bytes raw, {} bytes written in {}s, {:.1} MiB/s",
+                stats.bytes_raw,
+                stats.bytes_written,
+                stats.elapsed.num_seconds(),
+                stats.throughput() / (1024.0 * 1024.0)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Most recent steps of a task, newest first. Optionally only those of a single dataset.
+pub struct GetStepHistory {
+    pub task_name: String,
+    pub dataset: Option<PathBuf>,
+    pub limit: u32,
+}
+
+impl GetStepHistory {
+    pub fn new(task_name: String, dataset: Option<PathBuf>, limit: u32) -> Self {
+        GetStepHistory {
+            task_name,
+            dataset,
+            limit,
+        }
+    }
+}
+
+impl Message for GetStepHistory {
+    type Result = Result<Vec<StepHistory>, rusqlite::Error>;
+}
+
 pub struct NeedsReset {
     pub task_name: String,
     pub task: Task,
@@ -192,3 +431,210 @@ impl TimestampedMessage<UpdateResetCounts> {
         }
     }
 }
+
+/// Change in progress of a dataset of a running task.
+pub enum ProgressUpdate {
+    /// Waiting for a permit to start sending.
+    Queued,
+    /// Stream is being sent. Each destination counts raw bytes it received on its own.
+    Sending {
+        estimate: Option<u64>,
+        counters: Vec<ByteCounter>,
+    },
+    Finished,
+}
+
+pub struct UpdateProgress {
+    pub task_name: String,
+    pub dataset: PathBuf,
+    pub update: ProgressUpdate,
+}
+
+impl UpdateProgress {
+    pub fn new(task_name: String, dataset: PathBuf, update: ProgressUpdate) -> Self {
+        UpdateProgress {
+            task_name,
+            dataset,
+            update,
+        }
+    }
+}
+
+impl Message for UpdateProgress {
+    type Result = ();
+}
+
+/// Dataset of a running task that is being sent.
+#[derive(Debug, Clone)]
+pub struct DatasetProgress {
+    pub dataset: PathBuf,
+    /// Raw bytes sent so far.
+    pub sent: u64,
+    /// Size of the stream according to `zfs send -nP`.
+    pub estimate: Option<u64>,
+    pub started_at: DateTime<Utc>,
+}
+
+impl DatasetProgress {
+    /// Time left at the average rate so far.
+    pub fn eta(&self, now: DateTime<Utc>) -> Option<Duration> {
+        let estimate = self.estimate?;
+        let elapsed = (now - self.started_at).num_milliseconds();
+        if self.sent == 0 || elapsed <= 0 {
+            return None;
+        }
+        let left = estimate.saturating_sub(self.sent) as f64;
+        let ms = left * elapsed as f64 / self.sent as f64;
+        Some(Duration::milliseconds(ms as i64))
+    }
+}
+
+impl Display for DatasetProgress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} bytes", self.dataset.display(), self.sent)?;
+        if let Some(estimate) = self.estimate {
+            let percent = if estimate > 0 {
+                (self.sent as f64 * 100.0 / estimate as f64).min(100.0)
+            } else {
+                100.0
+            };
+            write!(f, " of ~{} ({:.1}%)", estimate, percent)?;
+        }
+        if let Some(eta) = self.eta(Utc::now()) {
+            write!(f, ", ETA {}s", eta.num_seconds())?;
+        }
+        Ok(())
+    }
+}
+
+/// What a running task is up to.
+#[derive(Debug, Clone)]
+pub struct TaskProgress {
+    pub task: String,
+    pub started_at: DateTime<Utc>,
+    /// Datasets that are done, whether they succeeded or not.
+    pub finished: usize,
+    pub sending: Vec<DatasetProgress>,
+    /// Datasets waiting for a free slot, see `parallelism` of the task.
+    pub queued: Vec<PathBuf>,
+}
+
+impl Display for TaskProgress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Task {} running since {}: {} finished, {} sending, {} queued",
+            self.task,
+            self.started_at.to_rfc3339(),
+            self.finished,
+            self.sending.len(),
+            self.queued.len()
+        )?;
+        for dataset in &self.sending {
+            write!(f, "\n  {}", dataset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Progress of running tasks, or of a single one.
+pub struct GetProgress {
+    pub task_name: Option<String>,
+}
+
+impl GetProgress {
+    pub fn new(task_name: Option<String>) -> Self {
+        GetProgress { task_name }
+    }
+}
+
+impl Message for GetProgress {
+    type Result = Vec<TaskProgress>;
+}
+
+/// Latest recorded outcome of a dataset, for metrics.
+#[derive(Debug, Clone)]
+pub struct DatasetMetrics {
+    pub task: String,
+    pub dataset: String,
+    pub last_state: String,
+    pub last_success: Option<DateTime<Utc>>,
+    /// Stats of the latest finished step.
+    pub last_stats: Option<StepStats>,
+    /// Raw bytes sent by every completed step.
+    pub sent_total: u64,
+}
+
+/// Collect everything the task manager knows as Prometheus metrics.
+pub struct GetMetrics;
+
+impl Message for GetMetrics {
+    type Result = Result<Vec<MetricFamily>, rusqlite::Error>;
+}
+
+/// How a dataset stands against the RPO of its task, as of the latest check.
+#[derive(Debug, Clone)]
+pub struct RpoStatus {
+    pub task: String,
+    pub dataset: String,
+    pub rpo: Duration,
+    /// `None` if the dataset was never backed up.
+    pub last_success: Option<DateTime<Utc>>,
+    /// First run of the dataset, or the first check if it never ran. A dataset that was never
+    /// backed up is held to the RPO from here.
+    pub first_seen: DateTime<Utc>,
+    pub checked_at: DateTime<Utc>,
+}
+
+impl RpoStatus {
+    /// Age of the latest completed backup when checked.
+    pub fn age(&self) -> Option<Duration> {
+        self.last_success.map(|at| self.checked_at - at)
+    }
+
+    pub fn is_breached(&self) -> bool {
+        let since = self.last_success.unwrap_or(self.first_seen);
+        self.checked_at - since > self.rpo
+    }
+
+    /// How the dataset stands, without naming it.
+    pub fn standing(&self) -> String {
+        let last = match self.age() {
+            Some(age) => format!("last completed {} ago", hours_or_minutes(age)),
+            None => format!(
+                "never completed, first seen {} ago",
+                hours_or_minutes(self.checked_at - self.first_seen)
+            ),
+        };
+        format!("{}, RPO {}", last, hours_or_minutes(self.rpo))
+    }
+}
+
+fn hours_or_minutes(duration: Duration) -> String {
+    if duration < Duration::hours(1) {
+        format!("{}m", duration.num_minutes())
+    } else {
+        format!("{}h", duration.num_hours())
+    }
+}
+
+impl Display for RpoStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}: {}", self.task, self.dataset, self.standing())
+    }
+}
+
+/// Datasets that breach their RPO.
+pub struct GetRpoBreaches {
+    pub task_name: Option<String>,
+}
+
+impl GetRpoBreaches {
+    pub fn new(task_name: Option<String>) -> Self {
+        GetRpoBreaches { task_name }
+    }
+}
+
+impl Message for GetRpoBreaches {
+    type Result = Vec<RpoStatus>;
+}
@@ -0,0 +1,126 @@
+use super::messages::{DatasetProgress, ProgressUpdate, TaskProgress};
+use crate::daemon::measure::ByteCounter;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+struct Sending {
+    started_at: DateTime<Utc>,
+    estimate: Option<u64>,
+    counters: Vec<ByteCounter>,
+}
+
+/// Progress of a running task as the `TaskManager` keeps it.
+pub struct RunningTask {
+    started_at: DateTime<Utc>,
+    finished: usize,
+    queued: Vec<PathBuf>,
+    sending: BTreeMap<PathBuf, Sending>,
+}
+
+impl RunningTask {
+    pub fn new(started_at: DateTime<Utc>) -> Self {
+        RunningTask {
+            started_at,
+            finished: 0,
+            queued: Vec::new(),
+            sending: BTreeMap::new(),
+        }
+    }
+
+    pub fn apply(&mut self, dataset: PathBuf, update: ProgressUpdate) {
+        self.queued.retain(|queued| queued != &dataset);
+        match update {
+            ProgressUpdate::Queued => self.queued.push(dataset),
+            ProgressUpdate::Sending { estimate, counters } => {
+                let sending = Sending {
+                    started_at: Utc::now(),
+                    estimate,
+                    counters,
+                };
+                self.sending.insert(dataset, sending);
+            }
+            ProgressUpdate::Finished => {
+                self.sending.remove(&dataset);
+                self.finished += 1;
+            }
+        }
+    }
+
+    pub fn snapshot(&self, task: &str) -> TaskProgress {
+        let sending = self
+            .sending
+            .iter()
+            .map(|(dataset, sending)| DatasetProgress {
+                dataset: dataset.clone(),
+                // Destinations read the same stream, the fastest one is how far `zfs send` got.
+                sent: sending
+                    .counters
+                    .iter()
+                    .map(ByteCounter::get)
+                    .max()
+                    .unwrap_or(0),
+                estimate: sending.estimate,
+                started_at: sending.started_at,
+            })
+            .collect();
+        TaskProgress {
+            task: task.to_string(),
+            started_at: self.started_at,
+            finished: self.finished,
+            sending,
+            queued: self.queued.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn tracks_datasets() {
+        let mut running = RunningTask::new(Utc::now());
+        running.apply(PathBuf::from("z/usr"), ProgressUpdate::Queued);
+        running.apply(PathBuf::from("z/var"), ProgressUpdate::Queued);
+        let counters = vec![ByteCounter::default(), ByteCounter::default()];
+        running.apply(
+            PathBuf::from("z/usr"),
+            ProgressUpdate::Sending {
+                estimate: Some(1000),
+                counters: counters.clone(),
+            },
+        );
+        counters[0].add(100);
+        counters[1].add(250);
+
+        let progress = running.snapshot("test");
+        assert_eq!(progress.queued, vec![PathBuf::from("z/var")]);
+        assert_eq!(progress.sending.len(), 1);
+        assert_eq!(progress.sending[0].sent, 250);
+
+        running.apply(PathBuf::from("z/usr"), ProgressUpdate::Finished);
+        let progress = running.snapshot("test");
+        assert!(progress.sending.is_empty());
+        assert_eq!(progress.finished, 1);
+    }
+
+    #[test]
+    fn eta() {
+        let started_at = Utc::now();
+        let progress = DatasetProgress {
+            dataset: PathBuf::from("z/usr"),
+            sent: 250,
+            estimate: Some(1000),
+            started_at,
+        };
+        let eta = progress.eta(started_at + Duration::seconds(10)).unwrap();
+        assert_eq!(eta, Duration::seconds(30));
+        let unknown = DatasetProgress {
+            estimate: None,
+            ..progress
+        };
+        assert!(unknown.eta(started_at + Duration::seconds(10)).is_none());
+    }
+}
@@ -1,4 +1,7 @@
-use super::messages::{CompletionState, RowId};
+use super::messages::{
+    CompletionState, DatasetMetrics, RowId, StepChecksums, StepHistory, StepStats, Transfer,
+};
+use crate::daemon::measure::CHECKSUM_ALGORITHM;
 use crate::daemon::system::actors::task_manager::errors::{InsertTaskLogError, UpdateTaskLogError};
 use chrono::{DateTime, Utc};
 use rusqlite::{params, Connection, OptionalExtension};
@@ -87,17 +90,297 @@ pub fn update_step_log(
     conn: &Connection,
     row_id: RowId,
     state: CompletionState,
+    error: &Option<String>,
+    checksum_raw: &Option<String>,
+    encryption_key_id: &Option<String>,
+    stats: &Option<StepStats>,
     timestamp: DateTime<Utc>,
 ) -> Result<RowId, rusqlite::Error> {
     let now = timestamp.to_rfc3339();
     let state = format!("{:?}", state);
-    let mut stmt =
-        conn.prepare("UPDATE step_log SET state = ?1, completed_at = ?2 WHERE id = ?3")?;
-    stmt.execute(params![state, now, row_id])?;
+    let algorithm = checksum_raw.as_ref().map(|_| CHECKSUM_ALGORITHM);
+    let bytes_raw = stats.as_ref().map(|s| s.bytes_raw as i64);
+    let bytes_written = stats.as_ref().map(|s| s.bytes_written as i64);
+    let elapsed_ms = stats.as_ref().map(|s| s.elapsed.num_milliseconds());
+    let throughput = stats.as_ref().map(StepStats::throughput);
+    let mut stmt = conn.prepare("UPDATE step_log SET state = ?1, completed_at = ?2, error = ?3, checksum_algorithm = ?4, checksum_raw = ?5, encryption_key_id = ?6, bytes_raw = ?7, bytes_written = ?8, elapsed_ms = ?9, throughput = ?10 WHERE id = ?11")?;
+    stmt.execute(params![
+        state,
+        now,
+        error,
+        algorithm,
+        checksum_raw,
+        encryption_key_id,
+        bytes_raw,
+        bytes_written,
+        elapsed_ms,
+        throughput,
+        row_id
+    ])?;
 
     Ok(row_id)
 }
 
+pub fn insert_step_destination_log(
+    conn: &Connection,
+    step_id: RowId,
+    destination: &str,
+    state: CompletionState,
+    error: &Option<String>,
+    transfer: &Option<Transfer>,
+    timestamp: DateTime<Utc>,
+) -> Result<RowId, rusqlite::Error> {
+    let now = timestamp.to_rfc3339();
+    let state = state.to_string();
+    let checksum = transfer.as_ref().and_then(|t| t.checksum.as_deref());
+    let bytes_raw = transfer.as_ref().map(|t| t.bytes_raw as i64);
+    let bytes_written = transfer.as_ref().map(|t| t.bytes_written as i64);
+    let received_by = transfer.as_ref().map(|t| t.received_by.as_str());
+    let compression = transfer.as_ref().and_then(|t| t.compression.as_ref());
+    let codec = compression.map(|c| c.codec.as_str());
+    let level = compression.map(|c| c.level);
+    let window_log = compression.and_then(|c| c.window_log);
+    let decision = transfer
+        .as_ref()
+        .and_then(|t| t.compression_decision.as_deref());
+    let elapsed_ms = transfer.as_ref().map(|t| t.elapsed.num_milliseconds());
+    let throughput = transfer.as_ref().map(Transfer::throughput);
+    let mut stmt = conn.prepare("INSERT INTO step_destination_log (step_id, destination, state, error, checksum_written, bytes_raw, bytes_written, received_by, compression_codec, compression_level, compression_window_log, compression_decision, elapsed_ms, throughput, completed_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)")?;
+    let row_id = stmt.insert(params![
+        step_id,
+        destination,
+        state,
+        error,
+        checksum,
+        bytes_raw,
+        bytes_written,
+        received_by,
+        codec,
+        level,
+        window_log,
+        decision,
+        elapsed_ms,
+        throughput,
+        now
+    ])?;
+    Ok(row_id)
+}
+
+/// Average ratio of written to raw bytes over recent successful transfers of the dataset to the
+/// destination that were compressed with `codec`.
+pub fn get_compression_ratio(
+    conn: &Connection,
+    task_name: &str,
+    dataset: &str,
+    destination: &str,
+    codec: &str,
+) -> Result<Option<f64>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT SUM(bytes_written), SUM(bytes_raw) FROM (SELECT d.bytes_written, d.bytes_raw FROM step_destination_log d JOIN step_log s ON d.step_id = s.id WHERE s.task = ?1 AND s.dataset = ?2 AND d.destination = ?3 AND d.state = ?4 AND d.compression_codec = ?5 AND d.bytes_raw > 0 ORDER BY d.completed_at DESC LIMIT 10)",
+    )?;
+    let state = CompletionState::Completed.to_string();
+    let (written, raw): (Option<i64>, Option<i64>) = stmt
+        .query_row(&[task_name, dataset, destination, &state, codec], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+    match (written, raw) {
+        (Some(written), Some(raw)) if raw > 0 => Ok(Some(written as f64 / raw as f64)),
+        _ => Ok(None),
+    }
+}
+
+/// Most recent steps of a task, newest first.
+pub fn get_step_history(
+    conn: &Connection,
+    task_name: &str,
+    dataset: Option<&str>,
+    limit: u32,
+) -> Result<Vec<StepHistory>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT dataset, snapshot, state, started_at, completed_at, bytes_raw, bytes_written, elapsed_ms FROM step_log WHERE task = ?1 AND (?2 IS NULL OR dataset = ?2) ORDER BY started_at DESC, id DESC LIMIT ?3",
+    )?;
+    let parse = |date: String| -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(&date)
+            .expect("Failed to parser timestamp")
+            .into()
+    };
+    let history = stmt
+        .query_map(params![task_name, dataset, limit], |row| {
+            let completed_at: Option<String> = row.get(4)?;
+            let bytes_raw: Option<i64> = row.get(5)?;
+            let bytes_written: Option<i64> = row.get(6)?;
+            let elapsed_ms: Option<i64> = row.get(7)?;
+            let stats = match (bytes_raw, bytes_written, elapsed_ms) {
+                (Some(bytes_raw), Some(bytes_written), Some(elapsed_ms)) => Some(StepStats {
+                    bytes_raw: bytes_raw as u64,
+                    bytes_written: bytes_written as u64,
+                    elapsed: chrono::Duration::milliseconds(elapsed_ms),
+                }),
+                _ => None,
+            };
+            Ok(StepHistory {
+                dataset: row.get(0)?,
+                snapshot: row.get(1)?,
+                state: row.get(2)?,
+                started_at: parse(row.get(3)?),
+                completed_at: completed_at.map(parse),
+                stats,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(history)
+}
+
+/// Latest finished step of every dataset, along with when it last succeeded and how much it
+/// sent in total.
+pub fn get_dataset_metrics(conn: &Connection) -> Result<Vec<DatasetMetrics>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT s.task, s.dataset, s.state, s.bytes_raw, s.bytes_written, s.elapsed_ms, \
+         (SELECT MAX(c.completed_at) FROM step_log c WHERE c.task = s.task AND c.dataset = s.dataset AND c.state = ?1), \
+         (SELECT COALESCE(SUM(c.bytes_raw), 0) FROM step_log c WHERE c.task = s.task AND c.dataset = s.dataset AND c.state = ?1) \
+         FROM step_log s WHERE s.id IN (SELECT MAX(id) FROM step_log WHERE completed_at IS NOT NULL GROUP BY task, dataset) \
+         ORDER BY s.task, s.dataset",
+    )?;
+    let state = CompletionState::Completed.to_string();
+    let metrics = stmt
+        .query_map(params![state], |row| {
+            let bytes_raw: Option<i64> = row.get(3)?;
+            let bytes_written: Option<i64> = row.get(4)?;
+            let elapsed_ms: Option<i64> = row.get(5)?;
+            let last_stats = match (bytes_raw, bytes_written, elapsed_ms) {
+                (Some(bytes_raw), Some(bytes_written), Some(elapsed_ms)) => Some(StepStats {
+                    bytes_raw: bytes_raw as u64,
+                    bytes_written: bytes_written as u64,
+                    elapsed: chrono::Duration::milliseconds(elapsed_ms),
+                }),
+                _ => None,
+            };
+            let last_success: Option<String> = row.get(6)?;
+            let sent_total: i64 = row.get(7)?;
+            Ok(DatasetMetrics {
+                task: row.get(0)?,
+                dataset: row.get(1)?,
+                last_state: row.get(2)?,
+                last_success: last_success.map(|date| {
+                    DateTime::parse_from_rfc3339(&date)
+                        .expect("Failed to parser timestamp")
+                        .into()
+                }),
+                last_stats,
+                sent_total: sent_total as u64,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(metrics)
+}
+
+/// When each dataset of the task last completed.
+pub fn get_last_successes(
+    conn: &Connection,
+    task_name: &str,
+) -> Result<HashMap<String, DateTime<Utc>>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT dataset, MAX(completed_at) FROM step_log WHERE task = ?1 AND state = ?2 GROUP BY dataset",
+    )?;
+    let state = CompletionState::Completed.to_string();
+    let successes = stmt
+        .query_map(params![task_name, state], |row| {
+            let completed_at: String = row.get(1)?;
+            let completed_at: DateTime<Utc> = DateTime::parse_from_rfc3339(&completed_at)
+                .expect("Failed to parser timestamp")
+                .into();
+            Ok((row.get(0)?, completed_at))
+        })?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+    Ok(successes)
+}
+
+/// When each dataset of the task was first run, whatever the outcome.
+pub fn get_first_runs(
+    conn: &Connection,
+    task_name: &str,
+) -> Result<HashMap<String, DateTime<Utc>>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT dataset, MIN(started_at) FROM step_log WHERE task = ?1 GROUP BY dataset",
+    )?;
+    let first_runs = stmt
+        .query_map(params![task_name], |row| {
+            let started_at: String = row.get(1)?;
+            let started_at: DateTime<Utc> = DateTime::parse_from_rfc3339(&started_at)
+                .expect("Failed to parser timestamp")
+                .into();
+            Ok((row.get(0)?, started_at))
+        })?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+    Ok(first_runs)
+}
+
+/// State of the latest finished run of every task.
+pub fn get_last_task_states(conn: &Connection) -> Result<Vec<(String, String)>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT task, state FROM task_log WHERE id IN (SELECT MAX(id) FROM task_log WHERE completed_at IS NOT NULL GROUP BY task) ORDER BY task",
+    )?;
+    let states = stmt
+        .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(states)
+}
+
+/// Number of failed transfers per task and destination.
+pub fn get_destination_errors(
+    conn: &Connection,
+) -> Result<Vec<(String, String, u64)>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT s.task, d.destination, COUNT(*) FROM step_destination_log d JOIN step_log s ON s.id = d.step_id WHERE d.state = ?1 GROUP BY s.task, d.destination ORDER BY s.task, d.destination",
+    )?;
+    let state = CompletionState::Failed.to_string();
+    let errors = stmt
+        .query_map(params![state], |row| {
+            let count: i64 = row.get(2)?;
+            Ok((row.get(0)?, row.get(1)?, count as u64))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(errors)
+}
+
+pub fn get_step_checksums(
+    conn: &Connection,
+    task_name: &str,
+    dataset: &str,
+    snapshot: &str,
+) -> Result<Option<StepChecksums>, rusqlite::Error> {
+    let mut step_stmt = conn.prepare(
+        "SELECT id, checksum_algorithm, checksum_raw FROM step_log WHERE task = ?1 AND dataset = ?2 AND snapshot = ?3 AND state = ?4 AND checksum_raw IS NOT NULL ORDER BY completed_at DESC",
+    )?;
+    let state = CompletionState::Completed.to_string();
+    let step = step_stmt
+        .query_row(&[task_name, dataset, snapshot, &state], |row| {
+            let id: RowId = row.get(0)?;
+            let algorithm: String = row.get(1)?;
+            let raw: String = row.get(2)?;
+            Ok((id, algorithm, raw))
+        })
+        .optional()?;
+    let (step_id, algorithm, raw) = match step {
+        Some(step) => step,
+        None => return Ok(None),
+    };
+
+    let mut dst_stmt = conn.prepare(
+        "SELECT COALESCE(received_by, destination), checksum_written FROM step_destination_log WHERE step_id = ?1 AND state = ?2 AND checksum_written IS NOT NULL",
+    )?;
+    let written = dst_stmt
+        .query_map(params![step_id, state], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<Result<HashMap<String, String>, _>>()?;
+    Ok(Some(StepChecksums {
+        algorithm,
+        raw,
+        written,
+    }))
+}
+
 pub fn get_count_and_date_of_last_reset(
     conn: &Connection,
     task_name: &str,
@@ -115,6 +398,9 @@ pub fn get_count_and_date_of_last_reset(
     .optional()
 }
 
+/// Snapshots to send incrementally from. A dataset is sent in full if the last snapshot didn't
+/// reach every destination itself, e.g. it went to a fallback, since that destination can't
+/// apply an incremental stream on top of it.
 pub fn get_sources(
     conn: &Connection,
     pool: &str,
@@ -122,7 +408,10 @@ pub fn get_sources(
     task_name: &str,
 ) -> Result<HashMap<PathBuf, PathBuf>, rusqlite::Error> {
     let mut last_snapshot_stms = conn.prepare(
-        "SELECT snapshot FROM step_log WHERE dataset = ?1 AND pool = ?2 AND task = ?3 AND state = ?4 ORDER BY completed_at DESC",
+        "SELECT id, snapshot FROM step_log WHERE dataset = ?1 AND pool = ?2 AND task = ?3 AND state = ?4 ORDER BY completed_at DESC",
+    )?;
+    let mut missed_stmt = conn.prepare(
+        "SELECT COUNT(*) FROM step_destination_log WHERE step_id = ?1 AND (state != ?2 OR received_by != destination)",
     )?;
 
     let mut ret = HashMap::with_capacity(datasets.len());
@@ -132,11 +421,16 @@ pub fn get_sources(
         let dataset_as_str = dataset.to_string_lossy().to_string();
         let snapshot = last_snapshot_stms
             .query_row(&[&dataset_as_str, pool, task_name, &state], |row| {
-                let snapshot: String = row.get(0)?;
-                Ok(snapshot)
+                let id: RowId = row.get(0)?;
+                let snapshot: String = row.get(1)?;
+                Ok((id, snapshot))
             })
             .optional()?;
-        if let Some(snap) = snapshot {
+        if let Some((step_id, snap)) = snapshot {
+            let missed: i64 = missed_stmt.query_row(params![step_id, state], |row| row.get(0))?;
+            if missed > 0 {
+                continue;
+            }
             let snapshot_full = format!("{}@{}", &dataset_as_str, snap).into();
             ret.insert(dataset.clone(), snapshot_full);
         }
@@ -180,6 +474,7 @@ pub fn update_reset_counts(
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::daemon::manifest::ManifestCompression;
     use std::error::Error;
 
     static TASK_NAME: &str = "test-task";
@@ -230,4 +525,228 @@ mod test {
         }
         Ok(())
     }
+
+    #[test]
+    fn step_checksums() -> Result<(), Box<dyn Error>> {
+        let conn = get_connection();
+        let now = Utc::now();
+        let run_id = insert_task_log(&conn, TASK_NAME, now)?;
+        let step_id = insert_step_log(
+            &conn, run_id, TASK_NAME, "z", "z/usr", "snap", &None, &None, now,
+        )?;
+        assert!(get_step_checksums(&conn, TASK_NAME, "z/usr", "snap")?.is_none());
+
+        let raw = Some("raw".to_string());
+        update_step_log(
+            &conn,
+            step_id,
+            CompletionState::Completed,
+            &None,
+            &raw,
+            &None,
+            &None,
+            now,
+        )?;
+        let transfer = Some(Transfer {
+            received_by: "fulcrum".to_string(),
+            checksum: Some("written".to_string()),
+            bytes_raw: 400,
+            bytes_written: 100,
+            elapsed: chrono::Duration::seconds(2),
+            compression: Some(ManifestCompression {
+                codec: "zstd".to_string(),
+                level: 3,
+                workers: 1,
+                window_log: None,
+            }),
+            compression_decision: None,
+        });
+        insert_step_destination_log(
+            &conn,
+            step_id,
+            "fulcrum",
+            CompletionState::Completed,
+            &None,
+            &transfer,
+            now,
+        )?;
+        // Stored uncompressed, doesn't count towards the ratio.
+        let stored = transfer.clone().map(|transfer| Transfer {
+            bytes_written: 400,
+            compression: None,
+            ..transfer
+        });
+        insert_step_destination_log(
+            &conn,
+            step_id,
+            "fulcrum",
+            CompletionState::Completed,
+            &None,
+            &stored,
+            now,
+        )?;
+
+        let checksums = get_step_checksums(&conn, TASK_NAME, "z/usr", "snap")?.unwrap();
+        assert_eq!(checksums.algorithm, CHECKSUM_ALGORITHM);
+        assert_eq!(checksums.raw, "raw");
+        assert_eq!(
+            checksums.written.get("fulcrum").map(String::as_str),
+            Some("written")
+        );
+        assert_eq!(
+            get_compression_ratio(&conn, TASK_NAME, "z/usr", "fulcrum", "zstd")?,
+            Some(0.25)
+        );
+        assert_eq!(
+            get_compression_ratio(&conn, TASK_NAME, "z/usr", "fulcrum", "xz")?,
+            None
+        );
+        assert_eq!(
+            get_compression_ratio(&conn, TASK_NAME, "z/usr", "temp", "zstd")?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sources_after_failover() -> Result<(), Box<dyn Error>> {
+        let conn = get_connection();
+        let datasets = vec![PathBuf::from("z/usr")];
+        let now = Utc::now();
+        let run_id = insert_task_log(&conn, TASK_NAME, now)?;
+        for (snapshot, received_by) in [("first", "fulcrum"), ("second", "temp")].iter() {
+            let step_id = insert_step_log(
+                &conn, run_id, TASK_NAME, "z", "z/usr", snapshot, &None, &None, now,
+            )?;
+            update_step_log(
+                &conn,
+                step_id,
+                CompletionState::Completed,
+                &None,
+                &None,
+                &None,
+                &None,
+                Utc::now(),
+            )?;
+            let transfer = Some(Transfer {
+                received_by: received_by.to_string(),
+                checksum: None,
+                bytes_raw: 400,
+                bytes_written: 100,
+                elapsed: chrono::Duration::seconds(2),
+                compression: None,
+                compression_decision: None,
+            });
+            insert_step_destination_log(
+                &conn,
+                step_id,
+                "fulcrum",
+                CompletionState::Completed,
+                &None,
+                &transfer,
+                now,
+            )?;
+            let sources = get_sources(&conn, "z", &datasets, TASK_NAME)?;
+            if *received_by == "fulcrum" {
+                assert_eq!(
+                    sources.get(&datasets[0]),
+                    Some(&PathBuf::from("z/usr@first"))
+                );
+            } else {
+                assert!(sources.is_empty());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn step_history() -> Result<(), Box<dyn Error>> {
+        let conn = get_connection();
+        let now = Utc::now();
+        let run_id = insert_task_log(&conn, TASK_NAME, now)?;
+        for (idx, dataset) in ["z/usr", "z/var"].iter().enumerate() {
+            let step_id = insert_step_log(
+                &conn,
+                run_id,
+                TASK_NAME,
+                "z",
+                dataset,
+                "snap",
+                &None,
+                &None,
+                now + chrono::Duration::seconds(idx as i64),
+            )?;
+            let stats = Some(StepStats {
+                bytes_raw: 4096,
+                bytes_written: 1024,
+                elapsed: chrono::Duration::seconds(2),
+            });
+            update_step_log(
+                &conn,
+                step_id,
+                CompletionState::Completed,
+                &None,
+                &None,
+                &None,
+                &stats,
+                now,
+            )?;
+        }
+
+        let history = get_step_history(&conn, TASK_NAME, None, 10)?;
+        let datasets: Vec<&str> = history.iter().map(|step| step.dataset.as_str()).collect();
+        assert_eq!(datasets, vec!["z/var", "z/usr"]);
+        let stats = history[0].stats.as_ref().unwrap();
+        assert_eq!(stats.elapsed, chrono::Duration::seconds(2));
+        assert_eq!(stats.throughput(), 2048.0);
+        assert_eq!(history[0].compression_ratio(), Some(0.25));
+
+        let history = get_step_history(&conn, TASK_NAME, Some("z/usr"), 10)?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].state, "Completed");
+        Ok(())
+    }
+
+    #[test]
+    fn metrics() -> Result<(), Box<dyn Error>> {
+        let conn = get_connection();
+        let now = Utc::now();
+        let run_id = insert_task_log(&conn, TASK_NAME, now)?;
+        let stats = Some(StepStats {
+            bytes_raw: 4096,
+            bytes_written: 1024,
+            elapsed: chrono::Duration::seconds(2),
+        });
+        for snapshot in ["first", "second"].iter() {
+            let state = || match *snapshot {
+                "first" => CompletionState::Completed,
+                _ => CompletionState::Failed,
+            };
+            let step_id = insert_step_log(
+                &conn, run_id, TASK_NAME, "z", "z/usr", snapshot, &None, &None, now,
+            )?;
+            update_step_log(&conn, step_id, state(), &None, &None, &None, &stats, now)?;
+            insert_step_destination_log(&conn, step_id, "fulcrum", state(), &None, &None, now)?;
+        }
+        update_task_log_state(&conn, run_id, CompletionState::CompletedWithErrors, now)?;
+
+        let datasets = get_dataset_metrics(&conn)?;
+        assert_eq!(datasets.len(), 1);
+        assert_eq!(datasets[0].last_state, "Failed");
+        assert_eq!(datasets[0].sent_total, 4096);
+        assert!(datasets[0].last_success.is_some());
+        assert_eq!(
+            get_last_successes(&conn, TASK_NAME)?.get("z/usr"),
+            datasets[0].last_success.as_ref()
+        );
+        assert_eq!(
+            get_last_task_states(&conn)?,
+            vec![(TASK_NAME.to_string(), "CompletedWithErrors".to_string())]
+        );
+        assert_eq!(
+            get_destination_errors(&conn)?,
+            vec![(TASK_NAME.to_string(), "fulcrum".to_string(), 1)]
+        );
+        Ok(())
+    }
 }
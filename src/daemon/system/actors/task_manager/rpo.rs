@@ -0,0 +1,113 @@
+use super::messages::RpoStatus;
+use crate::daemon::config::Task;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Status of every dataset of the task that is held to an RPO. Datasets that were seen on the
+/// `previous` check keep their `first_seen`.
+pub fn evaluate(
+    task_name: &str,
+    task: &Task,
+    datasets: &[PathBuf],
+    last_successes: &HashMap<String, DateTime<Utc>>,
+    first_runs: &HashMap<String, DateTime<Utc>>,
+    previous: &[RpoStatus],
+    now: DateTime<Utc>,
+) -> Vec<RpoStatus> {
+    datasets
+        .iter()
+        .filter_map(|dataset| {
+            let dataset = dataset.to_string_lossy().to_string();
+            let rpo = task.rpo_for(&dataset)?;
+            let first_seen = previous
+                .iter()
+                .find(|status| status.dataset == dataset)
+                .map(|status| status.first_seen)
+                .or_else(|| first_runs.get(&dataset).cloned())
+                .unwrap_or(now);
+            Some(RpoStatus {
+                task: task_name.to_string(),
+                last_success: last_successes.get(&dataset).cloned(),
+                dataset,
+                rpo,
+                first_seen,
+                checked_at: now,
+            })
+        })
+        .collect()
+}
+
+/// Datasets that breach their RPO now but didn't on the previous check, and ones that are back
+/// within it.
+pub fn changes<'a>(
+    previous: &[RpoStatus],
+    current: &'a [RpoStatus],
+) -> (Vec<&'a RpoStatus>, Vec<&'a RpoStatus>) {
+    let was_breached = |dataset: &str| {
+        previous
+            .iter()
+            .any(|status| status.dataset == dataset && status.is_breached())
+    };
+    let breached = current
+        .iter()
+        .filter(|status| status.is_breached() && !was_breached(&status.dataset))
+        .collect();
+    let recovered = current
+        .iter()
+        .filter(|status| !status.is_breached() && was_breached(&status.dataset))
+        .collect();
+    (breached, recovered)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Duration;
+
+    fn status(dataset: &str, hours_ago: Option<i64>, now: DateTime<Utc>) -> RpoStatus {
+        RpoStatus {
+            task: "test".to_string(),
+            dataset: dataset.to_string(),
+            rpo: Duration::hours(24),
+            last_success: hours_ago.map(|hours| now - Duration::hours(hours)),
+            first_seen: now - Duration::hours(30),
+            checked_at: now,
+        }
+    }
+
+    #[test]
+    fn reports_changes_once() {
+        let now = Utc::now();
+        let previous = vec![status("z/usr", Some(20), now), status("z/var", None, now)];
+        let current = vec![
+            status("z/usr", Some(25), now),
+            status("z/var", Some(1), now),
+        ];
+        assert!(current[0].is_breached());
+
+        let (breached, recovered) = changes(&previous, &current);
+        assert_eq!(breached.len(), 1);
+        assert_eq!(breached[0].dataset, "z/usr");
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].dataset, "z/var");
+
+        let (breached, recovered) = changes(&current, &current);
+        assert!(breached.is_empty());
+        assert!(recovered.is_empty());
+    }
+
+    #[test]
+    fn never_completed_counts_from_first_seen() {
+        let now = Utc::now();
+        let mut status = status("z/usr", None, now);
+        status.first_seen = now - Duration::minutes(5);
+        assert!(!status.is_breached());
+        status.rpo = Duration::minutes(3);
+        assert!(status.is_breached());
+        assert_eq!(
+            status.standing(),
+            "never completed, first seen 5m ago, RPO 3m"
+        );
+    }
+}
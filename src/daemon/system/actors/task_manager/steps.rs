@@ -1,19 +1,25 @@
 use super::messages::{
-    CompletionState, GetSources, NeedsReset, RowId, StepLogMessage, TaskLogMessage,
-    UpdateResetCountsMessage,
+    CompletionState, GetCompressionRatio, GetSources, NeedsReset, ProgressUpdate, RowId,
+    StepLogMessage, StepStats, TaskLogMessage, Transfer, UpdateProgress, UpdateResetCountsMessage,
 };
-use crate::daemon::config::Task;
+use crate::daemon::config::{Task, TaskDestination};
+use crate::daemon::measure::ByteCounter;
+use crate::daemon::notify::{self, DatasetNotice, Event, Notification};
 use crate::daemon::system::actors::destination_manager::DestinationManager;
 use crate::daemon::system::actors::task_manager::errors::RepositoryError;
 use crate::daemon::system::actors::task_manager::TaskManager;
 use crate::daemon::system::actors::zfs_manager::ZfsManager;
-use crate::daemon::system::messages::destination_manager::SaveFromPipe;
+use crate::daemon::system::messages::destination_manager::{
+    CheckFreeSpace, FinalizeSave, GetAgentSlots, SaveFromPipe, SavedFile,
+};
 use crate::daemon::system::messages::zfs_manager::{
-    GetDatasetsForTask, MakeSnapshots, SendSnapshotToPipe,
+    EstimateSendSize, GetDatasetsForTask, GetGuid, MakeSnapshots, SendSnapshotToPipe,
 };
+use crate::daemon::tee;
+use actix::clock::delay_for;
 use actix::{Addr, MailboxError, SystemService};
 use chrono::{DateTime, Utc};
-use filedescriptor::Pipe;
+use filedescriptor::{FileDescriptor, Pipe};
 use futures::stream::FuturesUnordered;
 use futures::{FutureExt, StreamExt};
 use rusqlite::Error as SqlError;
@@ -22,8 +28,12 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{Display, Error as FmtError, Formatter};
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use tokio::sync::Semaphore;
 
+/// How often free space is re-checked while a dataset is deferred.
+const FREE_SPACE_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 pub struct DatasetError {
     pub dataset: PathBuf,
@@ -36,6 +46,10 @@ pub enum DatasetErrorKind {
     SqlError(SqlError),
     PipeError(String),
     SendError(SendError),
+    /// Failed destinations along with their errors.
+    Destinations(Vec<(String, String)>),
+    /// Not enough destinations can fit the stream to satisfy destination policy.
+    InsufficientSpace(Vec<InsufficientSpace>),
     Other(String),
 }
 
@@ -58,11 +72,39 @@ impl Display for DatasetErrorKind {
             DatasetErrorKind::MailboxError(e) => write!(f, "{}", e),
             DatasetErrorKind::SendError(e) => write!(f, "{}", e),
             DatasetErrorKind::SqlError(e) => write!(f, "{}", e),
+            DatasetErrorKind::Destinations(errors) => {
+                let errors: Vec<String> = errors
+                    .iter()
+                    .map(|(dst, e)| format!("`{}`: {}", dst, e))
+                    .collect();
+                write!(f, "{}", errors.join("; "))
+            }
+            DatasetErrorKind::InsufficientSpace(destinations) => {
+                let errors: Vec<String> = destinations.iter().map(ToString::to_string).collect();
+                write!(f, "{}", errors.join("; "))
+            }
             DatasetErrorKind::Other(e) => write!(f, "{}", e),
         }
     }
 }
 
+#[derive(Debug)]
+pub struct InsufficientSpace {
+    pub destination: String,
+    pub available: u64,
+    pub required: u64,
+}
+
+impl Display for InsufficientSpace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        write!(
+            f,
+            "Not enough space on `{}`: {} bytes available, estimated backup size is {} bytes",
+            self.destination, self.available, self.required
+        )
+    }
+}
+
 impl Display for DatasetError {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
         f.debug_struct("DatasetError")
@@ -140,26 +182,65 @@ pub(super) async fn process_task_step_wrapper(
     self_addr: Addr<TaskManager>,
 ) -> Result<(), StepError> {
     info!(logger, "Processing");
-    let task = maybe_task.ok_or_else(|| StepError::TaskNotFound(task_name.clone()))?;
-    let run_id =
-        task_log_progress(&self_addr, TaskLogMessage::started_now(task_name.clone())).await?;
-    debug!(logger, "Run id: {}", run_id);
-    let result = process_task_step(
-        task_name,
-        task,
-        &logger,
-        zfs_addr,
-        self_addr.clone(),
-        run_id,
-    )
+    let result = async {
+        let task = maybe_task.ok_or_else(|| StepError::TaskNotFound(task_name.clone()))?;
+        let run_id =
+            task_log_progress(&self_addr, TaskLogMessage::started_now(task_name.clone())).await?;
+        debug!(logger, "Run id: {}", run_id);
+        process_task_step(
+            task_name.clone(),
+            task,
+            &logger,
+            zfs_addr,
+            self_addr.clone(),
+            run_id,
+        )
+        .await
+    }
     .await;
     match &result {
         Ok(()) => info!(logger, "Finished"),
         Err(e) => error!(logger, "Finished with errors: {}", e),
     };
+    match &result {
+        // Runs that got as far as logging their outcome are notified about by `process_task_step`.
+        Ok(()) | Err(StepError::PartialErrors(_)) => {}
+        Err(_) => notify::dispatch(
+            &logger,
+            notification(&task_name, &CompletionState::Failed, &result),
+        ),
+    }
     result
 }
 
+/// Notification about a finished task, with the error of every dataset that failed.
+fn notification(
+    task_name: &str,
+    state: &CompletionState,
+    result: &Result<(), StepError>,
+) -> Notification {
+    let event = match state {
+        CompletionState::Completed => Event::Completed,
+        CompletionState::CompletedWithErrors => Event::CompletedWithErrors,
+        CompletionState::Pending | CompletionState::Failed => Event::Failed,
+    };
+    let mut notification = Notification::new(task_name.to_string(), event);
+    match result {
+        Ok(()) => {}
+        Err(StepError::PartialErrors(errors)) => {
+            notification.datasets = errors
+                .iter()
+                .map(|e| DatasetNotice {
+                    dataset: e.dataset.to_string_lossy().to_string(),
+                    error: e.error.to_string(),
+                })
+                .collect();
+        }
+        Err(e) => notification.error = Some(e.to_string()),
+    }
+    notification
+}
+
 async fn process_task_step(
     task_name: String,
     task: Task,
@@ -197,7 +278,7 @@ async fn process_task_step(
         .iter()
         .map(|dataset| {
             let source = sources.get(dataset).cloned();
-            process_dataset(
+            let step = process_dataset(
                 &logger,
                 &zfs_addr,
                 &task,
@@ -210,7 +291,15 @@ async fn process_task_step(
                 task_name.clone(),
                 source,
                 now.clone(),
-            )
+            );
+            let progress =
+                UpdateProgress::new(task_name.clone(), dataset.clone(), ProgressUpdate::Finished);
+            let self_addr = &self_addr;
+            async move {
+                let result = step.await;
+                self_addr.do_send(progress);
+                result
+            }
         })
         .collect::<FuturesUnordered<_>>();
 
@@ -233,12 +322,14 @@ async fn process_task_step(
         }
         _ => CompletionState::Failed,
     };
+    let notice = notification(&task_name, &completion_state, &result);
     let completed_at = Utc::now();
     let log_msg = TaskLogMessage::completed(run_id, completion_state, completed_at.clone());
     let _ = task_log_progress(&self_addr, log_msg).await?;
 
     let reset_msg = UpdateResetCountsMessage::new(task_name.clone(), needs_reset, completed_at);
     self_addr.send(reset_msg).await??;
+    notify::dispatch(logger, notice);
     result
 }
 
@@ -330,62 +421,327 @@ async fn process_dataset(
         source.clone(),
     );
     let row_id = step_log_progress(msg, dataset.clone(), &self_addr).await?;
+    self_addr.do_send(UpdateProgress::new(
+        task_name.clone(),
+        dataset.clone(),
+        ProgressUpdate::Queued,
+    ));
+    let snapshot = PathBuf::from(format!("{}@{}", dataset.to_string_lossy(), &snapshot_name));
+
+    // Waiting for free space doesn't hold up other datasets, so it's done before taking a permit.
+    let mut failed: Vec<(String, String)> = Vec::new();
+    let estimate = estimate_send_size(&logger, zfs_addr, &snapshot, &source).await;
+    let (destinations, insufficient) = check_free_space(
+        &logger,
+        dst_manager,
+        self_addr,
+        task,
+        &task_name,
+        &dataset,
+        estimate,
+    )
+    .await;
+    for space in &insufficient {
+        failed.push((space.destination.clone(), space.to_string()));
+    }
+    if !task
+        .destination_policy
+        .is_satisfied(destinations.len(), task.destinations.len())
+    {
+        warn!(logger, "Skipping dataset, not enough space on destinations");
+        for (name, e) in &failed {
+            let msg = StepLogMessage::destination_completed_now(
+                row_id,
+                name.clone(),
+                CompletionState::Failed,
+                Some(e.clone()),
+                None,
+            );
+            step_log_progress(msg, dataset.clone(), &self_addr).await?;
+        }
+        let error = DatasetErrorKind::InsufficientSpace(insufficient);
+        let msg = StepLogMessage::completed_now(
+            row_id,
+            CompletionState::Failed,
+            Some(error.to_string()),
+            None,
+            None,
+            None,
+        );
+        step_log_progress(msg, dataset.clone(), &self_addr).await?;
+        return Err(DatasetError::new(dataset, error));
+    }
+
     debug!(logger, "Waiting for a permit work on {}", dataset.display());
     let _permit = semaphore.acquire().await;
     debug!(logger, "Got the permit the work on {}", dataset.display());
-    let snapshot = PathBuf::from(format!("{}@{}", dataset.to_string_lossy(), &snapshot_name));
-    let pipe = Pipe::new().map_err(|e| {
-        DatasetError::new(dataset.clone(), DatasetErrorKind::PipeError(e.to_string()))
-    })?;
-    let rx = pipe.read.try_clone().map_err(|e| {
-        DatasetError::new(dataset.clone(), DatasetErrorKind::PipeError(e.to_string()))
-    })?;
-    let dst_req = SaveFromPipe::new(
-        task.destination.clone(),
+
+    // The tee blocks on whichever destination doesn't read, so every agent the stream may go to
+    // is taken before anything is sent.
+    let slots = if destinations.len() > 1 {
+        let msg = GetAgentSlots::new(
+            destinations
+                .iter()
+                .map(|destination| (destination.name.clone(), destination.fallback.clone()))
+                .collect(),
+        );
+        dst_manager
+            .send(msg)
+            .await
+            .map_err(|e| DatasetError::new(dataset.clone(), DatasetErrorKind::MailboxError(e)))?
+    } else {
+        Vec::new()
+    };
+    let mut _reserved = Vec::with_capacity(slots.len());
+    for slot in &slots {
+        _reserved.push(slot.acquire().await);
+    }
+
+    let SendPipes { write, inputs, tee } = send_pipes(destinations.len())
+        .map_err(|e| DatasetError::new(dataset.clone(), DatasetErrorKind::PipeError(e)))?;
+    // Adaptive compression without sampling goes by how well earlier streams compressed.
+    let mut history_ratios = Vec::with_capacity(destinations.len());
+    for destination in &destinations {
+        let ratio = match (
+            &task.adaptive_compression,
+            task.compression_for(destination),
+        ) {
+            (Some(adaptive), Some(compression)) if adaptive.sample == 0 => {
+                let msg = GetCompressionRatio::new(
+                    task_name.to_string(),
+                    dataset.clone(),
+                    destination.name.clone(),
+                    compression.codec().to_string(),
+                );
+                match self_addr.send(msg).await {
+                    Ok(Ok(ratio)) => ratio,
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        history_ratios.push(ratio);
+    }
+    let counters: Vec<ByteCounter> = destinations.iter().map(|_| Default::default()).collect();
+    self_addr.do_send(UpdateProgress::new(
+        task_name.clone(),
         dataset.clone(),
-        snapshot.clone(),
-        task.compression.clone(),
-        rx,
-        date,
-    );
-    let mut dst_res = dst_manager.send(dst_req).fuse();
-    let zfs_req = SendSnapshotToPipe(snapshot.clone(), source.clone(), pipe);
+        ProgressUpdate::Sending {
+            estimate,
+            counters: counters.clone(),
+        },
+    ));
+    let transfer_started = Utc::now();
+    let mut dst_res = destinations
+        .iter()
+        .zip(inputs)
+        .zip(history_ratios)
+        .zip(counters)
+        .map(|(((destination, rx), history_ratio), progress)| {
+            let dst_req = SaveFromPipe::new(
+                destination.name.clone(),
+                task_name.clone(),
+                pool.clone(),
+                dataset.clone(),
+                snapshot.clone(),
+                source.clone(),
+                task.compression_for(destination),
+                task.encryption.clone(),
+                rx,
+                date,
+                destination.fallback.clone(),
+                task.adaptive_compression.clone(),
+                history_ratio,
+                progress,
+            );
+            let name = destination.name.clone();
+            dst_manager.send(dst_req).map(move |res| (name, res))
+        })
+        .collect::<FuturesUnordered<_>>();
+    if let Some((tee_input, outputs)) = tee {
+        tee::spawn(logger.clone(), tee_input, outputs);
+    }
+    let zfs_req = SendSnapshotToPipe(snapshot.clone(), source.clone(), write);
     let mut zfs_res = zfs_addr.send(zfs_req).fuse();
 
-    let mut error: Option<DatasetErrorKind> = None;
+    // Wait for every side even if one of them fails: files written so far have to be
+    // discarded and they're only known once destinations are done.
+    let mut zfs_error: Option<DatasetErrorKind> = None;
+    let mut send_flags = Vec::new();
+    let mut pending: Vec<(String, SavedFile)> = Vec::new();
     loop {
         futures::select! {
             zfs_r = zfs_res => {
                 match zfs_r {
-                    Err(e) => error = Some(DatasetErrorKind::MailboxError(e)),
+                    Err(e) => zfs_error = Some(DatasetErrorKind::MailboxError(e)),
                     Ok(res) => match res {
-                          Ok(_) => {},
-                          Err(e) => error = Some(DatasetErrorKind::Other(e.to_string()))
+                          Ok(flags) => send_flags = flags,
+                          Err(e) => zfs_error = Some(DatasetErrorKind::Other(e)),
                     }
                 }
             },
-            dst_r = dst_res => {
+            (name, dst_r) = dst_res.select_next_some() => {
                 match dst_r {
-                    Err(e) => error = Some(DatasetErrorKind::MailboxError(e)),
+                    Err(e) => failed.push((name, e.to_string())),
                     Ok(res) => match res {
-                          Ok(_) => {},
-                          Err(e) => error = Some(DatasetErrorKind::Other(e.to_string()))
+                          Ok(file) => pending.push((name, file)),
+                          Err(e) => failed.push((name, e.to_string())),
                     }
                 }
             },
             complete => break
         }
-        if error.is_some() {
-            break;
+    }
+    let stats = if pending.is_empty() {
+        None
+    } else {
+        Some(StepStats {
+            bytes_raw: pending
+                .iter()
+                .map(|(_, saved)| saved.manifest.bytes_raw)
+                .max()
+                .unwrap_or(0),
+            bytes_written: pending
+                .iter()
+                .map(|(_, saved)| saved.manifest.bytes_written)
+                .sum(),
+            elapsed: Utc::now() - transfer_started,
+        })
+    };
+    if let Some(ref stats) = stats {
+        info!(
+            logger,
+            "Sent {} bytes, wrote {} bytes in {}s ({:.1} MiB/s)",
+            stats.bytes_raw,
+            stats.bytes_written,
+            stats.elapsed.num_seconds(),
+            stats.throughput() / (1024.0 * 1024.0)
+        );
+    }
+
+    let succeeded = zfs_error.is_none()
+        && task
+            .destination_policy
+            .is_satisfied(pending.len(), task.destinations.len());
+    let guid = if succeeded {
+        match zfs_addr.send(GetGuid(snapshot.clone())).await {
+            Ok(Ok(guid)) => Some(guid),
+            Ok(Err(e)) => {
+                warn!(logger, "Failed to get snapshot guid for manifest: {}", e);
+                None
+            }
+            Err(e) => {
+                warn!(logger, "Failed to get snapshot guid for manifest: {}", e);
+                None
+            }
         }
+    } else {
+        None
+    };
+    // Every destination hashed the same stream, so any of them will do.
+    let checksum_raw = pending.iter().find_map(|(_, saved)| {
+        saved
+            .manifest
+            .checksum
+            .as_ref()
+            .map(|checksum| checksum.raw.clone())
+    });
+    let encryption_key_id = pending.first().and_then(|(_, saved)| {
+        saved
+            .manifest
+            .encryption
+            .as_ref()
+            .and_then(|encryption| encryption.key_id.clone())
+    });
+    for (name, mut saved) in pending {
+        let transfer = Transfer {
+            received_by: saved.destination.clone(),
+            checksum: saved
+                .manifest
+                .checksum
+                .as_ref()
+                .map(|checksum| checksum.written.clone()),
+            bytes_raw: saved.manifest.bytes_raw,
+            bytes_written: saved.manifest.bytes_written,
+            elapsed: saved.manifest.completed_at - saved.manifest.started_at,
+            compression: saved.manifest.compression.clone(),
+            compression_decision: saved.compression_decision.as_ref().map(|d| d.to_string()),
+        };
+        let finalize = if succeeded {
+            saved.manifest.guid = guid;
+            saved.manifest.send_flags = send_flags.clone();
+            FinalizeSave::commit(saved)
+        } else {
+            FinalizeSave::discard(saved)
+        };
+        match dst_manager.send(finalize).await {
+            Err(e) => failed.push((name, e.to_string())),
+            Ok(Err(e)) => failed.push((name, e)),
+            Ok(Ok(())) if succeeded => {
+                let msg = StepLogMessage::destination_completed_now(
+                    row_id,
+                    name,
+                    CompletionState::Completed,
+                    None,
+                    Some(transfer),
+                );
+                step_log_progress(msg, dataset.clone(), &self_addr).await?;
+            }
+            Ok(Ok(())) => {
+                let error = "Discarded because the step failed".to_string();
+                let msg = StepLogMessage::destination_completed_now(
+                    row_id,
+                    name,
+                    CompletionState::Failed,
+                    Some(error),
+                    None,
+                );
+                step_log_progress(msg, dataset.clone(), &self_addr).await?;
+            }
+        }
+    }
+    for (name, e) in &failed {
+        warn!(logger, "Failed to save to destination `{}`: {}", name, e);
+        let msg = StepLogMessage::destination_completed_now(
+            row_id,
+            name.clone(),
+            CompletionState::Failed,
+            Some(e.clone()),
+            None,
+        );
+        step_log_progress(msg, dataset.clone(), &self_addr).await?;
     }
 
-    let completion_state = if error.is_some() {
-        CompletionState::Completed
+    let committed = task.destinations.len() - failed.len();
+    let error = if zfs_error.is_some() {
+        zfs_error
+    } else if !succeeded
+        || !task
+            .destination_policy
+            .is_satisfied(committed, task.destinations.len())
+    {
+        Some(DatasetErrorKind::Destinations(failed))
     } else {
+        None
+    };
+
+    let completion_state = if error.is_some() {
         CompletionState::Failed
+    } else {
+        CompletionState::Completed
     };
-    let msg = StepLogMessage::completed_now(row_id, completion_state);
+    let error_message = error.as_ref().map(ToString::to_string);
+    let checksum_raw = checksum_raw.filter(|_| completion_state == CompletionState::Completed);
+    let encryption_key_id =
+        encryption_key_id.filter(|_| completion_state == CompletionState::Completed);
+    let msg = StepLogMessage::completed_now(
+        row_id,
+        completion_state,
+        error_message,
+        checksum_raw,
+        encryption_key_id,
+        stats,
+    );
     step_log_progress(msg, dataset.clone(), &self_addr).await?;
 
     if let Some(e) = error {
@@ -395,6 +751,143 @@ async fn process_dataset(
     }
 }
 
+/// Size of the stream according to `zfs send -nP`.
+async fn estimate_send_size(
+    logger: &Logger,
+    zfs_addr: &Addr<ZfsManager>,
+    snapshot: &PathBuf,
+    source: &Option<PathBuf>,
+) -> Option<u64> {
+    match zfs_addr
+        .send(EstimateSendSize(snapshot.clone(), source.clone()))
+        .await
+    {
+        Ok(Ok(estimate)) => Some(estimate),
+        Ok(Err(e)) => {
+            warn!(logger, "Failed to estimate stream size: {}", e);
+            None
+        }
+        Err(e) => {
+            warn!(logger, "Failed to estimate stream size: {}", e);
+            None
+        }
+    }
+}
+
+/// Ask every destination whether the estimated stream fits. Returns destinations to send to and
+/// the ones without enough space. Destinations are only excluded on a definite answer: if the
+/// size can't be estimated or the check itself fails, the transfer goes ahead.
+async fn check_free_space<'a>(
+    logger: &Logger,
+    dst_manager: &Addr<DestinationManager>,
+    self_addr: &Addr<TaskManager>,
+    task: &'a Task,
+    task_name: &str,
+    dataset: &PathBuf,
+    estimate: Option<u64>,
+) -> (Vec<&'a TaskDestination>, Vec<InsufficientSpace>) {
+    let estimate = match estimate {
+        Some(estimate) => estimate,
+        None => {
+            warn!(logger, "Skipping free space check without an estimate");
+            return (task.destinations.iter().collect(), Vec::new());
+        }
+    };
+    let mut destinations = Vec::with_capacity(task.destinations.len());
+    let mut insufficient = Vec::new();
+    for destination in &task.destinations {
+        // Without history assume the stream doesn't compress at all.
+        let ratio = if let Some(compression) = task.compression_for(destination) {
+            let msg = GetCompressionRatio::new(
+                task_name.to_string(),
+                dataset.clone(),
+                destination.name.clone(),
+                compression.codec().to_string(),
+            );
+            match self_addr.send(msg).await {
+                Ok(Ok(Some(ratio))) => ratio.min(1.0),
+                _ => 1.0,
+            }
+        } else {
+            1.0
+        };
+        let required = (estimate as f64 * ratio) as u64;
+        let mut deadline = None;
+        loop {
+            let msg = CheckFreeSpace::new(destination.name.clone(), required);
+            match dst_manager.send(msg).await {
+                Ok(Ok(check)) if !check.sufficient => {
+                    let deadline = *deadline
+                        .get_or_insert_with(|| check.defer.map(|defer| Instant::now() + defer));
+                    match deadline {
+                        Some(deadline) if Instant::now() < deadline => {
+                            info!(logger, "Not enough free space, waiting: {} bytes available, {} bytes required", check.available, required; "destination" => &destination.name);
+                            delay_for(FREE_SPACE_RECHECK_INTERVAL).await;
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    let space = InsufficientSpace {
+                        destination: destination.name.clone(),
+                        available: check.available,
+                        required,
+                    };
+                    warn!(logger, "{}", space);
+                    insufficient.push(space);
+                }
+                Ok(Ok(check)) => {
+                    debug!(logger, "Enough space on destination: {} bytes available, {} bytes required", check.available, required; "destination" => &destination.name);
+                    destinations.push(destination);
+                }
+                Ok(Err(e)) => {
+                    warn!(logger, "Failed to check free space: {}", e; "destination" => &destination.name);
+                    destinations.push(destination);
+                }
+                Err(e) => {
+                    warn!(logger, "Failed to check free space: {}", e; "destination" => &destination.name);
+                    destinations.push(destination);
+                }
+            }
+            break;
+        }
+    }
+    (destinations, insufficient)
+}
+
+/// Pipes a stream goes through: the write end for `zfs send`, a read end per destination and,
+/// with more than one destination, the input and outputs of the tee in between. No other handle
+/// to the read side is kept, so `zfs send` fails instead of blocking once every reader is gone.
+struct SendPipes {
+    write: FileDescriptor,
+    inputs: Vec<FileDescriptor>,
+    tee: Option<(FileDescriptor, Vec<FileDescriptor>)>,
+}
+
+fn send_pipes(destinations: usize) -> Result<SendPipes, String> {
+    let Pipe { read, write } = Pipe::new().map_err(|e| e.to_string())?;
+    // With a single destination the agent reads straight from zfs send, otherwise the stream is
+    // teed into a pipe per destination.
+    if destinations == 1 {
+        return Ok(SendPipes {
+            write,
+            inputs: vec![read],
+            tee: None,
+        });
+    }
+    let mut inputs = Vec::with_capacity(destinations);
+    let mut outputs = Vec::with_capacity(destinations);
+    for _ in 0..destinations {
+        let dst_pipe = Pipe::new().map_err(|e| e.to_string())?;
+        inputs.push(dst_pipe.read);
+        outputs.push(dst_pipe.write);
+    }
+    Ok(SendPipes {
+        write,
+        inputs,
+        tee: Some((read, outputs)),
+    })
+}
+
 async fn step_log_progress(
     msg: StepLogMessage,
     dataset: PathBuf,
@@ -411,5 +904,32 @@ async fn step_log_progress(
 fn get_snapshot_name(now: &DateTime<Utc>) -> String {
     let date = now.format("%Y%m%d");
     let timestamp = now.timestamp();
-    format!("gazpacho-{}-{}", date, timestamp)
+    format!("{}{}-{}", super::SNAPSHOT_PREFIX, date, timestamp)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn send_fails_once_readers_are_gone() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        for destinations in 1..=2 {
+            let SendPipes {
+                mut write,
+                inputs,
+                tee,
+            } = send_pipes(destinations).unwrap();
+            let tee = tee.map(|(input, outputs)| tee::spawn(logger.clone(), input, outputs));
+            drop(inputs);
+
+            let chunk = vec![0u8; 1024 * 1024];
+            let sent = (0..64).try_for_each(|_| write.write_all(&chunk));
+            assert_eq!(sent.unwrap_err().kind(), std::io::ErrorKind::BrokenPipe);
+            if let Some(tee) = tee {
+                tee.join().unwrap().unwrap();
+            }
+        }
+    }
 }
@@ -1,12 +1,30 @@
 use crate::daemon::logging::GlobalLogger;
 use crate::daemon::system::messages::zfs_manager::{
-    GetDatasetsForTask, MakeSnapshots, SendSnapshotToPipe,
+    EstimateSendSize, GetDatasetsForTask, GetGuid, MakeSnapshots, SendSnapshotToPipe,
 };
 use actix::{Actor, Handler, MessageResult, Supervised, SyncContext};
-use libzetta::zfs::{DelegatingZfsEngine, SendFlags, ZfsEngine};
+use libzetta::zfs::{DelegatingZfsEngine, Properties, SendFlags, ZfsEngine};
 use regex::Regex;
 use slog::{debug, error, o, warn, Logger};
 use std::path::PathBuf;
+use std::process::Command;
+
+/// Flags every snapshot is sent with.
+const SEND_FLAGS: SendFlags = SendFlags::empty();
+
+/// Names of send flags as recorded in the manifest, after the matching `zfs send` options.
+fn send_flag_names(flags: SendFlags) -> Vec<String> {
+    [
+        (SendFlags::LZC_SEND_FLAG_EMBED_DATA, "embed"),
+        (SendFlags::LZC_SEND_FLAG_LARGE_BLOCK, "large_block"),
+        (SendFlags::LZC_SEND_FLAG_COMPRESS, "compressed"),
+        (SendFlags::LZC_SEND_FLAG_RAW, "raw"),
+    ]
+    .iter()
+    .filter(|(flag, _)| flags.contains(*flag))
+    .map(|(_, name)| name.to_string())
+    .collect()
+}
 
 pub struct ZfsManager {
     logger: Logger,
@@ -93,7 +111,7 @@ impl Handler<MakeSnapshots> for ZfsManager {
 }
 
 impl Handler<SendSnapshotToPipe> for ZfsManager {
-    type Result = Result<(), String>;
+    type Result = Result<Vec<String>, String>;
 
     fn handle(&mut self, msg: SendSnapshotToPipe, _ctx: &mut SyncContext<Self>) -> Self::Result {
         let result = if let Some(source) = msg.1 {
@@ -103,20 +121,19 @@ impl Handler<SendSnapshotToPipe> for ZfsManager {
                 &source.display(),
                 msg.0.display()
             );
-            self.z
-                .send_incremental(&msg.0, &source, msg.2.write, SendFlags::default())
+            self.z.send_incremental(&msg.0, &source, msg.2, SEND_FLAGS)
         } else {
             debug!(
                 self.logger,
                 "Sending full snapshot for {} to pipe",
                 msg.0.display()
             );
-            self.z.send_full(&msg.0, msg.2.write, SendFlags::default())
+            self.z.send_full(&msg.0, msg.2, SEND_FLAGS)
         };
         match result {
             Ok(()) => {
                 debug!(self.logger, "Sent {}", msg.0.display());
-                Ok(())
+                Ok(send_flag_names(SEND_FLAGS))
             }
             Err(e) => {
                 error!(
@@ -130,3 +147,80 @@ impl Handler<SendSnapshotToPipe> for ZfsManager {
         }
     }
 }
+
+impl Handler<GetGuid> for ZfsManager {
+    type Result = Result<u64, String>;
+
+    fn handle(&mut self, msg: GetGuid, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        match self.z.read_properties(&msg.0) {
+            Ok(Properties::Snapshot(properties)) => Ok(*properties.guid()),
+            Ok(_) => Err(format!("{} is not a snapshot", msg.0.display())),
+            Err(e) => {
+                error!(
+                    self.logger,
+                    "Failed to get guid of \"{}\": {}",
+                    msg.0.display(),
+                    e
+                );
+                Err(e.to_string())
+            }
+        }
+    }
+}
+
+impl Handler<EstimateSendSize> for ZfsManager {
+    type Result = Result<u64, String>;
+
+    fn handle(&mut self, msg: EstimateSendSize, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        let mut cmd = Command::new("zfs");
+        cmd.args(&["send", "-nP"]);
+        if let Some(source) = &msg.1 {
+            cmd.arg("-i").arg(source);
+        }
+        let output = cmd.arg(&msg.0).output().map_err(|e| e.to_string())?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            warn!(
+                self.logger,
+                "Failed to estimate send size of \"{}\": {}",
+                msg.0.display(),
+                stderr.trim()
+            );
+            return Err(stderr.trim().to_string());
+        }
+        parse_send_size(&String::from_utf8_lossy(&output.stdout))
+            .ok_or_else(|| "zfs send -nP didn't report size".to_string())
+    }
+}
+
+/// Pull total size out of `zfs send -nP` output, which ends with a `size\t<bytes>` line.
+fn parse_send_size(output: &str) -> Option<u64> {
+    output.lines().rev().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some("size"), Some(size)) => size.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn send_flags_by_name() {
+        assert!(send_flag_names(SendFlags::empty()).is_empty());
+        assert_eq!(
+            send_flag_names(SendFlags::LZC_SEND_FLAG_LARGE_BLOCK | SendFlags::LZC_SEND_FLAG_RAW),
+            vec!["large_block", "raw"]
+        );
+    }
+
+    #[test]
+    fn send_size_from_dry_run() {
+        let output = "incremental\tgazpacho-1\tz/usr@gazpacho-2\t1048576\nsize\t1048576\n";
+        assert_eq!(parse_send_size(output), Some(1048576));
+        assert_eq!(parse_send_size("full\tz/usr@gazpacho-1\n"), None);
+    }
+}
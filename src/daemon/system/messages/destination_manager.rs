@@ -1,10 +1,19 @@
-use crate::daemon::config::Compression;
+use crate::daemon::compression::{AdaptiveCompression, Compression, CompressionDecision};
 use crate::daemon::destination::Destination;
+use crate::daemon::encryption::Encryption;
+use crate::daemon::ensured::probe::ProbeReport;
+use crate::daemon::ensured::PendingFile;
+use crate::daemon::manifest::Manifest;
+use crate::daemon::measure::ByteCounter;
 use actix::Message;
 use chrono::{DateTime, Utc};
 use filedescriptor::FileDescriptor;
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Semaphore;
 
 pub struct NewDestinations(pub HashMap<String, Destination>);
 impl Message for NewDestinations {
@@ -13,33 +22,236 @@ impl Message for NewDestinations {
 
 pub struct SaveFromPipe {
     pub destination: String,
+    pub task: String,
+    pub pool: String,
     pub dataset: PathBuf,
     pub snapshot: PathBuf,
+    /// Source snapshot of incremental send.
+    pub source: Option<PathBuf>,
     pub compression: Option<Compression>,
+    pub encryption: Option<Encryption>,
     pub rx: FileDescriptor,
     pub date: DateTime<Utc>,
+    /// Overrides `fallback` of the destination.
+    pub fallback: Option<String>,
+    pub adaptive: Option<AdaptiveCompression>,
+    /// Ratio of recent transfers of the dataset to the destination, for adaptive compression.
+    pub history_ratio: Option<f64>,
+    /// Start of the stream already taken from `rx` for sampling.
+    pub head: Vec<u8>,
+    /// Raw bytes sent so far, watched by progress reporting.
+    pub progress: ByteCounter,
 }
 
 impl SaveFromPipe {
     pub fn new(
         destination: String,
+        task: String,
+        pool: String,
         dataset: PathBuf,
         snapshot: PathBuf,
+        source: Option<PathBuf>,
         compression: Option<Compression>,
+        encryption: Option<Encryption>,
         rx: FileDescriptor,
         date: DateTime<Utc>,
+        fallback: Option<String>,
+        adaptive: Option<AdaptiveCompression>,
+        history_ratio: Option<f64>,
+        progress: ByteCounter,
     ) -> Self {
         SaveFromPipe {
             destination,
+            task,
+            pool,
             dataset,
             snapshot,
+            source,
             compression,
+            encryption,
             rx,
             date,
+            fallback,
+            adaptive,
+            history_ratio,
+            head: Vec::new(),
+            progress,
         }
     }
 }
 
 impl Message for SaveFromPipe {
+    type Result = Result<SavedFile, SaveError>;
+}
+
+pub enum SaveError {
+    /// Destination couldn't be reached before anything was read from the stream, so the request
+    /// is handed back to be sent elsewhere.
+    Unreachable(String, SaveFromPipe),
+    Failed(String),
+}
+
+impl Display for SaveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveError::Unreachable(e, _) => write!(f, "{}", e),
+            SaveError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<String> for SaveError {
+    fn from(src: String) -> Self {
+        SaveError::Failed(src)
+    }
+}
+
+/// Uncommitted backup along with metadata of the stream it holds.
+pub struct SavedFile {
+    /// Destination that wrote the backup, the fallback if the requested one was unreachable.
+    pub destination: String,
+    /// Path the backup is known by, manifest is written next to it.
+    pub target: PathBuf,
+    /// A single file, or every volume of a split backup in order.
+    pub files: Vec<PendingFile>,
+    pub manifest: Manifest,
+    /// What adaptive compression did, if it was enabled.
+    pub compression_decision: Option<CompressionDecision>,
+}
+
+/// Commit or discard a backup written by `SaveFromPipe`. Manifest is written only on commit.
+pub struct FinalizeSave {
+    pub destination: String,
+    pub target: PathBuf,
+    pub files: Vec<PendingFile>,
+    pub manifest: Option<Manifest>,
+    pub commit: bool,
+}
+
+impl FinalizeSave {
+    pub fn commit(saved: SavedFile) -> Self {
+        FinalizeSave {
+            destination: saved.destination,
+            target: saved.target,
+            files: saved.files,
+            manifest: Some(saved.manifest),
+            commit: true,
+        }
+    }
+
+    pub fn discard(saved: SavedFile) -> Self {
+        FinalizeSave {
+            destination: saved.destination,
+            target: saved.target,
+            files: saved.files,
+            manifest: None,
+            commit: false,
+        }
+    }
+}
+
+impl Message for FinalizeSave {
     type Result = Result<(), String>;
 }
+
+/// Check that `required` bytes fit on the destination while keeping its `free_space` reserve.
+pub struct CheckFreeSpace {
+    pub destination: String,
+    pub required: u64,
+}
+
+impl CheckFreeSpace {
+    pub fn new(destination: String, required: u64) -> Self {
+        CheckFreeSpace {
+            destination,
+            required,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SpaceCheck {
+    pub available: u64,
+    pub total: u64,
+    pub required: u64,
+    pub sufficient: bool,
+    /// How long the step keeps checking before giving up on the destination.
+    pub defer: Option<Duration>,
+}
+
+impl Message for CheckFreeSpace {
+    type Result = Result<SpaceCheck, String>;
+}
+
+/// Run connectivity and permission self-test against the destination.
+pub struct TestDestination {
+    pub destination: String,
+}
+
+impl TestDestination {
+    pub fn new(destination: String) -> Self {
+        TestDestination { destination }
+    }
+}
+
+impl Message for TestDestination {
+    type Result = Result<ProbeReport, String>;
+}
+
+/// Remove partial files left on the destination by transfers that died before `before`.
+pub struct RemoveStalePartials {
+    pub before: SystemTime,
+}
+
+impl RemoveStalePartials {
+    pub fn new(before: SystemTime) -> Self {
+        RemoveStalePartials { before }
+    }
+}
+
+impl Message for RemoveStalePartials {
+    type Result = ();
+}
+
+/// Agent slots of destinations a teed stream goes to and their fallbacks, in the order they have
+/// to be acquired in.
+pub struct GetAgentSlots {
+    /// Destination and its fallback override.
+    pub destinations: Vec<(String, Option<String>)>,
+}
+
+impl GetAgentSlots {
+    pub fn new(destinations: Vec<(String, Option<String>)>) -> Self {
+        GetAgentSlots { destinations }
+    }
+}
+
+impl Message for GetAgentSlots {
+    type Result = Vec<Arc<Semaphore>>;
+}
+
+/// Read a backup back from the destination and hash it, to compare with recorded checksums.
+pub struct VerifyBackup {
+    pub destination: String,
+    pub target: PathBuf,
+}
+
+impl VerifyBackup {
+    pub fn new(destination: String, target: PathBuf) -> Self {
+        VerifyBackup {
+            destination,
+            target,
+        }
+    }
+}
+
+/// What was read back by `VerifyBackup`.
+pub struct StoredBackup {
+    pub manifest: Manifest,
+    pub bytes: u64,
+    pub checksum: String,
+}
+
+impl Message for VerifyBackup {
+    type Result = Result<StoredBackup, String>;
+}
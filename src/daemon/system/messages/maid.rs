@@ -12,3 +12,17 @@ impl Default for Cleanup {
         Self(None)
     }
 }
+
+/// Snapshots made by gazpacho that still exist, per task and dataset, as of the last count.
+pub struct CountSnapshots;
+
+#[derive(Clone, Debug)]
+pub struct SnapshotCount {
+    pub task: String,
+    pub dataset: String,
+    pub count: u64,
+}
+
+impl Message for CountSnapshots {
+    type Result = Vec<SnapshotCount>;
+}
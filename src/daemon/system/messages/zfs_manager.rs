@@ -1,5 +1,5 @@
 use actix::Message;
-use filedescriptor::Pipe;
+use filedescriptor::FileDescriptor;
 use std::path::PathBuf;
 
 pub struct GetDatasetsForTask {
@@ -32,8 +32,25 @@ impl Message for MakeSnapshots {
     type Result = Result<(), String>;
 }
 
-pub struct SendSnapshotToPipe(pub PathBuf, pub Option<PathBuf>, pub Pipe);
+/// Send the snapshot, incrementally if there is a source, into the write end of a pipe. Returns
+/// names of send flags the stream was made with.
+pub struct SendSnapshotToPipe(pub PathBuf, pub Option<PathBuf>, pub FileDescriptor);
 
 impl Message for SendSnapshotToPipe {
-    type Result = Result<(), String>;
+    type Result = Result<Vec<String>, String>;
+}
+
+/// Look up GUID of a snapshot.
+pub struct GetGuid(pub PathBuf);
+
+impl Message for GetGuid {
+    type Result = Result<u64, String>;
+}
+
+/// Estimate size of the send stream with `zfs send -nP`. Second field is the source snapshot of
+/// incremental send.
+pub struct EstimateSendSize(pub PathBuf, pub Option<PathBuf>);
+
+impl Message for EstimateSendSize {
+    type Result = Result<u64, String>;
 }
@@ -0,0 +1,42 @@
+use filedescriptor::FileDescriptor;
+use slog::{debug, warn, Logger};
+use std::io::{Read, Write};
+use std::thread::JoinHandle;
+
+const BUFFER_SIZE: usize = 128 * 1024;
+
+/// Copy everything from `input` into every output on a dedicated thread. An output that fails is
+/// dropped, copying stops once there are none left. Returns number of bytes read from `input`.
+pub fn spawn(
+    logger: Logger,
+    mut input: FileDescriptor,
+    outputs: Vec<FileDescriptor>,
+) -> JoinHandle<std::io::Result<u64>> {
+    std::thread::spawn(move || {
+        let mut outputs: Vec<Option<FileDescriptor>> = outputs.into_iter().map(Some).collect();
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        let mut total = 0;
+        loop {
+            let n = match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            total += n as u64;
+            for (idx, slot) in outputs.iter_mut().enumerate() {
+                if let Some(output) = slot {
+                    if let Err(e) = output.write_all(&buf[..n]) {
+                        warn!(logger, "Dropping output #{} of the stream: {}", idx, e);
+                        *slot = None;
+                    }
+                }
+            }
+            if outputs.iter().all(Option::is_none) {
+                debug!(logger, "No outputs left, stopping");
+                break;
+            }
+        }
+        Ok(total)
+    })
+}
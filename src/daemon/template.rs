@@ -0,0 +1,400 @@
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use uclicious::traits::TryInto;
+use uclicious::{FromObject, ObjectError, ObjectRef};
+
+/// Default layout of folders inside destination folder.
+pub const DEFAULT_PATH_TEMPLATE: &str = "{year}/{month}/{day}";
+/// Default file name. Kept compatible with layout of older versions.
+pub const DEFAULT_FILENAME_TEMPLATE: &str = "{date}-{timestamp}-{dataset_legacy}.{ext}";
+
+/// Everything a template can refer to.
+#[derive(Debug, Clone)]
+pub struct TemplateContext<'a> {
+    pub host: &'a str,
+    pub task: &'a str,
+    pub pool: &'a str,
+    pub dataset: &'a Path,
+    /// Snapshot name, without dataset.
+    pub snapshot: &'a str,
+    /// Source snapshot name of incremental send, without dataset.
+    pub source: Option<&'a str>,
+    pub date: DateTime<Utc>,
+    pub ext: &'a str,
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+enum Placeholder {
+    Host,
+    Task,
+    Pool,
+    /// Whole dataset name, encoded with `encode_dataset`.
+    Dataset,
+    /// Whole dataset name with `/` replaced by `_`. Not reversible.
+    DatasetLegacy,
+    /// Whole dataset name as a path.
+    DatasetPath,
+    /// Last component of dataset name.
+    DatasetName,
+    /// N-th component of dataset name, `0` is the pool.
+    DatasetSegment(usize),
+    Snapshot,
+    /// `full` or `incremental`.
+    Kind,
+    Source,
+    Date,
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Timestamp,
+    Ext,
+}
+
+impl Placeholder {
+    fn parse(name: &str) -> Option<Self> {
+        let placeholder = match name {
+            "host" => Placeholder::Host,
+            "task" => Placeholder::Task,
+            "pool" => Placeholder::Pool,
+            "dataset" => Placeholder::Dataset,
+            "dataset_legacy" => Placeholder::DatasetLegacy,
+            "dataset_path" => Placeholder::DatasetPath,
+            "dataset_name" => Placeholder::DatasetName,
+            "snapshot" => Placeholder::Snapshot,
+            "kind" => Placeholder::Kind,
+            "source" => Placeholder::Source,
+            "date" => Placeholder::Date,
+            "year" => Placeholder::Year,
+            "month" => Placeholder::Month,
+            "day" => Placeholder::Day,
+            "hour" => Placeholder::Hour,
+            "minute" => Placeholder::Minute,
+            "second" => Placeholder::Second,
+            "timestamp" => Placeholder::Timestamp,
+            "ext" => Placeholder::Ext,
+            name if name.starts_with("dataset[") && name.ends_with(']') => {
+                let idx = name["dataset[".len()..name.len() - 1].parse().ok()?;
+                Placeholder::DatasetSegment(idx)
+            }
+            _ => return None,
+        };
+        Some(placeholder)
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Placeholder::Host => "host".to_string(),
+            Placeholder::Task => "task".to_string(),
+            Placeholder::Pool => "pool".to_string(),
+            Placeholder::Dataset => "dataset".to_string(),
+            Placeholder::DatasetLegacy => "dataset_legacy".to_string(),
+            Placeholder::DatasetPath => "dataset_path".to_string(),
+            Placeholder::DatasetName => "dataset_name".to_string(),
+            Placeholder::DatasetSegment(idx) => format!("dataset[{}]", idx),
+            Placeholder::Snapshot => "snapshot".to_string(),
+            Placeholder::Kind => "kind".to_string(),
+            Placeholder::Source => "source".to_string(),
+            Placeholder::Date => "date".to_string(),
+            Placeholder::Year => "year".to_string(),
+            Placeholder::Month => "month".to_string(),
+            Placeholder::Day => "day".to_string(),
+            Placeholder::Hour => "hour".to_string(),
+            Placeholder::Minute => "minute".to_string(),
+            Placeholder::Second => "second".to_string(),
+            Placeholder::Timestamp => "timestamp".to_string(),
+            Placeholder::Ext => "ext".to_string(),
+        }
+    }
+
+    fn render(&self, ctx: &TemplateContext) -> String {
+        let dataset = ctx.dataset.to_string_lossy();
+        match self {
+            Placeholder::Host => ctx.host.to_string(),
+            Placeholder::Task => ctx.task.to_string(),
+            Placeholder::Pool => ctx.pool.to_string(),
+            Placeholder::Dataset => encode_dataset(&dataset),
+            Placeholder::DatasetLegacy => dataset.replace("/", "_"),
+            Placeholder::DatasetPath => dataset.to_string(),
+            Placeholder::DatasetName => dataset.rsplit('/').next().unwrap_or("").to_string(),
+            Placeholder::DatasetSegment(idx) => {
+                dataset.split('/').nth(*idx).unwrap_or("").to_string()
+            }
+            Placeholder::Snapshot => ctx.snapshot.to_string(),
+            Placeholder::Kind => {
+                if ctx.source.is_some() {
+                    "incremental".to_string()
+                } else {
+                    "full".to_string()
+                }
+            }
+            Placeholder::Source => ctx.source.unwrap_or("").to_string(),
+            Placeholder::Date => ctx.date.format("%Y%m%d").to_string(),
+            Placeholder::Year => ctx.date.format("%Y").to_string(),
+            Placeholder::Month => ctx.date.format("%m").to_string(),
+            Placeholder::Day => ctx.date.format("%d").to_string(),
+            Placeholder::Hour => ctx.date.format("%H").to_string(),
+            Placeholder::Minute => ctx.date.format("%M").to_string(),
+            Placeholder::Second => ctx.date.format("%S").to_string(),
+            Placeholder::Timestamp => ctx.date.timestamp().to_string(),
+            Placeholder::Ext => ctx.ext.to_string(),
+        }
+    }
+
+    /// Regex matching a rendered value.
+    fn pattern(&self) -> &'static str {
+        match self {
+            Placeholder::DatasetPath => ".+?",
+            Placeholder::Dataset => "[A-Za-z0-9_.:%-]+?",
+            Placeholder::Kind => "full|incremental",
+            Placeholder::Source => "[^/]*?",
+            Placeholder::Date => "[0-9]{8}",
+            Placeholder::Year => "[0-9]{4}",
+            Placeholder::Month
+            | Placeholder::Day
+            | Placeholder::Hour
+            | Placeholder::Minute
+            | Placeholder::Second => "[0-9]{2}",
+            Placeholder::Timestamp => "-?[0-9]+",
+            _ => "[^/]+?",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    UnknownPlaceholder(String),
+    Unterminated(String),
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateError::UnknownPlaceholder(name) => {
+                write!(f, "Unknown template placeholder `{{{}}}`", name)
+            }
+            TemplateError::Unterminated(template) => {
+                write!(f, "Unterminated placeholder in template `{}`", template)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Path or file name template with `{placeholder}` substitutions. `{{` and `}}` produce literal
+/// braces.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, TemplateError> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(TemplateError::Unterminated(template.to_string())),
+                        }
+                    }
+                    let placeholder = Placeholder::parse(&name)
+                        .ok_or_else(|| TemplateError::UnknownPlaceholder(name))?;
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Placeholder(placeholder));
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Template { segments })
+    }
+
+    pub fn default_path() -> Self {
+        Self::parse(DEFAULT_PATH_TEMPLATE).expect("Default path template is invalid")
+    }
+
+    pub fn default_filename() -> Self {
+        Self::parse(DEFAULT_FILENAME_TEMPLATE).expect("Default filename template is invalid")
+    }
+
+    pub fn render(&self, ctx: &TemplateContext) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.clone(),
+                Segment::Placeholder(placeholder) => placeholder.render(ctx),
+            })
+            .collect()
+    }
+
+    /// Parse a rendered string back into placeholder values. `dataset` is decoded. Returns `None`
+    /// if the string doesn't match the template.
+    pub fn extract(&self, rendered: &str) -> Option<HashMap<String, String>> {
+        let mut pattern = String::from("^");
+        let mut groups = Vec::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => pattern.push_str(&regex::escape(literal)),
+                Segment::Placeholder(placeholder) => {
+                    pattern.push_str(&format!("(?P<g{}>{})", groups.len(), placeholder.pattern()));
+                    groups.push(placeholder);
+                }
+            }
+        }
+        pattern.push('$');
+        let re = Regex::new(&pattern).ok()?;
+        let captures = re.captures(rendered)?;
+        let mut values = HashMap::new();
+        for (idx, placeholder) in groups.into_iter().enumerate() {
+            let value = captures.name(&format!("g{}", idx))?.as_str();
+            let value = match placeholder {
+                Placeholder::Dataset => decode_dataset(value)?,
+                _ => value.to_string(),
+            };
+            values.insert(placeholder.name(), value);
+        }
+        Some(values)
+    }
+}
+
+impl FromObject<ObjectRef> for Template {
+    fn try_from(value: ObjectRef) -> Result<Self, ObjectError> {
+        let template: String = value.try_into()?;
+        Template::parse(&template).map_err(|e| ObjectError::Other(e.to_string()))
+    }
+}
+
+/// Encode dataset name so it fits in a single path component and can be decoded back. Every
+/// byte outside of `[A-Za-z0-9_.:-]` becomes `%XX`, so `/` is `%2F`.
+pub fn encode_dataset(dataset: &str) -> String {
+    let mut encoded = String::with_capacity(dataset.len());
+    for byte in dataset.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'_' | b'.' | b':' | b'-' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Reverse of `encode_dataset`. Returns `None` on malformed input.
+pub fn decode_dataset(encoded: &str) -> Option<String> {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' {
+            let hex = encoded.get(idx + 1..idx + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            idx += 3;
+        } else {
+            decoded.push(bytes[idx]);
+            idx += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn context(dataset: &Path) -> TemplateContext<'_> {
+        TemplateContext {
+            host: "nimble",
+            task: "test",
+            pool: "z",
+            dataset,
+            snapshot: "gazpacho-20200301-1583020800",
+            source: Some("gazpacho-20200229-1582934400"),
+            date: "2020-03-01T00:00:00Z".parse().unwrap(),
+            ext: "zfs.zst",
+        }
+    }
+
+    #[test]
+    fn default_templates_keep_legacy_layout() {
+        let dataset = Path::new("z/usr/ports");
+        let ctx = context(dataset);
+        assert_eq!(Template::default_path().render(&ctx), "2020/03/01");
+        assert_eq!(
+            Template::default_filename().render(&ctx),
+            "20200301-1583020800-z_usr_ports.zfs.zst"
+        );
+    }
+
+    #[test]
+    fn render_placeholders() {
+        let dataset = Path::new("z/usr/my_ports");
+        let ctx = context(dataset);
+        let template =
+            Template::parse("{host}/{task}/{dataset[1]}/{dataset_name}-{kind}-{{x}}").unwrap();
+        assert_eq!(
+            template.render(&ctx),
+            "nimble/test/usr/my_ports-incremental-{x}"
+        );
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert!(Template::parse("{nope}").is_err());
+        assert!(Template::parse("{year").is_err());
+    }
+
+    #[test]
+    fn dataset_encoding_roundtrip() {
+        for dataset in &["z/usr/ports", "z/my_data/a_b", "z/with space/100%"] {
+            let encoded = encode_dataset(dataset);
+            assert!(!encoded.contains('/'));
+            assert_eq!(decode_dataset(&encoded).as_deref(), Some(*dataset));
+        }
+        assert_ne!(encode_dataset("z/a_b"), encode_dataset("z/a/b"));
+        assert!(decode_dataset("z%2").is_none());
+    }
+
+    #[test]
+    fn extract_values() {
+        let dataset = Path::new("z/usr/my_ports");
+        let ctx = context(dataset);
+        let template = Template::parse("{date}-{snapshot}-{kind}-{dataset}.{ext}").unwrap();
+        let rendered = template.render(&ctx);
+        let values = template.extract(&rendered).unwrap();
+        assert_eq!(values["dataset"], "z/usr/my_ports");
+        assert_eq!(values["date"], "20200301");
+        assert_eq!(values["kind"], "incremental");
+        assert_eq!(values["snapshot"], "gazpacho-20200301-1583020800");
+    }
+}
@@ -1,9 +1,19 @@
 use refinery::{Migration, Runner};
 use refinery_migrations::MigrationPrefix;
 
+mod v10_step_destination_compression;
+mod v11_step_destination_window_log;
+mod v12_step_destination_compression_decision;
+mod v13_step_stats;
 mod v1_create_task_log;
 mod v2_create_step_log;
 mod v3_reset_count;
+mod v4_step_log_error;
+mod v5_step_destination_log;
+mod v6_checksums;
+mod v7_encryption_key_id;
+mod v8_step_destination_bytes;
+mod v9_step_destination_received_by;
 
 // A hack because intellij-rust doesn't like working on modules that isn't clearly declared.
 pub fn runner() -> Runner {
@@ -26,6 +36,66 @@ pub fn runner() -> Runner {
             prefix: MigrationPrefix::Versioned,
             sql: v3_reset_count::migration(),
         },
+        Migration {
+            name: "add_step_log_error".to_string(),
+            version: 4,
+            prefix: MigrationPrefix::Versioned,
+            sql: v4_step_log_error::migration(),
+        },
+        Migration {
+            name: "create_step_destination_log".to_string(),
+            version: 5,
+            prefix: MigrationPrefix::Versioned,
+            sql: v5_step_destination_log::migration(),
+        },
+        Migration {
+            name: "add_checksums".to_string(),
+            version: 6,
+            prefix: MigrationPrefix::Versioned,
+            sql: v6_checksums::migration(),
+        },
+        Migration {
+            name: "add_encryption_key_id".to_string(),
+            version: 7,
+            prefix: MigrationPrefix::Versioned,
+            sql: v7_encryption_key_id::migration(),
+        },
+        Migration {
+            name: "add_step_destination_bytes".to_string(),
+            version: 8,
+            prefix: MigrationPrefix::Versioned,
+            sql: v8_step_destination_bytes::migration(),
+        },
+        Migration {
+            name: "add_step_destination_received_by".to_string(),
+            version: 9,
+            prefix: MigrationPrefix::Versioned,
+            sql: v9_step_destination_received_by::migration(),
+        },
+        Migration {
+            name: "add_step_destination_compression".to_string(),
+            version: 10,
+            prefix: MigrationPrefix::Versioned,
+            sql: v10_step_destination_compression::migration(),
+        },
+        Migration {
+            name: "add_step_destination_window_log".to_string(),
+            version: 11,
+            prefix: MigrationPrefix::Versioned,
+            sql: v11_step_destination_window_log::migration(),
+        },
+        Migration {
+            name: "add_step_destination_compression_decision".to_string(),
+            version: 12,
+            prefix: MigrationPrefix::Versioned,
+            sql: v12_step_destination_compression_decision::migration(),
+        },
+        Migration {
+            name: "add_step_stats".to_string(),
+            version: 13,
+            prefix: MigrationPrefix::Versioned,
+            sql: v13_step_stats::migration(),
+        },
     ];
 
     Runner::new(&migrations)
@@ -0,0 +1,12 @@
+use barrel::{backend::Sqlite, types, Migration};
+
+pub fn migration() -> String {
+    let mut m = Migration::new();
+
+    m.change_table("step_destination_log", |t| {
+        t.add_column("compression_codec", types::text().nullable(true));
+        t.add_column("compression_level", types::integer().nullable(true));
+    });
+
+    m.make::<Sqlite>()
+}
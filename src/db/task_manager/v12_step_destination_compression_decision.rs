@@ -0,0 +1,11 @@
+use barrel::{backend::Sqlite, types, Migration};
+
+pub fn migration() -> String {
+    let mut m = Migration::new();
+
+    m.change_table("step_destination_log", |t| {
+        t.add_column("compression_decision", types::text().nullable(true));
+    });
+
+    m.make::<Sqlite>()
+}
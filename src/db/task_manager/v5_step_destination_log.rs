@@ -0,0 +1,24 @@
+use barrel::{backend::Sqlite, types, Migration};
+
+pub fn migration() -> String {
+    let mut m = Migration::new();
+
+    m.create_table("step_destination_log", |t| {
+        t.add_column("id", types::primary().increments(true));
+        t.add_column(
+            "step_id",
+            types::foreign("step_log", vec![String::from("id")]),
+        );
+        t.add_column("destination", types::text().nullable(false));
+        t.add_column("state", types::text().nullable(false));
+        t.add_column("error", types::text().nullable(true));
+        t.add_column("completed_at", types::text().nullable(false));
+
+        t.add_index(
+            "idx_step_destination_log_step",
+            types::index(vec!["step_id", "destination"]),
+        )
+    });
+
+    m.make::<Sqlite>()
+}
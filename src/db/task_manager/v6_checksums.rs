@@ -0,0 +1,15 @@
+use barrel::{backend::Sqlite, types, Migration};
+
+pub fn migration() -> String {
+    let mut m = Migration::new();
+
+    m.change_table("step_log", |t| {
+        t.add_column("checksum_algorithm", types::text().nullable(true));
+        t.add_column("checksum_raw", types::text().nullable(true));
+    });
+    m.change_table("step_destination_log", |t| {
+        t.add_column("checksum_written", types::text().nullable(true));
+    });
+
+    m.make::<Sqlite>()
+}
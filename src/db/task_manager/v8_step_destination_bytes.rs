@@ -0,0 +1,12 @@
+use barrel::{backend::Sqlite, types, Migration};
+
+pub fn migration() -> String {
+    let mut m = Migration::new();
+
+    m.change_table("step_destination_log", |t| {
+        t.add_column("bytes_raw", types::integer().nullable(true));
+        t.add_column("bytes_written", types::integer().nullable(true));
+    });
+
+    m.make::<Sqlite>()
+}
@@ -1,4 +1,4 @@
-use chrono::Duration;
+use chrono::{Duration, NaiveTime};
 use uclicious::{ObjectError, ObjectRef, TryInto};
 
 pub fn time_to_chrono(src: ObjectRef) -> Result<Option<Duration>, ObjectError> {
@@ -7,3 +7,58 @@ pub fn time_to_chrono(src: ObjectRef) -> Result<Option<Duration>, ObjectError> {
         .map(Option::from)
         .map_err(|e| ObjectError::other(e))
 }
+
+/// Parse local time of day such as `08:00` or `18:30:00`.
+pub fn time_of_day(src: ObjectRef) -> Result<NaiveTime, ObjectError> {
+    let time: String = src.try_into()?;
+    NaiveTime::parse_from_str(&time, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(&time, "%H:%M:%S"))
+        .map_err(|_| ObjectError::Other(format!("\"{}\" is not a valid time of day", time)))
+}
+
+/// Hostname of the machine gazpacho is running on.
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return String::from("localhost");
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+/// Directory under the system temp dir for a test. It's removed when dropped, so a failing test
+/// doesn't leave it behind.
+#[cfg(test)]
+pub struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "gazpacho-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+
+    pub fn join<P: AsRef<std::path::Path>>(&self, path: P) -> std::path::PathBuf {
+        self.0.join(path)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}